@@ -0,0 +1,26 @@
+struct vertex_output {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@group(0) @binding(0)
+var output_texture: texture_2d<f32>;
+
+// One triangle that covers the viewport, uv 0..1 across the visible part.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> vertex_output {
+    let uv = vec2<f32>( f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u) );
+
+    var out: vertex_output;
+    out.position = vec4<f32>( uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0 );
+    out.uv = uv;
+    return out;
+}
+
+// Nearest texel, the output is Rgba32Float which can't be filtered.
+@fragment
+fn fragment(in: vertex_output) -> @location(0) vec4<f32> {
+    let size = vec2<f32>( textureDimensions(output_texture) );
+    let texel = vec2<i32>( min(in.uv * size, size - 1.0) );
+    return vec4<f32>( textureLoad(output_texture, texel, 0).rgb, 1.0 );
+}
@@ -0,0 +1,134 @@
+#define_import_path bevy_raytrace::camera_rays
+
+// Imported after the bindings, as it reads the importer's camera, globals and
+// pixel_stats_buffer.
+//
+// Primary rays for each projection the camera supports, shared by the generate
+// pass and the megakernel.
+
+let PROJECTION_PERSPECTIVE: u32 = 0u;
+let PROJECTION_ORTHOGRAPHIC: u32 = 1u;
+let PROJECTION_EQUIRECTANGULAR: u32 = 2u;
+let PROJECTION_FISHEYE: u32 = 3u;
+let FOV_HORIZONTAL: u32 = 0u;
+let FOV_VERTICAL: u32 = 1u;
+
+// Converged pixels aren't traced from the frame after they converged until
+// the image starts over.
+fn pixel_skipped( pixel: u32 ) -> bool {
+    let converged_at = pixel_stats_buffer.pixels[pixel].converged_at;
+    return converged_at != 0u && converged_at <= globals.accumulated_frames;
+}
+
+// Pixel relative to the image center, y up.
+fn centered( pixel: vec2<f32> ) -> vec2<f32> {
+    return vec2<f32>( pixel.x - f32(globals.render_width) / 2.0, f32(globals.render_height) / 2.0 - pixel.y );
+}
+
+// Rays that are never traced, for pixels outside the image circle. Like every
+// camera ray, the slot is filled in by the caller.
+fn no_ray( pixel: vec2<f32> ) -> ray {
+    return ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, 0u, 0u, 0.0, 0.0, 0u );
+}
+
+// "Essential Ray Generation Shaders", McGuire & Majercik
+fn pinhole_ray( pixel: vec2<f32> ) -> ray {
+    let tan_half_angle = tan(camera.fov / 2.f);
+
+    // Half the image along the axis the field of view spans.
+    var aspect_scale = f32(globals.render_width) / 2.0;
+    if ( camera.fov_axis == FOV_VERTICAL ) {
+        aspect_scale = f32(globals.render_height) / 2.0;
+    }
+
+    let ray_dir = normalize( vec3<f32>( centered(pixel) * tan_half_angle / aspect_scale, -1.0) );
+
+    // The cone starts at a point and spreads by the angle one pixel covers.
+    let cone_spread = atan( tan_half_angle / aspect_scale );
+    return ray( vec3<f32>(0.f), EPSILON, ray_dir, VERY_FAR, 0u, 0u, 0.0, cone_spread, 0u );
+}
+
+fn orthographic_ray( pixel: vec2<f32> ) -> ray {
+    let pixel_size = camera.orthographic_height / f32(globals.render_height);
+    let origin = vec3<f32>( centered(pixel) * pixel_size, 0.0 );
+
+    // Parallel rays, so the cone keeps the width of a pixel and never spreads.
+    return ray( origin, EPSILON, vec3<f32>(0.0, 0.0, -1.0), VERY_FAR, 0u, 0u, pixel_size, 0.0, 0u );
+}
+
+// Longitude across the width and latitude down the height, the whole sphere
+// around the camera with -z in the middle.
+fn equirectangular_ray( pixel: vec2<f32> ) -> ray {
+    let uv = pixel / vec2<f32>( f32(globals.render_width), f32(globals.render_height) );
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    let theta = (0.5 - uv.y) * PI;
+    let ray_dir = vec3<f32>( sin(phi) * cos(theta), sin(theta), -cos(phi) * cos(theta) );
+
+    let cone_spread = PI / f32(globals.render_height);
+    return ray( vec3<f32>(0.f), EPSILON, ray_dir, VERY_FAR, 0u, 0u, 0.0, cone_spread, 0u );
+}
+
+// Equidistant fisheye: the angle from the view axis grows linearly with the
+// distance from the image center, reaching fov / 2 at the edge of an image
+// circle that fits the height.
+fn fisheye_ray( pixel: vec2<f32> ) -> ray {
+    let half_h = f32(globals.render_height) / 2.0;
+    let p = centered(pixel) / half_h;
+    let radius = length(p);
+    if ( radius > 1.0 ) {
+        return no_ray( pixel );
+    }
+
+    let theta = radius * camera.fov / 2.0;
+    let phi = atan2(p.y, p.x);
+    let ray_dir = vec3<f32>( sin(theta) * cos(phi), sin(theta) * sin(phi), -cos(theta) );
+
+    let cone_spread = camera.fov / 2.0 / half_h;
+    return ray( vec3<f32>(0.f), EPSILON, ray_dir, VERY_FAR, 0u, 0u, 0.0, cone_spread, 0u );
+}
+
+// Shirley and Chiu's concentric mapping from the unit square to the unit
+// disk, which keeps strata intact unlike the polar mapping.
+fn concentric_disk( u: vec2<f32> ) -> vec2<f32> {
+    let offset = 2.0 * u - vec2<f32>(1.0);
+    if ( offset.x == 0.0 && offset.y == 0.0 ) {
+        return vec2<f32>(0.0);
+    }
+
+    var radius = offset.y;
+    var theta = PI / 2.0 - (PI / 4.0) * (offset.x / offset.y);
+    if ( abs(offset.x) > abs(offset.y) ) {
+        radius = offset.x;
+        theta = (PI / 4.0) * (offset.y / offset.x);
+    }
+
+    return radius * vec2<f32>( cos(theta), sin(theta) );
+}
+
+// Start the pinhole ray from a point on the lens instead, aimed at where the
+// pinhole ray crosses the focus plane. Only points on that plane stay sharp.
+fn thin_lens_ray( pixel: vec2<f32>, lens_sample: vec2<f32> ) -> ray {
+    var ray = pinhole_ray( pixel );
+
+    let focus_point = ray.dir * (camera.focus_distance / dot(ray.dir, vec3<f32>(0.f, 0.f, -1.f)));
+    let lens_point = concentric_disk( lens_sample ) * camera.aperture_radius;
+
+    ray.origin = vec3<f32>( lens_point, 0.f );
+    ray.dir = normalize(focus_point - ray.origin);
+
+    return ray;
+}
+
+// A camera space ray for the pixel with the selected projection. Only
+// perspective has a lens.
+fn camera_ray( pixel: vec2<f32>, lens_sample: vec2<f32> ) -> ray {
+    if ( camera.projection == PROJECTION_ORTHOGRAPHIC ) {
+        return orthographic_ray( pixel );
+    } else if ( camera.projection == PROJECTION_EQUIRECTANGULAR ) {
+        return equirectangular_ray( pixel );
+    } else if ( camera.projection == PROJECTION_FISHEYE ) {
+        return fisheye_ray( pixel );
+    }
+
+    return thin_lens_ray( pixel, lens_sample );
+}
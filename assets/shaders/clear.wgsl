@@ -7,11 +7,22 @@ struct camera_config {
     forward: vec3<f32>,
     fov: f32,
     up: vec3<f32>,
-    pad0: f32,
+    focus_distance: f32,
     right: vec3<f32>,
-    pad1: f32,
+    aperture_radius: f32,
     position: vec3<f32>,
-    pad2: f32,
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
 };
 
 struct globals_buf {
@@ -19,11 +30,17 @@ struct globals_buf {
     render_width: u32,
     render_height: u32,
     samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
     clear_index: atomic<u32>,
     generate_index: atomic<u32>,
     intersect_index: atomic<u32>,
-    shade_index: atomic<u32>,
     collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
 };
 
 struct ray {
@@ -33,6 +50,9 @@ struct ray {
     max: f32,
     pixel: u32,
     bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
 };
 
 struct ray_buf {
@@ -47,12 +67,18 @@ struct intersection {
     normal: vec3<f32>,
     material: u32,
     front_face: u32,
+    uv: vec2<f32>,
+    uv_density: f32,
 };
 
 struct intersection_buf {
     intersections: array<intersection>,
 };
 
+struct throughput_buf {
+    throughput: array<vec4<f32>>,
+};
+
 @group(0) @binding(0)
 var<uniform> camera: camera_config;
 
@@ -65,13 +91,20 @@ var<storage, read_write> ray_buffer: ray_buf;
 @group(1) @binding(1)
 var<storage, read_write> intersection_buffer: intersection_buf;
 
+@group(1) @binding(4)
+var<storage, read_write> throughput_buffer: throughput_buf;
+
 @group(2) @binding(0)
 var output: texture_storage_2d<rgba32float, read_write>;
 
 @compute @workgroup_size(128, 1, 1)
 fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
 {
+#ifdef INVOCATION_ID_INDEXING
+    let index = invocation_id.x;
+#else
     let index = atomicAdd( &globals.clear_index, 1u );
+#endif
     if ( index >= ray_buffer.ray_count ) {
         return;
     }
@@ -83,5 +116,9 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
 
     storageBarrier();
     textureStore(output, vec2<i32>(i32(x), i32(y)), clear);
-    intersection_buffer.intersections[index].color = clear;
+
+    // Paths start with full throughput, no light, and haven't scattered from
+    // anything rough yet.
+    throughput_buffer.throughput[index] = vec4<f32>( 1.0, 1.0, 1.0, 0.0 );
+    intersection_buffer.intersections[index].color = vec4<f32>( 0.0, 0.0, 0.0, 1.0 );
 }
@@ -7,11 +7,22 @@ struct camera_config {
     forward: vec3<f32>,
     fov: f32,
     up: vec3<f32>,
-    pad0: f32,
+    focus_distance: f32,
     right: vec3<f32>,
-    pad1: f32,
+    aperture_radius: f32,
     position: vec3<f32>,
-    pad2: f32,
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
 };
 
 struct globals_buf {
@@ -19,11 +30,17 @@ struct globals_buf {
     render_width: u32,
     render_height: u32,
     samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
     clear_index: atomic<u32>,
     generate_index: atomic<u32>,
     intersect_index: atomic<u32>,
-    shade_index: atomic<u32>,
     collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
 };
 
 struct ray {
@@ -33,6 +50,9 @@ struct ray {
     max: f32,
     pixel: u32,
     bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
 };
 
 struct ray_buf {
@@ -47,12 +67,25 @@ struct intersection {
     normal: vec3<f32>,
     material: u32,
     front_face: u32,
+    uv: vec2<f32>,
+    uv_density: f32,
 };
 
 struct intersection_buf {
     intersections: array<intersection>,
 };
 
+struct pixel_stats {
+    mean: vec3<f32>,
+    samples: u32,
+    m2: vec3<f32>,
+    converged_at: u32,
+};
+
+struct pixel_stats_buf {
+    pixels: array<pixel_stats>,
+};
+
 struct sphere {
     center: vec3<f32>,
     radius: f32,
@@ -69,7 +102,21 @@ struct material {
     reflectance: i32,
     fuzziness: f32,
     index_of_refraction: f32,
-    pad2: i32,
+    metallic: f32,
+    roughness: f32,
+    specular: f32,
+    base_color_texture: i32,
+    metallic_roughness_texture: i32,
+    emissive_texture: i32,
+    normal_map_texture: i32,
+    procedural: i32,
+    procedural_scale: f32,
+    procedural_color: vec4<f32>,
+    procedural_depth: u32,
+    absorption_density: f32,
+    cauchy_b: f32,
+    anisotropy: f32,
+    absorption: vec4<f32>,
 }
 
 struct material_buf {
@@ -81,46 +128,262 @@ struct shade {
     extension: ray,
 }
 
+struct blue_noise_buf {
+    values: array<f32, 4096>,
+};
+
 @group(0) @binding(0)
 var<uniform> camera: camera_config;
 
 @group(0) @binding(1)
 var<storage, read_write> globals: globals_buf;
 
+@group(0) @binding(2)
+var<storage, read> blue_noise: blue_noise_buf;
+
 @group(1) @binding(0)
 var<storage, read_write> ray_buffer: ray_buf;
 
 @group(1) @binding(1)
 var<storage, read_write> intersection_buffer: intersection_buf;
 
+@group(1) @binding(5)
+var<storage, read_write> pixel_stats_buffer: pixel_stats_buf;
+
 @group(2) @binding(0)
 var output: texture_storage_2d<rgba32float, read_write>;
 
+// A sample is an outlier this many standard deviations above the rest of
+// the window, and at least this many times their mean.
+let OUTLIER_SIGMAS: f32 = 3.0;
+let OUTLIER_RATIO: f32 = 4.0;
+
+let DEBUG_NONE: u32 = 0u;
+let DEBUG_SAMPLE_COUNT: u32 = 1u;
+
+let FILTER_BOX: u32 = 0u;
+let FILTER_TENT: u32 = 1u;
+let FILTER_GAUSSIAN: u32 = 2u;
+let FILTER_MITCHELL: u32 = 3u;
+
+#import bevy_raytrace::sampler
+
+// How far from the pixel center the filter reaches, in pixels.
+fn filter_radius() -> f32 {
+    if ( camera.pixel_filter == FILTER_TENT ) {
+        return 1.0;
+    } else if ( camera.pixel_filter == FILTER_GAUSSIAN ) {
+        return 1.5;
+    } else if ( camera.pixel_filter == FILTER_MITCHELL ) {
+        return 2.0;
+    }
+    return 0.5;
+}
+
+// Mitchell-Netravali with B = C = 1/3, for |x| up to 2.
+fn mitchell( x: f32 ) -> f32 {
+    let b = 1.0 / 3.0;
+    let c = 1.0 / 3.0;
+    let t = abs(x);
+    if ( t > 2.0 ) {
+        return 0.0;
+    }
+    if ( t > 1.0 ) {
+        return ((-b - 6.0*c) * t*t*t + (6.0*b + 30.0*c) * t*t + (-12.0*b - 48.0*c) * t + (8.0*b + 24.0*c)) / 6.0;
+    }
+    return ((12.0 - 9.0*b - 6.0*c) * t*t*t + (-18.0 + 12.0*b + 6.0*c) * t*t + (6.0 - 2.0*b)) / 6.0;
+}
+
+// One axis of the separable filter, x in pixels from the pixel center.
+fn filter_1d( x: f32 ) -> f32 {
+    let radius = filter_radius();
+    if ( abs(x) > radius ) {
+        return 0.0;
+    }
+
+    if ( camera.pixel_filter == FILTER_TENT ) {
+        return 1.0 - abs(x);
+    } else if ( camera.pixel_filter == FILTER_GAUSSIAN ) {
+        // Shifted down so it reaches zero at the radius, as in pbrt.
+        let alpha = 2.0;
+        return max( exp(-alpha * x * x) - exp(-alpha * radius * radius), 0.0 );
+    } else if ( camera.pixel_filter == FILTER_MITCHELL ) {
+        return mitchell( x );
+    }
+    return 1.0;
+}
+
+fn luminance( c: vec3<f32> ) -> f32 {
+    return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Converged pixels aren't traced from the frame after they converged until
+// the image starts over.
+fn pixel_skipped( pixel: u32 ) -> bool {
+    let converged_at = pixel_stats_buffer.pixels[pixel].converged_at;
+    return converged_at != 0u && converged_at <= globals.accumulated_frames;
+}
+
+// Converged once the standard error of the mean is below the threshold times
+// the pixel's luminance, plus a little so dark pixels can converge too.
+fn is_converged( stats: pixel_stats ) -> bool {
+    if ( camera.adaptive_threshold <= 0.0 || stats.samples < max(camera.min_samples, 2u) ) {
+        return false;
+    }
+
+    let n = f32(stats.samples);
+    let standard_error = sqrt( luminance(stats.m2) / (n * (n - 1.0)) );
+    return standard_error < camera.adaptive_threshold * (luminance(stats.mean) + 0.1);
+}
+
+// A sample far brighter than the rest of the window. It is judged against
+// the window without it, so a lone firefly can't hide behind the variance it
+// adds itself.
+fn is_outlier( l: f32, sum: f32, sum_sq: f32, count: f32 ) -> bool {
+    if ( count < 3.0 ) {
+        return false;
+    }
+
+    let others = count - 1.0;
+    let mean = (sum - l) / others;
+    let variance = max( (sum_sq - l*l) / others - mean*mean, 0.0 );
+    return l > max( mean + OUTLIER_SIGMAS * sqrt(variance), OUTLIER_RATIO * mean );
+}
+
+// Blue through green to red.
+fn heatmap( t: f32 ) -> vec3<f32> {
+    let c = 2.0 * clamp(t, 0.0, 1.0) - 1.0;
+    return clamp( vec3<f32>( c, 1.0 - abs(c), -c ), vec3<f32>(0.0), vec3<f32>(1.0) );
+}
+
+// Debug views hold exact values, so only the image has fireflies to reject.
+fn rejects_outliers() -> bool {
+    return camera.reject_outliers != 0u && globals.debug_view <= DEBUG_SAMPLE_COUNT;
+}
+
+// The pixel's accumulated color, or the fraction of accumulated frames that
+// traced it when showing sample counts.
+fn display( stats: pixel_stats ) -> vec4<f32> {
+    if ( globals.debug_view == DEBUG_SAMPLE_COUNT ) {
+        return vec4<f32>( heatmap( f32(stats.samples) / f32(globals.accumulated_frames + 1u) ), 1.0 );
+    }
+
+    return vec4<f32>( stats.mean, 1.0 );
+}
+
+// One invocation per pixel. Every sample near the pixel is weighted by the
+// reconstruction filter at its distance from the pixel center, so samples
+// are shared with the neighbouring pixels the filter reaches. With one sample
+// per pixel a frame, more samples inside a pixel only come from accumulating
+// frames.
 @compute @workgroup_size(128, 1, 1)
 fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
 {
+#ifdef INVOCATION_ID_INDEXING
+    let index = invocation_id.x;
+#else
     let index = atomicAdd( &globals.collect_index, 1u );
-    if ( index >= ray_buffer.ray_count ) {
+#endif
+    let dim = globals.render_width * globals.render_height;
+    if ( index >= dim ) {
         return;
     }
 
-    let r = ray_buffer.rays[index];
-    var pixel = r.pixel;
-    let y = u32( floor(f32(pixel) / f32(globals.render_width)) );
-    let x = pixel - (y*globals.render_width);
+    let x = i32( index % globals.render_width );
+    let y = i32( index / globals.render_width );
+    let center = vec2<f32>( f32(x) + 0.5, f32(y) + 0.5 );
 
-    let dim = globals.render_width * globals.render_height;
+    var stats = pixel_stats_buffer.pixels[index];
+    if ( pixel_skipped(index) ) {
+        storageBarrier();
+        textureStore(output, vec2<i32>(x, y), display(stats));
+        return;
+    }
+
+    // Samples land anywhere in their pixel, so look as far as any part of a
+    // neighbour could be within the radius.
+    let reach = i32( ceil(filter_radius() - 0.5) );
 
-    var accumulated_color = vec3<f32>( 0.0 );
-    for ( var i=0u; i<globals.samples_per_ray; i=i+1u) {
-        var intersection_index = index;// + dim*i;
-        var intersection = intersection_buffer.intersections[intersection_index];
+    // Luminance of every sample the filter can see, and at least the 3x3
+    // pixels around, to find outliers in.
+    var lum_sum = 0.0;
+    var lum_sq = 0.0;
+    var lum_count = 0.0;
+    if ( rejects_outliers() ) {
+        let stats_reach = max( reach, 1 );
+        for ( var dy=-stats_reach; dy<=stats_reach; dy=dy+1 ) {
+            for ( var dx=-stats_reach; dx<=stats_reach; dx=dx+1 ) {
+                let n = vec2<i32>( x + dx, y + dy );
+                if ( n.x < 0 || n.y < 0 || n.x >= i32(globals.render_width) || n.y >= i32(globals.render_height) ) {
+                    continue;
+                }
 
-        accumulated_color += intersection.color.xyz;
+                let neighbour = u32(n.y) * globals.render_width + u32(n.x);
+                if ( pixel_skipped(neighbour) ) {
+                    continue;
+                }
+
+                for ( var i=0u; i<globals.samples_per_ray; i=i+1u) {
+                    let l = luminance( intersection_buffer.intersections[neighbour + dim*i].color.xyz );
+                    lum_sum += l;
+                    lum_sq += l * l;
+                    lum_count += 1.0;
+                }
+            }
+        }
     }
 
-    let final_color = vec4<f32>( accumulated_color / f32(globals.samples_per_ray), 1.0 );
+    var weighted_color = vec3<f32>( 0.0 );
+    var total_weight = 0.0;
+    for ( var dy=-reach; dy<=reach; dy=dy+1 ) {
+        for ( var dx=-reach; dx<=reach; dx=dx+1 ) {
+            let n = vec2<i32>( x + dx, y + dy );
+            if ( n.x < 0 || n.y < 0 || n.x >= i32(globals.render_width) || n.y >= i32(globals.render_height) ) {
+                continue;
+            }
+
+            // Skipped neighbours have no samples this frame.
+            let neighbour = u32(n.y) * globals.render_width + u32(n.x);
+            if ( pixel_skipped(neighbour) ) {
+                continue;
+            }
+
+            for ( var i=0u; i<globals.samples_per_ray; i=i+1u) {
+                let slot = neighbour + dim*i;
+                let offset = vec2<f32>(n) + sample_offset(slot) - center;
+                let weight = filter_1d(offset.x) * filter_1d(offset.y);
+                let sample_color = intersection_buffer.intersections[slot].color.xyz;
+                if ( rejects_outliers() && is_outlier( luminance(sample_color), lum_sum, lum_sq, lum_count ) ) {
+                    continue;
+                }
+
+                weighted_color += weight * sample_color;
+                total_weight += weight;
+            }
+        }
+    }
+
+    // Mitchell's negative lobes can cancel out, keep the pixel black then.
+    var color = vec3<f32>( 0.0 );
+    if ( total_weight > 0.0 ) {
+        color = max( weighted_color / total_weight, vec3<f32>(0.0) );
+    }
+
+    // Welford's running mean and variance, starting over with the image.
+    if ( globals.accumulated_frames == 0u ) {
+        stats = pixel_stats( vec3<f32>(0.0), 0u, vec3<f32>(0.0), 0u );
+    }
+
+    stats.samples = stats.samples + 1u;
+    let delta = color - stats.mean;
+    stats.mean = stats.mean + delta / f32(stats.samples);
+    stats.m2 = stats.m2 + delta * (color - stats.mean);
+
+    if ( is_converged(stats) ) {
+        stats.converged_at = globals.accumulated_frames + 1u;
+    }
 
     storageBarrier();
-    textureStore(output, vec2<i32>(i32(x), i32(y)), final_color);
-}
\ No newline at end of file
+    pixel_stats_buffer.pixels[index] = stats;
+    textureStore(output, vec2<i32>(x, y), display(stats));
+}
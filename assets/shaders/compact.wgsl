@@ -0,0 +1,74 @@
+let VERY_FAR: f32 = 1e20f;
+let EPSILON: f32 = 0.001;
+let PI:f32 = 3.14159265358979;
+
+struct globals_buf {
+    frame: u32,
+    render_width: u32,
+    render_height: u32,
+    samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
+    clear_index: atomic<u32>,
+    generate_index: atomic<u32>,
+    intersect_index: atomic<u32>,
+    collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
+};
+
+struct ray {
+    origin: vec3<f32>,
+    min: f32,
+    dir: vec3<f32>,
+    max: f32,
+    pixel: u32,
+    bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
+};
+
+struct ray_buf {
+    ray_count: u32,
+    rays: array<ray>,
+};
+
+struct ray_queue_buf {
+    indices: array<u32>,
+};
+
+@group(0) @binding(1)
+var<storage, read_write> globals: globals_buf;
+
+@group(1) @binding(0)
+var<storage, read_write> ray_buffer: ray_buf;
+
+@group(1) @binding(2)
+var<storage, read_write> ray_queue: ray_queue_buf;
+
+// Pack the index of every ray that is still alive into the ray queue so the
+// following passes only launch work for those rays.
+@compute @workgroup_size(128, 1, 1)
+fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
+{
+#ifdef INVOCATION_ID_INDEXING
+    let index = invocation_id.x;
+#else
+    let index = atomicAdd( &globals.compact_index, 1u );
+#endif
+    if ( index >= ray_buffer.ray_count ) {
+        return;
+    }
+
+    let r = ray_buffer.rays[index];
+    if (r.origin.x == VERY_FAR) {
+        return;
+    }
+
+    let queue_index = atomicAdd( &globals.queue_count, 1u );
+    ray_queue.indices[queue_index] = index;
+}
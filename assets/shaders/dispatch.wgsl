@@ -0,0 +1,64 @@
+let WORKGROUP_SIZE: u32 = 128u;
+
+struct globals_buf {
+    frame: u32,
+    render_width: u32,
+    render_height: u32,
+    samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
+    clear_index: atomic<u32>,
+    generate_index: atomic<u32>,
+    intersect_index: atomic<u32>,
+    collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
+};
+
+// Matches wgpu's DispatchIndirect arguments.
+struct dispatch_indirect {
+    x: u32,
+    y: u32,
+    z: u32,
+};
+
+// One set of arguments per queue: alive, then miss, diffuse, metal,
+// dielectric, emissive, principled and medium.
+struct dispatch_buf {
+    args: array<dispatch_indirect>,
+};
+
+@group(0) @binding(1)
+var<storage, read_write> globals: globals_buf;
+
+@group(1) @binding(0)
+var<storage, read_write> dispatch: dispatch_buf;
+
+fn workgroups( count: u32 ) -> dispatch_indirect {
+    return dispatch_indirect( (count + WORKGROUP_SIZE - 1u) / WORKGROUP_SIZE, 1u, 1u );
+}
+
+// Turn the number of rays the compact pass queued into workgroup counts for
+// intersect, and reset the queue for the next compaction.
+@compute @workgroup_size(1, 1, 1)
+fn dispatch_alive(@builtin(global_invocation_id) invocation_id: vec3<u32>)
+{
+    let alive = atomicLoad( &globals.queue_count );
+    atomicStore( &globals.queue_count, 0u );
+    globals.alive_count = alive;
+
+    dispatch.args[0] = workgroups( alive );
+}
+
+// Turn the number of hits intersect binned into each material queue into
+// workgroup counts for that queue's shade kernel.
+@compute @workgroup_size(1, 1, 1)
+fn dispatch_shade(@builtin(global_invocation_id) invocation_id: vec3<u32>)
+{
+    for ( var q=0u; q<7u; q=q+1u ) {
+        dispatch.args[q + 1u] = workgroups( atomicLoad( &globals.material_counts[q] ) );
+    }
+}
@@ -7,11 +7,22 @@ struct camera_config {
     forward: vec3<f32>,
     fov: f32,
     up: vec3<f32>,
-    image_plane_distance: f32,
+    focus_distance: f32,
     right: vec3<f32>,
-    lens_focal_length: f32,
+    aperture_radius: f32,
     position: vec3<f32>,
-    fstop: f32,    
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
 };
 
 struct globals_buf {
@@ -19,11 +30,17 @@ struct globals_buf {
     render_width: u32,
     render_height: u32,
     samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
     clear_index: atomic<u32>,
     generate_index: atomic<u32>,
     intersect_index: atomic<u32>,
-    shade_index: atomic<u32>,
     collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
 };
 
 struct ray {
@@ -33,6 +50,9 @@ struct ray {
     max: f32,
     pixel: u32,
     bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
 };
 
 struct ray_buf {
@@ -40,91 +60,116 @@ struct ray_buf {
     rays: array<ray>,
 };
 
-@group(0) @binding(0)
-var<uniform> camera: camera_config;
-
-@group(0) @binding(1)
-var<storage, read_write> globals: globals_buf;
-
-@group(1) @binding(0)
-var<storage, read_write> ray_buffer: ray_buf;
-
-fn hash3( ni: u32 ) -> vec3<f32>
-{
-    // integer hash copied from Hugo Elias
-    var n = ni;
-	n = (n << 13u) ^ n;
-    n = n * (n * n * 15731u + 789221u) + 1376312589u;
-    let k = n * vec3<u32>(n, n*16807u, n*48271u);
-
-    let l = vec3<u32>(0x7fffffffu);
-    let m = vec3<f32>(f32(k.x&l.x), f32(k.y&l.y), f32(k.z&l.z));
-    return m / f32(0x7fffffff);
-}
+struct intersection {
+    color: vec4<f32>,
+    position: vec3<f32>,
+    t: f32,
+    normal: vec3<f32>,
+    material: u32,
+    front_face: u32,
+    uv: vec2<f32>,
+    uv_density: f32,
+};
 
-// "Essential Ray Generation Shaders", McGuire & Majercik
-fn pinhole_ray( pixel: vec2<f32> ) -> ray {
-    let tan_half_angle = tan(camera.fov / 2.f);
-    var aspect_scale = 0.0;
-//    if ( camera.fov_dir == 0 ) {
-        aspect_scale = f32(globals.render_width);
-  //  } else {
-    //    aspect_scale = globals.render_height;
-    //}
+struct intersection_buf {
+    intersections: array<intersection>,
+};
 
-    let half_w = f32(globals.render_width) / 2.0;
-    let half_h = f32(globals.render_height) / 2.0;
+struct pixel_stats {
+    mean: vec3<f32>,
+    samples: u32,
+    m2: vec3<f32>,
+    converged_at: u32,
+};
 
-    var ray_dir = vec3<f32>( vec2<f32>(pixel.x - half_w, -pixel.y + half_h) * tan_half_angle / aspect_scale, -1.0);
-    let ray_dir = normalize( ray_dir );
+struct pixel_stats_buf {
+    pixels: array<pixel_stats>,
+};
 
-    let pixel_index = u32( pixel.y * f32(globals.render_width) + pixel.x );
-    return ray( vec3<f32>(0.f), EPSILON, ray_dir, VERY_FAR, pixel_index, 0u );
-}
+struct ray_stats_buf {
+    primary_rays: atomic<u32>,
+    intersection_tests_low: atomic<u32>,
+    intersection_tests_high: atomic<u32>,
+    alive_rays: array<atomic<u32>, 32>,
+    terminated_rays: array<atomic<u32>, 32>,
+};
 
-fn thin_lens_ray( pixel: vec2<f32>, lens_offset: vec2<f32> ) -> ray {
-    var ray = pinhole_ray( pixel );
+struct blue_noise_buf {
+    values: array<f32, 4096>,
+};
 
-    let theta = lens_offset.x + 2.f * PI;
-    let radius = lens_offset.y;
+@group(0) @binding(0)
+var<uniform> camera: camera_config;
 
-    let u = cos(theta) * sqrt(radius);
-    let v = sin(theta) * sqrt(radius);
+@group(0) @binding(1)
+var<storage, read_write> globals: globals_buf;
 
-    let focus_plane = (camera.image_plane_distance * camera.lens_focal_length) /
-    (camera.image_plane_distance - camera.lens_focal_length);
+@group(0) @binding(2)
+var<storage, read> blue_noise: blue_noise_buf;
 
-    let focus_point = ray.dir * (focus_plane / dot(ray.dir, vec3<f32>(0.f, 0.f, -1.f)));
+@group(0) @binding(3)
+var<storage, read_write> ray_stats: ray_stats_buf;
 
-    let circle_of_confusion_radius = camera.lens_focal_length / (2.f * camera.fstop);
+@group(1) @binding(0)
+var<storage, read_write> ray_buffer: ray_buf;
 
-    ray.origin = vec3<f32>(1.f, 0.f, 0.f) * (u * circle_of_confusion_radius) +
-    vec3<f32>(0.f, 1.f, 0.f) * (v * circle_of_confusion_radius);
+@group(1) @binding(1)
+var<storage, read_write> intersection_buffer: intersection_buf;
 
-    ray.dir = normalize(focus_point - ray.origin);
+@group(1) @binding(5)
+var<storage, read_write> pixel_stats_buffer: pixel_stats_buf;
 
-    return ray;
-}
+#import bevy_raytrace::sampler
+#import bevy_raytrace::ray_stats
+#import bevy_raytrace::camera_rays
 
-@compute @workgroup_size(128, 1, 1)
-fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
-{
+fn generate_ray( invocation_id: vec3<u32> ) {
+#ifdef INVOCATION_ID_INDEXING
+    let index = invocation_id.x;
+#else
     let index = atomicAdd( &globals.generate_index, 1u );
+#endif
     if ( index >= ray_buffer.ray_count ) {
         return;
     }
 
     let x = index % globals.render_width;
     let y = (index / globals.render_width) % globals.render_height;
-    let pixel = vec2<f32>(f32(x), f32(y));
+    let pixel = vec2<f32>(f32(x), f32(y)) + sample_offset(index);
 
-    let lens_offset = vec2<f32>(0.0f, 0.0f);
-    //var pray = pinhole_ray(pixel);
-    var pray = thin_lens_ray(pixel, lens_offset);
+    var lens_sampler = sampler_for( index, DIM_LENS );
+    let lens_sample = sample_2d( &lens_sampler );
+    var pray = camera_ray(pixel, lens_sample);
+    if ( pixel_skipped( index % (globals.render_width * globals.render_height) ) ) {
+        pray = no_ray( pixel );
+    }
 
-    pray.origin += camera.transform.w.xyz;
+    // The ray's slot in the buffers, sample * width * height + pixel, which
+    // also picks its sampler.
+    pray.pixel = index;
+
+    if ( pray.origin.x == VERY_FAR ) {
+        storageBarrier();
+        ray_buffer.rays[index] = pray;
+        intersection_buffer.intersections[index].color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    pray.origin = (camera.transform * vec4<f32>(pray.origin, 1.0)).xyz;
     pray.dir = (camera.transform * vec4<f32>(pray.dir, 0.0)).xyz;
 
+    count_primary_ray();
+
     storageBarrier();
     ray_buffer.rays[index] = pray;
-}   
\ No newline at end of file
+}   
+
+@compute @workgroup_size(128, 1, 1)
+fn main(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    generate_ray( invocation_id );
+    end_ray_stats( local_index );
+}
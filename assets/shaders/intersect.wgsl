@@ -2,16 +2,35 @@ let VERY_FAR: f32 = 1e20f;
 let EPSILON: f32 = 0.001;
 let PI:f32 = 3.14159265358979;
 
+let QUEUE_MISS: u32 = 0u;
+let QUEUE_DIFFUSE: u32 = 1u;
+let QUEUE_METAL: u32 = 2u;
+let QUEUE_DIELECTRIC: u32 = 3u;
+let QUEUE_EMISSIVE: u32 = 4u;
+let QUEUE_PRINCIPLED: u32 = 5u;
+let QUEUE_MEDIUM: u32 = 6u;
+
 struct camera_config {
     transform: mat4x4<f32>,
     forward: vec3<f32>,
     fov: f32,
     up: vec3<f32>,
-    pad0: f32,
+    focus_distance: f32,
     right: vec3<f32>,
-    pad1: f32,
+    aperture_radius: f32,
     position: vec3<f32>,
-    pad2: f32,
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
 };
 
 struct globals_buf {
@@ -19,11 +38,17 @@ struct globals_buf {
     render_width: u32,
     render_height: u32,
     samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
     clear_index: atomic<u32>,
     generate_index: atomic<u32>,
     intersect_index: atomic<u32>,
-    shade_index: atomic<u32>,
     collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
 };
 
 struct ray {
@@ -33,6 +58,9 @@ struct ray {
     max: f32,
     pixel: u32,
     bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
 };
 
 struct ray_buf {
@@ -47,12 +75,18 @@ struct intersection {
     normal: vec3<f32>,
     material: u32,
     front_face: u32,
+    uv: vec2<f32>,
+    uv_density: f32,
 };
 
 struct intersection_buf {
     intersections: array<intersection>,
 };
 
+struct ray_queue_buf {
+    indices: array<u32>,
+};
+
 struct sphere {
     center: vec3<f32>,
     radius: f32,
@@ -64,100 +98,175 @@ struct object_list {
     spheres: array<sphere>,
 };
 
+struct medium {
+    center: vec3<f32>,
+    radius: f32,
+    density: f32,
+    material: u32,
+};
+
+struct medium_list {
+    fog_density: f32,
+    fog_height: f32,
+    fog_falloff: f32,
+    fog_material: u32,
+    medium_count: u32,
+    media: array<medium>,
+};
+
+struct grid_medium {
+    world_to_local: mat4x4<f32>,
+    density: f32,
+    material: u32,
+};
+
+struct grid_medium_list {
+    grid_count: u32,
+    grids: array<grid_medium>,
+};
+
+struct material {
+    color: vec4<f32>,
+    reflectance: i32,
+    fuzziness: f32,
+    index_of_refraction: f32,
+    metallic: f32,
+    roughness: f32,
+    specular: f32,
+    base_color_texture: i32,
+    metallic_roughness_texture: i32,
+    emissive_texture: i32,
+    normal_map_texture: i32,
+    procedural: i32,
+    procedural_scale: f32,
+    procedural_color: vec4<f32>,
+    procedural_depth: u32,
+    absorption_density: f32,
+    cauchy_b: f32,
+    anisotropy: f32,
+    absorption: vec4<f32>,
+}
+
+struct material_buf {
+    m: array<material>,
+}
+
+struct ray_stats_buf {
+    primary_rays: atomic<u32>,
+    intersection_tests_low: atomic<u32>,
+    intersection_tests_high: atomic<u32>,
+    alive_rays: array<atomic<u32>, 32>,
+    terminated_rays: array<atomic<u32>, 32>,
+};
+
+struct blue_noise_buf {
+    values: array<f32, 4096>,
+};
+
 @group(0) @binding(0)
 var<uniform> camera: camera_config;
 
 @group(0) @binding(1)
 var<storage, read_write> globals: globals_buf;
 
+@group(0) @binding(2)
+var<storage, read> blue_noise: blue_noise_buf;
+
+@group(0) @binding(3)
+var<storage, read_write> ray_stats: ray_stats_buf;
+
 @group(1) @binding(0)
 var<storage, read_write> ray_buffer: ray_buf;
 
 @group(1) @binding(1)
 var<storage, read_write> intersection_buffer: intersection_buf;
 
+@group(1) @binding(2)
+var<storage, read_write> ray_queue: ray_queue_buf;
+
+@group(1) @binding(3)
+var<storage, read_write> material_queues: ray_queue_buf;
+
 @group(2) @binding(0)
 var<storage, read> objects: object_list;
 
-fn point_at(r: ray, t: f32) -> vec3<f32> {
-    return r.origin + r.dir * t;
-}
-
-fn default_intersection() -> intersection {
-    return intersection ( vec4<f32>(1.0), vec3<f32>(0.0), VERY_FAR, vec3<f32>(0.0), 0u, 0u );
-}
+@group(2) @binding(1)
+var<storage, read> materials: material_buf;
 
-fn sqr( x: f32 ) -> f32 {
-    return x*x;
-}
+@group(2) @binding(5)
+var<storage, read> media: medium_list;
 
-fn intersect_sphere(r: ray, s: sphere) -> intersection {
-    var i = default_intersection();
+@group(2) @binding(6)
+var<storage, read> grid_media: grid_medium_list;
 
-    let oc = r.origin - s.center;
-    let a = sqr(length(r.dir));
-    let half_b = dot(oc, r.dir);
-    let c = sqr(length(oc)) - sqr(s.radius);
+@group(2) @binding(7)
+var density_grid: texture_3d<f32>;
 
-    let dis = sqr(half_b) - a*c;
-    if ( dis < 0.0 ) {
-        return i;
-    }
+@group(2) @binding(8)
+var density_sampler: sampler;
 
-    let sqrtd = sqrt(dis);
+#import bevy_raytrace::sampler
+#import bevy_raytrace::ray_stats
+#import bevy_raytrace::intersection
 
-    var root = (-half_b - sqrtd) / a;
-    if ( root < r.min || r.max < root ) {
-        root = (-half_b + sqrtd) / a;
-        if ( root < r.min || r.max < root ) {
-            return i;
-        }
+// Which material queue shades this hit.
+fn material_queue(i: intersection) -> u32 {
+    if ( i.t == VERY_FAR ) {
+        return QUEUE_MISS;
     }
 
-    i.t = root;
-    i.position = point_at(r, root);
-    i.normal = normalize((i.position - s.center) / s.radius);
-    i.front_face = 1u;
-
-    if ( dot(r.dir, i.normal) > 0.0) {
-        i.normal = -i.normal;
-        i.front_face = 0u;
+    let reflectance = materials.m[i.material].reflectance;
+    if ( reflectance == 1 ) {
+        return QUEUE_METAL;
+    } else if ( reflectance == 2 ) {
+        return QUEUE_DIELECTRIC;
+    } else if ( reflectance == 3 ) {
+        return QUEUE_EMISSIVE;
+    } else if ( reflectance == 4 ) {
+        return QUEUE_PRINCIPLED;
+    } else if ( reflectance == 5 ) {
+        return QUEUE_MEDIUM;
     }
 
-    i.material = s.material;
-
-    return i;
+    return QUEUE_DIFFUSE;
 }
 
-// Brute force. The world isn't partitioned in any way.
-fn intersect_world(r: ray) -> intersection {
-    var closest_hit = default_intersection();
-    for(var i: i32 = 0; i < i32(objects.sphere_count); i = i + 1 ) {
-        let hit = intersect_sphere( r, objects.spheres[i] );
-        if ( hit.t < closest_hit.t ) {
-            closest_hit = hit;
-        }        
-    }
-
-    return closest_hit;
-}
-
-@compute @workgroup_size(128, 1, 1)
-fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
-{
-    let index = atomicAdd( &globals.intersect_index, 1u );
-    if ( index >= ray_buffer.ray_count ) {
+fn intersect_ray( invocation_id: vec3<u32> ) {
+#ifdef INVOCATION_ID_INDEXING
+    let queue_index = invocation_id.x;
+#else
+    let queue_index = atomicAdd( &globals.intersect_index, 1u );
+#endif
+    if ( queue_index >= globals.alive_count ) {
         return;
     }
 
+    let index = ray_queue.indices[queue_index];
+
     let r = ray_buffer.rays[index];
     if (r.origin.x == VERY_FAR) {
         return;
     }
 
+    count_alive_ray( r.bounces );
+
     var i = intersect_world(r);
     i.color = intersection_buffer.intersections[index].color;
 
     storageBarrier();
     intersection_buffer.intersections[index] = i;
-}
\ No newline at end of file
+
+    let queue = material_queue(i);
+    let material_slot = atomicAdd( &globals.material_counts[queue], 1u );
+    material_queues.indices[queue * ray_buffer.ray_count + material_slot] = index;
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn main(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    intersect_ray( invocation_id );
+    end_ray_stats( local_index );
+}
@@ -0,0 +1,217 @@
+#define_import_path bevy_raytrace::intersection
+
+// Imported after the bindings and bevy_raytrace::ray_stats, as it reads the
+// importer's objects and media and counts intersection tests.
+//
+// Closest hits against the spheres and media in the scene, shared by the
+// intersect pass and the megakernel.
+
+let MAX_DELTA_TRACKING_STEPS: u32 = 256u;
+
+fn point_at(r: ray, t: f32) -> vec3<f32> {
+    return r.origin + r.dir * t;
+}
+
+fn default_intersection() -> intersection {
+    return intersection ( vec4<f32>(1.0), vec3<f32>(0.0), VERY_FAR, vec3<f32>(0.0), 0u, 0u, vec2<f32>(0.0), 1.0 );
+}
+
+fn sqr( x: f32 ) -> f32 {
+    return x*x;
+}
+
+fn intersect_sphere(r: ray, s: sphere) -> intersection {
+    var i = default_intersection();
+
+    let oc = r.origin - s.center;
+    let a = sqr(length(r.dir));
+    let half_b = dot(oc, r.dir);
+    let c = sqr(length(oc)) - sqr(s.radius);
+
+    let dis = sqr(half_b) - a*c;
+    if ( dis < 0.0 ) {
+        return i;
+    }
+
+    let sqrtd = sqrt(dis);
+
+    var root = (-half_b - sqrtd) / a;
+    if ( root < r.min || r.max < root ) {
+        root = (-half_b + sqrtd) / a;
+        if ( root < r.min || r.max < root ) {
+            return i;
+        }
+    }
+
+    i.t = root;
+    i.position = point_at(r, root);
+    i.normal = normalize((i.position - s.center) / s.radius);
+    i.front_face = 1u;
+
+    // Spherical uv from the outward normal, u around y and v from the bottom pole.
+    let theta = acos( clamp(-i.normal.y, -1.0, 1.0) );
+    let phi = atan2( -i.normal.z, i.normal.x ) + PI;
+    i.uv = vec2<f32>( phi / (2.0 * PI), theta / PI );
+    i.uv_density = PI * s.radius * sqrt(2.0);
+
+    if ( dot(r.dir, i.normal) > 0.0) {
+        i.normal = -i.normal;
+        i.front_face = 0u;
+    }
+
+    i.material = s.material;
+
+    return i;
+}
+
+// Entry and exit distance of the ray through a sphere, clamped to the ray's
+// extent. Starts at the ray's min when the origin is inside, and is empty
+// when entry is not before exit.
+fn sphere_span( r: ray, center: vec3<f32>, radius: f32 ) -> vec2<f32> {
+    let oc = r.origin - center;
+    let a = sqr(length(r.dir));
+    let half_b = dot(oc, r.dir);
+    let c = sqr(length(oc)) - sqr(radius);
+
+    let dis = sqr(half_b) - a*c;
+    if ( dis < 0.0 ) {
+        return vec2<f32>(VERY_FAR, 0.0);
+    }
+
+    let sqrtd = sqrt(dis);
+    return vec2<f32>( max((-half_b - sqrtd) / a, r.min), min((-half_b + sqrtd) / a, r.max) );
+}
+
+fn scatter( r: ray, t: f32, material: u32 ) -> intersection {
+    var i = default_intersection();
+    i.t = t;
+    i.position = point_at(r, t);
+    i.normal = -normalize(r.dir);
+    i.front_face = 1u;
+    i.material = material;
+    return i;
+}
+
+// Distance sampling needs fresh numbers every bounce, unlike shading.
+// PCG random numbers, for when a loop needs an unknown number of them.
+fn next_random( state: ptr<function, u32> ) -> f32 {
+    *state = *state * 747796405u + 2891336453u;
+    var word = ((*state >> ((*state >> 28u) + 4u)) ^ *state) * 277803737u;
+    word = (word >> 22u) ^ word;
+    return f32(word) / 4294967295.0;
+}
+
+// Entry and exit distance of the ray through the unit cube around the origin,
+// clamped to [t_min, t_max].
+fn unit_box_span( origin: vec3<f32>, dir: vec3<f32>, t_min: f32, t_max: f32 ) -> vec2<f32> {
+    let inv_dir = 1.0 / dir;
+    let t0 = (vec3<f32>(-0.5) - origin) * inv_dir;
+    let t1 = (vec3<f32>(0.5) - origin) * inv_dir;
+    let near = min(t0, t1);
+    let far = max(t0, t1);
+    return vec2<f32>( max( max(near.x, near.y), max(near.z, t_min) ), min( min(far.x, far.y), min(far.z, t_max) ) );
+}
+
+// Delta tracking through each density grid. Tentative collisions are sampled
+// against the grid's densest extinction, and accepted as real scattering with
+// probability of the density there over that majorant.
+fn intersect_grid_media( r: ray, closest_hit: intersection, seed: u32 ) -> intersection {
+    var state = seed;
+
+    var hit = closest_hit;
+    for(var g: i32 = 0; g < i32(grid_media.grid_count); g = g + 1 ) {
+        let grid = grid_media.grids[g];
+
+        // The transform is affine, so t means the same in grid space.
+        let origin = (grid.world_to_local * vec4<f32>(r.origin, 1.0)).xyz;
+        let dir = (grid.world_to_local * vec4<f32>(r.dir, 0.0)).xyz;
+        let span = unit_box_span( origin, dir, r.min, hit.t );
+        if ( span.x >= span.y ) {
+            continue;
+        }
+
+        var t = span.x;
+        for ( var step = 0u; step < MAX_DELTA_TRACKING_STEPS; step = step + 1u ) {
+            t = t - log( max(1.0 - next_random(&state), 1e-7) ) / grid.density;
+            if ( t >= span.y ) {
+                break;
+            }
+
+            let uvw = origin + dir * t + vec3<f32>(0.5);
+            let density = textureSampleLevel( density_grid, density_sampler, uvw, 0.0 ).r;
+            if ( next_random(&state) < density ) {
+                hit = scatter( r, t, grid.material );
+                break;
+            }
+        }
+    }
+
+    return hit;
+}
+
+// Exponentially sample the distance to where the height fog scatters the ray,
+// for an optical depth drawn as -log(u). The optical depth along the ray has
+// a closed form for exponential density, so it is inverted directly.
+fn sample_height_fog( r: ray, optical_depth: f32 ) -> f32 {
+    let density = media.fog_density * exp( -media.fog_falloff * (r.origin.y - media.fog_height) );
+    let c = media.fog_falloff * r.dir.y;
+    if ( abs(c) < 1e-5 ) {
+        return optical_depth / density;
+    }
+
+    // Heading up, the fog can thin out before the optical depth is reached.
+    let x = 1.0 - optical_depth * c / density;
+    if ( x <= 0.0 ) {
+        return VERY_FAR;
+    }
+
+    return -log(x) / c;
+}
+
+// Sample a scattering distance in every medium the ray passes through before
+// the closest surface, and scatter in the medium instead if that comes first.
+fn intersect_media( r: ray, closest_hit: intersection ) -> intersection {
+    var samp = bounce_sampler( r, DIM_MEDIA );
+
+    var hit = intersect_grid_media( r, closest_hit, sample_seed(&samp) );
+    let medium_sample = sample_1d( &samp );
+    for(var m: i32 = 0; m < i32(media.medium_count); m = m + 1 ) {
+        let medium = media.media[m];
+        let span = sphere_span( r, medium.center, medium.radius );
+        let exit = min( span.y, hit.t );
+        if ( span.x >= exit ) {
+            continue;
+        }
+
+        // Decorrelate the media from each other with a golden ratio offset.
+        let u = fract( medium_sample + f32(m) * 0.618034 );
+        let travel = -log( max(1.0 - u, 1e-7) ) / medium.density;
+        if ( span.x + travel < exit ) {
+            hit = scatter( r, span.x + travel, medium.material );
+        }
+    }
+
+    if ( media.fog_density > 0.0 ) {
+        let t = sample_height_fog( r, -log( max(1.0 - sample_1d(&samp), 1e-7) ) );
+        if ( t > r.min && t < hit.t ) {
+            hit = scatter( r, t, media.fog_material );
+        }
+    }
+
+    return hit;
+}
+
+// Brute force. The world isn't partitioned in any way.
+fn intersect_world(r: ray) -> intersection {
+    count_intersection_tests( objects.sphere_count + media.medium_count + grid_media.grid_count );
+
+    var closest_hit = default_intersection();
+    for(var i: i32 = 0; i < i32(objects.sphere_count); i = i + 1 ) {
+        let hit = intersect_sphere( r, objects.spheres[i] );
+        if ( hit.t < closest_hit.t ) {
+            closest_hit = hit;
+        }        
+    }
+
+    return intersect_media( r, closest_hit );
+}
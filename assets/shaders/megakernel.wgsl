@@ -0,0 +1,349 @@
+let VERY_FAR: f32 = 1e20f;
+let EPSILON: f32 = 0.001;
+let PI:f32 = 3.14159265358979;
+
+let DEBUG_NONE: u32 = 0u;
+
+struct camera_config {
+    transform: mat4x4<f32>,
+    forward: vec3<f32>,
+    fov: f32,
+    up: vec3<f32>,
+    focus_distance: f32,
+    right: vec3<f32>,
+    aperture_radius: f32,
+    position: vec3<f32>,
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
+};
+
+struct globals_buf {
+    frame: u32,
+    render_width: u32,
+    render_height: u32,
+    samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
+    clear_index: atomic<u32>,
+    generate_index: atomic<u32>,
+    intersect_index: atomic<u32>,
+    collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
+};
+
+struct ray {
+    origin: vec3<f32>,
+    min: f32,
+    dir: vec3<f32>,
+    max: f32,
+    pixel: u32,
+    bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
+};
+
+struct ray_buf {
+    ray_count: u32,
+    rays: array<ray>,
+};
+
+struct intersection {
+    color: vec4<f32>,
+    position: vec3<f32>,
+    t: f32,
+    normal: vec3<f32>,
+    material: u32,
+    front_face: u32,
+    uv: vec2<f32>,
+    uv_density: f32,
+};
+
+struct intersection_buf {
+    intersections: array<intersection>,
+};
+
+struct pixel_stats {
+    mean: vec3<f32>,
+    samples: u32,
+    m2: vec3<f32>,
+    converged_at: u32,
+};
+
+struct pixel_stats_buf {
+    pixels: array<pixel_stats>,
+};
+
+struct sphere {
+    center: vec3<f32>,
+    radius: f32,
+    material: u32,
+};
+
+struct object_list {
+    sphere_count: u32,
+    spheres: array<sphere>,
+};
+
+struct medium {
+    center: vec3<f32>,
+    radius: f32,
+    density: f32,
+    material: u32,
+};
+
+struct medium_list {
+    fog_density: f32,
+    fog_height: f32,
+    fog_falloff: f32,
+    fog_material: u32,
+    medium_count: u32,
+    media: array<medium>,
+};
+
+struct grid_medium {
+    world_to_local: mat4x4<f32>,
+    density: f32,
+    material: u32,
+};
+
+struct grid_medium_list {
+    grid_count: u32,
+    grids: array<grid_medium>,
+};
+
+struct material {
+    color: vec4<f32>,
+    reflectance: i32,
+    fuzziness: f32,
+    index_of_refraction: f32,
+    metallic: f32,
+    roughness: f32,
+    specular: f32,
+    base_color_texture: i32,
+    metallic_roughness_texture: i32,
+    emissive_texture: i32,
+    normal_map_texture: i32,
+    procedural: i32,
+    procedural_scale: f32,
+    procedural_color: vec4<f32>,
+    procedural_depth: u32,
+    absorption_density: f32,
+    cauchy_b: f32,
+    anisotropy: f32,
+    absorption: vec4<f32>,
+}
+
+struct material_buf {
+    m: array<material>,
+}
+
+struct perlin_buf {
+    ranvec: array<vec4<f32>, 256>,
+    perm_x: array<u32, 256>,
+    perm_y: array<u32, 256>,
+    perm_z: array<u32, 256>,
+}
+
+struct shade {
+    // Light emitted toward the camera if the path ends here, otherwise the
+    // attenuation along the extension ray.
+    color: vec4<f32>,
+    extension: ray,
+}
+
+struct ray_stats_buf {
+    primary_rays: atomic<u32>,
+    intersection_tests_low: atomic<u32>,
+    intersection_tests_high: atomic<u32>,
+    alive_rays: array<atomic<u32>, 32>,
+    terminated_rays: array<atomic<u32>, 32>,
+};
+
+struct blue_noise_buf {
+    values: array<f32, 4096>,
+};
+
+@group(0) @binding(0)
+var<uniform> camera: camera_config;
+
+@group(0) @binding(1)
+var<storage, read_write> globals: globals_buf;
+
+@group(0) @binding(2)
+var<storage, read> blue_noise: blue_noise_buf;
+
+@group(0) @binding(3)
+var<storage, read_write> ray_stats: ray_stats_buf;
+
+@group(1) @binding(0)
+var<storage, read_write> ray_buffer: ray_buf;
+
+@group(1) @binding(1)
+var<storage, read_write> intersection_buffer: intersection_buf;
+
+@group(1) @binding(5)
+var<storage, read_write> pixel_stats_buffer: pixel_stats_buf;
+
+@group(2) @binding(0)
+var<storage, read> objects: object_list;
+
+@group(2) @binding(1)
+var<storage, read> materials: material_buf;
+
+@group(2) @binding(2)
+var material_textures: texture_2d_array<f32>;
+
+@group(2) @binding(3)
+var material_sampler: sampler;
+
+@group(2) @binding(4)
+var<storage, read> perlin: perlin_buf;
+
+@group(2) @binding(5)
+var<storage, read> media: medium_list;
+
+@group(2) @binding(6)
+var<storage, read> grid_media: grid_medium_list;
+
+@group(2) @binding(7)
+var density_grid: texture_3d<f32>;
+
+@group(2) @binding(8)
+var density_sampler: sampler;
+
+#import bevy_raytrace::sampler
+#import bevy_raytrace::ray_stats
+#import bevy_raytrace::camera_rays
+#import bevy_raytrace::intersection
+#import bevy_raytrace::shading
+
+fn shade_hit( r: ray, hit: intersection, samp: ptr<function, sampler_state> ) -> shade {
+    let s = surface_at(r, hit);
+    let i = s.i;
+    let material = s.m;
+    if ( material.reflectance == 1 ) {
+        return metallic(r, i, material, samp);
+    } else if ( material.reflectance == 2 ) {
+        return dielectric(r, i, material, samp);
+    } else if ( material.reflectance == 3 ) {
+        return emissive(r, material);
+    } else if ( material.reflectance == 4 ) {
+        return principled(r, i, material, samp);
+    } else if ( material.reflectance == 5 ) {
+        return volume(r, i, material, samp);
+    }
+
+    return lambertian(r, i, material, samp);
+}
+
+fn trace_path( invocation_id: vec3<u32> ) {
+    // The megakernel replaces generate, so it takes its work from the same counter.
+#ifdef INVOCATION_ID_INDEXING
+    let index = invocation_id.x;
+#else
+    let index = atomicAdd( &globals.generate_index, 1u );
+#endif
+    if ( index >= ray_buffer.ray_count ) {
+        return;
+    }
+
+    let x = index % globals.render_width;
+    let y = (index / globals.render_width) % globals.render_height;
+    let pixel = vec2<f32>(f32(x), f32(y)) + sample_offset(index);
+
+    var lens_sampler = sampler_for( index, DIM_LENS );
+    let lens_sample = sample_2d( &lens_sampler );
+    var r = camera_ray(pixel, lens_sample);
+    if ( pixel_skipped( index % (globals.render_width * globals.render_height) ) ) {
+        r = no_ray( pixel );
+    }
+
+    // The ray's slot in the buffers, sample * width * height + pixel, which
+    // also picks its sampler.
+    r.pixel = index;
+
+    if ( r.origin.x == VERY_FAR ) {
+        storageBarrier();
+        ray_buffer.rays[index] = r;
+        intersection_buffer.intersections[index].color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    r.origin = (camera.transform * vec4<f32>(r.origin, 1.0)).xyz;
+    r.dir = (camera.transform * vec4<f32>(r.dir, 0.0)).xyz;
+
+    count_primary_ray();
+
+    var radiance = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+    path_roughness = 0.0;
+
+    for ( var bounce=0u; bounce<=camera.max_bounces; bounce=bounce+1u ) {
+        count_alive_ray( bounce );
+        let i = intersect_world(r);
+
+        // Debug views read the first hit back from the intersection buffer,
+        // as the wavefront passes leave it there.
+        if ( bounce == 0u && globals.debug_view != DEBUG_NONE ) {
+            intersection_buffer.intersections[index] = i;
+        }
+
+        var s: shade;
+        if ( i.t == VERY_FAR ) {
+            s = miss(r);
+        } else if ( max_bounces_reached(r) ) {
+            s = absorb(r);
+        } else {
+            var samp = bounce_sampler( r, DIM_SHADE );
+            s = shade_hit(r, i, &samp);
+        }
+
+        throughput *= s.color.xyz;
+        r = s.extension;
+
+        if ( r.origin.x == VERY_FAR ) {
+            radiance += clamp_contribution( r, throughput );
+            count_terminated_ray( bounce );
+            break;
+        }
+
+        let weight = roulette_weight( r, throughput );
+        if ( weight == 0.0 ) {
+            r = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces, 0.0, 0.0, r.channel );
+            count_terminated_ray( bounce );
+            break;
+        }
+        throughput = throughput * weight;
+    }
+
+    storageBarrier();
+    ray_buffer.rays[index] = r;
+    intersection_buffer.intersections[index].color = vec4<f32>( radiance, 1.0 );
+}
+
+// Generate, trace and shade a whole path in one thread. Gives the same image
+// as the wavefront passes but keeps the path in registers between bounces.
+@compute @workgroup_size(128, 1, 1)
+fn main(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    trace_path( invocation_id );
+    end_ray_stats( local_index );
+}
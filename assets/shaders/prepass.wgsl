@@ -7,11 +7,22 @@ struct camera_config {
     forward: vec3<f32>,
     fov: f32,
     up: vec3<f32>,
-    pad0: f32,
+    focus_distance: f32,
     right: vec3<f32>,
-    pad1: f32,
+    aperture_radius: f32,
     position: vec3<f32>,
-    pad2: f32,
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
 };
 
 struct globals_buf {
@@ -19,11 +30,17 @@ struct globals_buf {
     render_width: u32,
     render_height: u32,
     samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
     clear_index: atomic<u32>,
     generate_index: atomic<u32>,
     intersect_index: atomic<u32>,
-    shade_index: atomic<u32>,
     collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
 };
 
 struct ray {
@@ -33,6 +50,9 @@ struct ray {
     max: f32,
     pixel: u32,
     bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
 };
 
 struct ray_buf {
@@ -58,6 +78,11 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
     globals.clear_index = 0u;
     globals.generate_index = 0u;
     globals.intersect_index = 0u;
-    globals.shade_index = 0u;
     globals.collect_index = 0u;
+    globals.compact_index = 0u;
+
+    for ( var q=0u; q<7u; q=q+1u ) {
+        atomicStore( &globals.material_counts[q], 0u );
+        atomicStore( &globals.shade_indices[q], 0u );
+    }
 }
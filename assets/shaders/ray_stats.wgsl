@@ -0,0 +1,99 @@
+#define_import_path bevy_raytrace::ray_stats
+
+// Imported after the bindings, as it adds to the importer's ray_stats.
+//
+// Kernels that count rays run their work between begin_ray_stats and
+// end_ray_stats, both called by every invocation of the workgroup. Counts go
+// to workgroup memory, and end_ray_stats adds each total to ray_stats once per
+// workgroup rather than once per ray. Nothing is counted unless RAY_STATS is
+// set, which it is while the diagnostics overlay is shown.
+
+// Matches MAX_STATS_BOUNCES in ray_trace_stats.rs.
+let MAX_STATS_BOUNCES: u32 = 32u;
+
+#ifdef RAY_STATS
+var<workgroup> workgroup_primary_rays: atomic<u32>;
+var<workgroup> workgroup_intersection_tests: atomic<u32>;
+var<workgroup> workgroup_alive_rays: array<atomic<u32>, 32>;
+var<workgroup> workgroup_terminated_rays: array<atomic<u32>, 32>;
+#endif
+
+// Bounces past the last stats entry are counted in it.
+fn stats_bounce( bounces: u32 ) -> u32 {
+    return min( bounces, MAX_STATS_BOUNCES - 1u );
+}
+
+// Workgroups are at least MAX_STATS_BOUNCES invocations, so each of the first
+// ones looks after one bounce's counters.
+fn begin_ray_stats( local_index: u32 ) {
+#ifdef RAY_STATS
+    if ( local_index == 0u ) {
+        atomicStore( &workgroup_primary_rays, 0u );
+        atomicStore( &workgroup_intersection_tests, 0u );
+    }
+    if ( local_index < MAX_STATS_BOUNCES ) {
+        atomicStore( &workgroup_alive_rays[local_index], 0u );
+        atomicStore( &workgroup_terminated_rays[local_index], 0u );
+    }
+    workgroupBarrier();
+#endif
+}
+
+fn count_primary_ray() {
+#ifdef RAY_STATS
+    atomicAdd( &workgroup_primary_rays, 1u );
+#endif
+}
+
+fn count_intersection_tests( tests: u32 ) {
+#ifdef RAY_STATS
+    atomicAdd( &workgroup_intersection_tests, tests );
+#endif
+}
+
+fn count_alive_ray( bounces: u32 ) {
+#ifdef RAY_STATS
+    atomicAdd( &workgroup_alive_rays[stats_bounce(bounces)], 1u );
+#endif
+}
+
+fn count_terminated_ray( bounces: u32 ) {
+#ifdef RAY_STATS
+    atomicAdd( &workgroup_terminated_rays[stats_bounce(bounces)], 1u );
+#endif
+}
+
+fn end_ray_stats( local_index: u32 ) {
+#ifdef RAY_STATS
+    workgroupBarrier();
+
+    if ( local_index == 0u ) {
+        let primary = atomicLoad( &workgroup_primary_rays );
+        if ( primary > 0u ) {
+            atomicAdd( &ray_stats.primary_rays, primary );
+        }
+
+        // The count is split in two words, the low one carrying into the high
+        // one when it wraps.
+        let tests = atomicLoad( &workgroup_intersection_tests );
+        if ( tests > 0u ) {
+            let low = atomicAdd( &ray_stats.intersection_tests_low, tests );
+            if ( low > 0xffffffffu - tests ) {
+                atomicAdd( &ray_stats.intersection_tests_high, 1u );
+            }
+        }
+    }
+
+    if ( local_index < MAX_STATS_BOUNCES ) {
+        let alive = atomicLoad( &workgroup_alive_rays[local_index] );
+        if ( alive > 0u ) {
+            atomicAdd( &ray_stats.alive_rays[local_index], alive );
+        }
+
+        let terminated = atomicLoad( &workgroup_terminated_rays[local_index] );
+        if ( terminated > 0u ) {
+            atomicAdd( &ray_stats.terminated_rays[local_index], terminated );
+        }
+    }
+#endif
+}
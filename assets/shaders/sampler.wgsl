@@ -0,0 +1,160 @@
+#define_import_path bevy_raytrace::sampler
+
+// Imported by every kernel that draws random numbers, after its bindings.
+// Reads camera.sampler_kind, the render size and frame from globals, the
+// blue_noise tile, and the importer's ray struct.
+
+// Every random number a path uses comes from a sampler, addressed by the
+// pixel, the sample index of that pixel across frames, and a dimension. Each
+// decision in a path gets its own dimension, so low discrepancy sequences
+// stay stratified per decision instead of being reused.
+let SAMPLER_INDEPENDENT: u32 = 0u;
+let SAMPLER_SOBOL: u32 = 1u;
+let SAMPLER_BLUE_NOISE: u32 = 2u;
+
+// Dimensions of the camera ray, then a fixed budget per bounce so each kernel
+// of the wavefront knows where its dimensions start.
+let DIM_PIXEL: u32 = 0u;
+let DIM_LENS: u32 = 2u;
+let DIM_BOUNCE: u32 = 4u;
+let DIMS_PER_BOUNCE: u32 = 8u;
+let DIM_MEDIA: u32 = 0u;
+let DIM_SHADE: u32 = 3u;
+let DIM_ROULETTE: u32 = 7u;
+
+let BLUE_NOISE_SIZE: u32 = 64u;
+
+struct sampler_state {
+    pixel: u32,
+    index: u32,
+    dimension: u32,
+};
+
+fn pcg_hash( v: u32 ) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// The top 24 bits, so the result is exactly representable and below 1.
+fn unit_float( x: u32 ) -> f32 {
+    return f32(x >> 8u) / 16777216.0;
+}
+
+fn reverse_bits( v: u32 ) -> u32 {
+    var x = v;
+    x = ((x >> 1u) & 0x55555555u) | ((x & 0x55555555u) << 1u);
+    x = ((x >> 2u) & 0x33333333u) | ((x & 0x33333333u) << 2u);
+    x = ((x >> 4u) & 0x0f0f0f0fu) | ((x & 0x0f0f0f0fu) << 4u);
+    x = ((x >> 8u) & 0x00ff00ffu) | ((x & 0x00ff00ffu) << 8u);
+    return (x >> 16u) | (x << 16u);
+}
+
+// The second Sobol dimension. The first is just reverse_bits(index).
+fn sobol_1( index: u32 ) -> u32 {
+    var result = 0u;
+    var direction = 0x80000000u;
+    for ( var i = index; i != 0u; i = i >> 1u ) {
+        if ( (i & 1u) != 0u ) {
+            result = result ^ direction;
+        }
+        direction = direction ^ (direction >> 1u);
+    }
+    return result;
+}
+
+// Owen scrambling by hashing, "Practical Hash-based Owen Scrambling",
+// Burley 2020. Each bit is flipped depending only on the bits above it.
+fn laine_karras_permutation( v: u32, seed: u32 ) -> u32 {
+    var x = v + seed;
+    x = x ^ (x * 0x6c50b47cu);
+    x = x ^ (x * 0xb82f1e52u);
+    x = x ^ (x * 0xc7afe638u);
+    x = x ^ (x * 0x8d22f6e6u);
+    return x;
+}
+
+fn nested_uniform_scramble( x: u32, seed: u32 ) -> u32 {
+    return reverse_bits( laine_karras_permutation( reverse_bits(x), seed ) );
+}
+
+// The same pixel in a 64x64 blue noise tile, shifted by a different amount
+// for every dimension so dimensions don't share a pattern.
+fn blue_noise_at( pixel: u32, dimension: u32 ) -> f32 {
+    let shift = pcg_hash( dimension ^ 0x9e3779b9u );
+    let x = (pixel % globals.render_width + shift) % BLUE_NOISE_SIZE;
+    let y = (pixel / globals.render_width + (shift >> 16u)) % BLUE_NOISE_SIZE;
+    return blue_noise.values[y * BLUE_NOISE_SIZE + x];
+}
+
+fn sampler_for( slot: u32, dimension: u32 ) -> sampler_state {
+    let pixels = globals.render_width * globals.render_height;
+    let sample_index = slot / pixels;
+    return sampler_state( slot % pixels, sample_index + globals.samples_per_ray * globals.frame, dimension );
+}
+
+// Where a bounce's dimensions start, with `offset` picking the kernel's share.
+fn bounce_sampler( r: ray, offset: u32 ) -> sampler_state {
+    return sampler_for( r.pixel, DIM_BOUNCE + r.bounces * DIMS_PER_BOUNCE + offset );
+}
+
+// A well mixed u32 for seeding loops that need an unknown number of random
+// numbers. Takes up a dimension.
+fn sample_seed( s: ptr<function, sampler_state> ) -> u32 {
+    let seed = pcg_hash( (*s).pixel ^ pcg_hash( (*s).index ^ pcg_hash( (*s).dimension ) ) );
+    (*s).dimension = (*s).dimension + 1u;
+    return seed;
+}
+
+fn sample_1d( s: ptr<function, sampler_state> ) -> f32 {
+    let pixel = (*s).pixel;
+    let index = (*s).index;
+    let dimension = (*s).dimension;
+    (*s).dimension = dimension + 1u;
+
+    if ( camera.sampler_kind == SAMPLER_SOBOL ) {
+        // Shuffle the order per pixel and dimension, then scramble the values.
+        let seed = pcg_hash( pixel ^ pcg_hash( dimension ) );
+        let shuffled = nested_uniform_scramble( index, seed );
+        return unit_float( nested_uniform_scramble( reverse_bits(shuffled), pcg_hash(seed) ) );
+    } else if ( camera.sampler_kind == SAMPLER_BLUE_NOISE ) {
+        // Golden ratio sequence, rotated per pixel by the blue noise.
+        let sequence = unit_float( index * 2654435769u );
+        return fract( sequence + blue_noise_at(pixel, dimension) );
+    }
+
+    return unit_float( pcg_hash( pixel ^ pcg_hash( index ^ pcg_hash( dimension ) ) ) );
+}
+
+fn sample_2d( s: ptr<function, sampler_state> ) -> vec2<f32> {
+    let pixel = (*s).pixel;
+    let index = (*s).index;
+    let dimension = (*s).dimension;
+    (*s).dimension = dimension + 2u;
+
+    if ( camera.sampler_kind == SAMPLER_SOBOL ) {
+        // Both dimensions share the shuffle so the pair stays a (0,2) sequence.
+        let seed = pcg_hash( pixel ^ pcg_hash( dimension ) );
+        let shuffled = nested_uniform_scramble( index, seed );
+        return vec2<f32>(
+            unit_float( nested_uniform_scramble( reverse_bits(shuffled), pcg_hash(seed) ) ),
+            unit_float( nested_uniform_scramble( sobol_1(shuffled), pcg_hash(seed + 1u) ) )
+        );
+    } else if ( camera.sampler_kind == SAMPLER_BLUE_NOISE ) {
+        // R2 sequence, rotated per pixel by the blue noise.
+        let sequence = vec2<f32>( unit_float( index * 3242174889u ), unit_float( index * 2447445414u ) );
+        return fract( sequence + vec2<f32>( blue_noise_at(pixel, dimension), blue_noise_at(pixel, dimension + 1u) ) );
+    }
+
+    return vec2<f32>(
+        unit_float( pcg_hash( pixel ^ pcg_hash( index ^ pcg_hash( dimension ) ) ) ),
+        unit_float( pcg_hash( pixel ^ pcg_hash( index ^ pcg_hash( dimension + 1u ) ) ) )
+    );
+}
+
+// Where in its pixel a sample lands, 0 to 1 on each axis. Generate places the
+// camera ray with it and collect recomputes it to weight the sample.
+fn sample_offset( slot: u32 ) -> vec2<f32> {
+    var s = sampler_for( slot, DIM_PIXEL );
+    return sample_2d( &s );
+}
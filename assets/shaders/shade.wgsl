@@ -2,16 +2,47 @@ let VERY_FAR: f32 = 1e20f;
 let EPSILON: f32 = 0.001;
 let PI:f32 = 3.14159265358979;
 
+let QUEUE_MISS: u32 = 0u;
+let QUEUE_DIFFUSE: u32 = 1u;
+let QUEUE_METAL: u32 = 2u;
+let QUEUE_DIELECTRIC: u32 = 3u;
+let QUEUE_EMISSIVE: u32 = 4u;
+let QUEUE_PRINCIPLED: u32 = 5u;
+let QUEUE_MEDIUM: u32 = 6u;
+let INVALID_INDEX: u32 = 0xffffffffu;
+
+let DEBUG_NONE: u32 = 0u;
+let DEBUG_SAMPLE_COUNT: u32 = 1u;
+let DEBUG_NORMALS: u32 = 2u;
+let DEBUG_DEPTH: u32 = 3u;
+let DEBUG_ALBEDO: u32 = 4u;
+let DEBUG_MATERIAL_ID: u32 = 5u;
+let DEBUG_UV: u32 = 6u;
+let DEBUG_BOUNCES: u32 = 7u;
+// Depth shown as white in the depth view.
+let DEBUG_DEPTH_RANGE: f32 = 30.0;
+
 struct camera_config {
     transform: mat4x4<f32>,
     forward: vec3<f32>,
     fov: f32,
     up: vec3<f32>,
-    pad0: f32,
+    focus_distance: f32,
     right: vec3<f32>,
-    pad1: f32,
+    aperture_radius: f32,
     position: vec3<f32>,
-    pad2: f32,
+    projection: u32,
+    fov_axis: u32,
+    orthographic_height: f32,
+    pixel_filter: u32,
+    sampler_kind: u32,
+    max_bounces: u32,
+    roulette_depth: u32,
+    adaptive_threshold: f32,
+    min_samples: u32,
+    max_radiance: f32,
+    regularize: u32,
+    reject_outliers: u32,
 };
 
 struct globals_buf {
@@ -19,11 +50,17 @@ struct globals_buf {
     render_width: u32,
     render_height: u32,
     samples_per_ray: u32,
+    accumulated_frames: u32,
+    debug_view: u32,
     clear_index: atomic<u32>,
     generate_index: atomic<u32>,
     intersect_index: atomic<u32>,
-    shade_index: atomic<u32>,
     collect_index: atomic<u32>,
+    compact_index: atomic<u32>,
+    queue_count: atomic<u32>,
+    alive_count: u32,
+    material_counts: array<atomic<u32>, 7>,
+    shade_indices: array<atomic<u32>, 7>,
 };
 
 struct ray {
@@ -33,6 +70,9 @@ struct ray {
     max: f32,
     pixel: u32,
     bounces: u32,
+    cone_width: f32,
+    cone_spread: f32,
+    channel: u32,
 };
 
 struct ray_buf {
@@ -47,12 +87,22 @@ struct intersection {
     normal: vec3<f32>,
     material: u32,
     front_face: u32,
+    uv: vec2<f32>,
+    uv_density: f32,
 };
 
 struct intersection_buf {
     intersections: array<intersection>,
 };
 
+struct throughput_buf {
+    throughput: array<vec4<f32>>,
+};
+
+struct ray_queue_buf {
+    indices: array<u32>,
+};
+
 struct sphere {
     center: vec3<f32>,
     radius: f32,
@@ -69,190 +119,380 @@ struct material {
     reflectance: i32,
     fuzziness: f32,
     index_of_refraction: f32,
-    pad2: i32,
+    metallic: f32,
+    roughness: f32,
+    specular: f32,
+    base_color_texture: i32,
+    metallic_roughness_texture: i32,
+    emissive_texture: i32,
+    normal_map_texture: i32,
+    procedural: i32,
+    procedural_scale: f32,
+    procedural_color: vec4<f32>,
+    procedural_depth: u32,
+    absorption_density: f32,
+    cauchy_b: f32,
+    anisotropy: f32,
+    absorption: vec4<f32>,
 }
 
 struct material_buf {
     m: array<material>,
 }
 
+struct perlin_buf {
+    ranvec: array<vec4<f32>, 256>,
+    perm_x: array<u32, 256>,
+    perm_y: array<u32, 256>,
+    perm_z: array<u32, 256>,
+}
+
 struct shade {
+    // Light emitted toward the camera if the path ends here, otherwise the
+    // attenuation along the extension ray.
     color: vec4<f32>,
     extension: ray,
 }
 
+struct ray_stats_buf {
+    primary_rays: atomic<u32>,
+    intersection_tests_low: atomic<u32>,
+    intersection_tests_high: atomic<u32>,
+    alive_rays: array<atomic<u32>, 32>,
+    terminated_rays: array<atomic<u32>, 32>,
+};
+
+struct blue_noise_buf {
+    values: array<f32, 4096>,
+};
+
 @group(0) @binding(0)
 var<uniform> camera: camera_config;
 
 @group(0) @binding(1)
 var<storage, read_write> globals: globals_buf;
 
+@group(0) @binding(2)
+var<storage, read> blue_noise: blue_noise_buf;
+
+@group(0) @binding(3)
+var<storage, read_write> ray_stats: ray_stats_buf;
+
 @group(1) @binding(0)
 var<storage, read_write> ray_buffer: ray_buf;
 
 @group(1) @binding(1)
 var<storage, read_write> intersection_buffer: intersection_buf;
 
+@group(1) @binding(3)
+var<storage, read_write> material_queues: ray_queue_buf;
+
+@group(1) @binding(4)
+var<storage, read_write> throughput_buffer: throughput_buf;
+
 @group(2) @binding(0)
 var<storage, read> objects: object_list;
 
 @group(2) @binding(1)
 var<storage, read> materials: material_buf;
 
+@group(2) @binding(2)
+var material_textures: texture_2d_array<f32>;
+
+@group(2) @binding(3)
+var material_sampler: sampler;
+
+@group(2) @binding(4)
+var<storage, read> perlin: perlin_buf;
+
 let NEWTON_ITER = 2;
 let HALLEY_ITER = 0;
 
-fn hash3( ni: u32 ) -> vec3<f32>
-{
-    // integer hash copied from Hugo Elias
-    var n = ni;
-	n = (n << 13u) ^ n;
-    n = n * (n * n * 15731u + 789221u) + 1376312589u;
-    let k = n * vec3<u32>(n, n*16807u, n*48271u);
-
-    let l = vec3<u32>(0x7fffffffu);
-    let m = vec3<f32>(f32(k.x&l.x), f32(k.y&l.y), f32(k.z&l.z));
-    return m / f32(0x7fffffff);
+#import bevy_raytrace::sampler
+#import bevy_raytrace::ray_stats
+#import bevy_raytrace::shading
+
+// Take the next ray index from a material queue, or INVALID_INDEX once the
+// queue is empty.
+fn dequeue( queue: u32, invocation_id: vec3<u32> ) -> u32 {
+#ifdef INVOCATION_ID_INDEXING
+    let queue_index = invocation_id.x;
+#else
+    let queue_index = atomicAdd( &globals.shade_indices[queue], 1u );
+#endif
+    if ( queue_index >= atomicLoad( &globals.material_counts[queue] ) ) {
+        return INVALID_INDEX;
+    }
+
+    return material_queues.indices[queue * ray_buffer.ray_count + queue_index];
 }
 
-fn lambertian( r: ray, i: intersection, m: material, seed: vec3<f32> ) -> shade {    
-    var offset = i.normal * EPSILON;
-    
-    var destination =  i.position + i.normal + normalize(seed);
+fn apply( index: u32, s: shade ) {
+    var throughput = throughput_buffer.throughput[index].xyz * s.color.xyz;
+    var extension = s.extension;
 
-    var e_origin = i.position;
-    var e_dir = normalize(destination - e_origin);
+    if ( extension.origin.x != VERY_FAR ) {
+        let weight = roulette_weight( extension, throughput );
+        if ( weight == 0.0 ) {
+            extension = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, extension.pixel, extension.bounces, 0.0, 0.0, extension.channel );
+        }
+        throughput = throughput * weight;
+    }
 
-    let c = m.color;
-    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u);
+    // Counted at the bounce the path was shaded on.
+    if ( extension.origin.x == VERY_FAR ) {
+        count_terminated_ray( extension.bounces - 1u );
+    }
 
-    return shade( c, e );
-}
+    ray_buffer.rays[index] = extension;
 
-fn reflect( v: vec3<f32>, n: vec3<f32> ) -> vec3<f32> {
-    return v - 2.0*dot(v,n) * n;
+    storageBarrier();
+    if ( s.extension.origin.x == VERY_FAR ) {
+        // The path ended here, so what came back is the light it picked up.
+        let color = intersection_buffer.intersections[index].color;
+        intersection_buffer.intersections[index].color = vec4<f32>( color.xyz + clamp_contribution(s.extension, throughput), 1.0 );
+    } else {
+        throughput_buffer.throughput[index] = vec4<f32>( throughput, path_roughness );
+    }
 }
 
-fn metallic( r: ray, i: intersection, m: material, seed: vec3<f32> ) -> shade {
-    let c = m.color;
-    let offset = i.normal * EPSILON;
-    let e_origin = i.position + offset;
-    let reflected = normalize(reflect(r.dir, i.normal));
-    let noise = m.fuzziness*normalize(seed);
-    let e_dir = normalize( reflected + noise );
-    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u);
+fn shade_miss_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_MISS, invocation_id );
+    if ( index == INVALID_INDEX ) {
+        return;
+    }
 
-    return shade( c, e );
+    let r = ray_buffer.rays[index];
+    apply( index, miss(r) );
 }
 
-fn refract( uv: vec3<f32>, n: vec3<f32>, etai_over_etat: f32 ) -> vec3<f32> {
-    let cos_theta = min(dot(-uv, n), 1.0);
-    let r_out_perp =  etai_over_etat * (uv + cos_theta*n);
-    let l = length(r_out_perp);
-    let r_out_parallel = -sqrt(abs(1.0 - (l*l))) * n;
-    return normalize( r_out_perp + r_out_parallel );
-}
+fn shade_diffuse_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_DIFFUSE, invocation_id );
+    if ( index == INVALID_INDEX ) {
+        return;
+    }
+
+    let r = ray_buffer.rays[index];
+    if ( max_bounces_reached(r) ) {
+        apply( index, absorb(r) );
+        return;
+    }
 
-fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
-    // Use Schlick's approximation for reflectance.
-    var r0 = (1.0-ref_idx) / (1.0+ref_idx);
-    r0 = r0 * r0;
-    return r0 + (1.0-r0)*pow((1.0 - cosine), 5.0);
+    let s = surface_at(r, intersection_buffer.intersections[index]);
+    path_roughness = throughput_buffer.throughput[index].w;
+    var samp = bounce_sampler( r, DIM_SHADE );
+    apply( index, lambertian(r, s.i, s.m, &samp) );
 }
 
-fn dielectric( r: ray, i: intersection, m: material, seed: vec3<f32> ) -> shade {
-    var refraction_ratio = m.index_of_refraction;
-    if ( i.front_face == 1u ) {
-        refraction_ratio = 1.0/m.index_of_refraction;
-     }
+fn shade_metal_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_METAL, invocation_id );
+    if ( index == INVALID_INDEX ) {
+        return;
+    }
+
+    let r = ray_buffer.rays[index];
+    if ( max_bounces_reached(r) ) {
+        apply( index, absorb(r) );
+        return;
+    }
 
-    let unit_dir = normalize(r.dir);
-    let cos_theta = min(dot(-unit_dir, i.normal), 1.0);
-    let sin_theta = sqrt(1.0 - cos_theta*cos_theta);
+    let s = surface_at(r, intersection_buffer.intersections[index]);
+    path_roughness = throughput_buffer.throughput[index].w;
+    var samp = bounce_sampler( r, DIM_SHADE );
+    apply( index, metallic(r, s.i, s.m, &samp) );
+}
 
-    let cannot_refract = refraction_ratio * sin_theta > 1.0;
+fn shade_dielectric_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_DIELECTRIC, invocation_id );
+    if ( index == INVALID_INDEX ) {
+        return;
+    }
 
-    var e_dir = vec3<f32>(0.0, 0.0, 0.0);
-    if ( cannot_refract || reflectance(cos_theta, refraction_ratio) > seed.x) {
-        e_dir = reflect(r.dir, i.normal);
-    } else {
-        e_dir = refract(unit_dir, i.normal, refraction_ratio);
+    let r = ray_buffer.rays[index];
+    if ( max_bounces_reached(r) ) {
+        apply( index, absorb(r) );
+        return;
     }
 
-    let e_origin = i.position + i.normal * EPSILON;
-    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u);
+    let s = surface_at(r, intersection_buffer.intersections[index]);
+    path_roughness = throughput_buffer.throughput[index].w;
+    var samp = bounce_sampler( r, DIM_SHADE );
+    apply( index, dielectric(r, s.i, s.m, &samp) );
+}
+
+fn shade_emissive_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_EMISSIVE, invocation_id );
+    if ( index == INVALID_INDEX ) {
+        return;
+    }
 
-    let attenuation = vec4<f32>(1.0);
-    return shade( attenuation, e );
+    let r = ray_buffer.rays[index];
+    let s = surface_at(r, intersection_buffer.intersections[index]);
+    apply( index, emissive(r, s.m) );
 }
 
-fn miss(r: ray) -> shade {
-    let unit = normalize(r.dir);
-    let t = 0.5 * unit.y + 1.0;
-    let sky_gradient = (1.0-t) * vec3<f32>(1.0) + t * vec3<f32>(0.5, 0.7, 1.0);
+fn shade_principled_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_PRINCIPLED, invocation_id );
+    if ( index == INVALID_INDEX ) {
+        return;
+    }
 
-    let no_extension = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces+1u );
+    let r = ray_buffer.rays[index];
+    if ( max_bounces_reached(r) ) {
+        apply( index, absorb(r) );
+        return;
+    }
 
-    return shade( vec4<f32>(sky_gradient, 1.0), no_extension );
+    let s = surface_at(r, intersection_buffer.intersections[index]);
+    path_roughness = throughput_buffer.throughput[index].w;
+    var samp = bounce_sampler( r, DIM_SHADE );
+    apply( index, principled(r, s.i, s.m, &samp) );
 }
 
-@compute @workgroup_size(128, 1, 1)
-fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>)
-{
-    let index = atomicAdd( &globals.shade_index, 1u );
-    if ( index >= ray_buffer.ray_count ) {
+fn shade_medium_ray( invocation_id: vec3<u32> ) {
+    let index = dequeue( QUEUE_MEDIUM, invocation_id );
+    if ( index == INVALID_INDEX ) {
         return;
     }
 
     let r = ray_buffer.rays[index];
-    if (r.origin.x == VERY_FAR) {
+    if ( max_bounces_reached(r) ) {
+        apply( index, absorb(r) );
         return;
     }
-    let i = intersection_buffer.intersections[index];
-    var pixel = r.pixel;
-    let y = pixel / globals.render_width;
-    let x = pixel - (y*globals.render_width);
 
-    let seed = hash3( x 
-        + globals.render_width*y 
-        + (globals.render_width*globals.render_height) * globals.frame );
+    let s = surface_at(r, intersection_buffer.intersections[index]);
+    path_roughness = throughput_buffer.throughput[index].w;
+    var samp = bounce_sampler( r, DIM_SHADE );
+    apply( index, volume(r, s.i, s.m, &samp) );
+}
 
-    var st = vec2<f32>(
-        f32(x) / f32(globals.render_width),
-        f32(y) / f32(globals.render_height),
-    );
-    st *= f32(globals.frame) % 1000.0;
-    st *= 100.0;
+// One kernel per material queue, so every thread in a workgroup runs the same
+// material code.
 
-    var color = intersection_buffer.intersections[index].color;
+@compute @workgroup_size(128, 1, 1)
+fn shade_miss(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_miss_ray( invocation_id );
+    end_ray_stats( local_index );
+}
 
-    if ( i.t == VERY_FAR ) {
-        var s = miss(r);
-        color *= s.color;
+@compute @workgroup_size(128, 1, 1)
+fn shade_diffuse(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_diffuse_ray( invocation_id );
+    end_ray_stats( local_index );
+}
 
-        ray_buffer.rays[index] = s.extension;
-    } else {
-        let material = materials.m[i.material];
-        if ( r.bounces == 2u ) {
-            ray_buffer.rays[index] = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces+1u );
-            color = vec4<f32>(0.0, 0.0, 0.0, 1.0 );
-        } else {
-            if ( material.reflectance == 0 ) {
-                var s = lambertian(r, i, material, seed);
-                color *= s.color;
-                ray_buffer.rays[index] = s.extension;
-            } else if ( material.reflectance == 1 ) {
-                var s = metallic(r, i, material, seed);
-                color *= s.color;
-                ray_buffer.rays[index] = s.extension;
-            } else if ( material.reflectance == 2 ) {
-                var s = dielectric(r, i, material, seed);
-                color *= s.color;
-                ray_buffer.rays[index] = s.extension;
-            }
-        }
+@compute @workgroup_size(128, 1, 1)
+fn shade_metal(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_metal_ray( invocation_id );
+    end_ray_stats( local_index );
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn shade_dielectric(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_dielectric_ray( invocation_id );
+    end_ray_stats( local_index );
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn shade_emissive(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_emissive_ray( invocation_id );
+    end_ray_stats( local_index );
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn shade_principled(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_principled_ray( invocation_id );
+    end_ray_stats( local_index );
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn shade_medium(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    begin_ray_stats( local_index );
+    shade_medium_ray( invocation_id );
+    end_ray_stats( local_index );
+}
+
+// Blue through green to red.
+fn heatmap( t: f32 ) -> vec3<f32> {
+    let c = 2.0 * clamp(t, 0.0, 1.0) - 1.0;
+    return clamp( vec3<f32>( c, 1.0 - abs(c), -c ), vec3<f32>(0.0), vec3<f32>(1.0) );
+}
+
+// What the debug view shows for a path, from its last ray and the first hit
+// left in the intersection buffer.
+fn debug_color( r: ray, hit: intersection ) -> vec3<f32> {
+    if ( globals.debug_view == DEBUG_BOUNCES ) {
+        return heatmap( f32(r.bounces) / f32(camera.max_bounces + 1u) );
+    }
+
+    // Rays that weren't traced this frame have a stale hit, and misses have none.
+    if ( r.bounces == 0u || hit.t == VERY_FAR ) {
+        return vec3<f32>( 0.0 );
     }
 
+    // The camera ray is gone by now. A cone of zero width samples textures
+    // at their finest level.
+    let camera_ray = ray( camera.position, EPSILON, hit.position - camera.position, VERY_FAR, r.pixel, 0u, 0.0, 0.0, r.channel );
+    let s = surface_at( camera_ray, hit );
+
+    if ( globals.debug_view == DEBUG_NORMALS ) {
+        return 0.5 * s.i.normal + 0.5;
+    } else if ( globals.debug_view == DEBUG_DEPTH ) {
+        let depth = dot( hit.position - camera.position, camera.forward );
+        return vec3<f32>( clamp(depth / DEBUG_DEPTH_RANGE, 0.0, 1.0) );
+    } else if ( globals.debug_view == DEBUG_ALBEDO ) {
+        return s.m.color.xyz;
+    } else if ( globals.debug_view == DEBUG_MATERIAL_ID ) {
+        let h = pcg_hash( hit.material + 1u );
+        return vec3<f32>( f32(h & 255u), f32((h >> 8u) & 255u), f32((h >> 16u) & 255u) ) / 255.0;
+    } else if ( globals.debug_view == DEBUG_UV ) {
+        return vec3<f32>( fract(hit.uv), 0.0 );
+    }
+
+    return vec3<f32>( 0.0 );
+}
+
+// Replaces every sample's light with the debug view's value once the paths
+// are done, so collect filters and accumulates it like the image.
+@compute @workgroup_size(128, 1, 1)
+fn shade_debug(@builtin(global_invocation_id) invocation_id: vec3<u32>)
+{
+    let index = invocation_id.x;
+    if ( index >= ray_buffer.ray_count || globals.debug_view <= DEBUG_SAMPLE_COUNT ) {
+        return;
+    }
+
+    let r = ray_buffer.rays[index];
+    let color = debug_color( r, intersection_buffer.intersections[index] );
+
     storageBarrier();
-    intersection_buffer.intersections[index].color = color;
-}
\ No newline at end of file
+    intersection_buffer.intersections[index].color = vec4<f32>( color, 1.0 );
+}
@@ -0,0 +1,495 @@
+#define_import_path bevy_raytrace::shading
+
+// Imported after the bindings and bevy_raytrace::sampler, as it reads the
+// importer's camera, materials and textures and draws from its samplers.
+//
+// Material lobes and the path rules around them, shared by the shade passes
+// and the megakernel.
+
+// Fraction of the path's roughness later lobes are raised to.
+let REGULARIZATION: f32 = 0.3;
+
+// The roughest lobe the current path has scattered from, 1 for diffuse.
+// Loaded before shading, raised by the material that scatters, and stored
+// with the throughput.
+var<private> path_roughness: f32;
+
+// The least roughness a glossy or specular lobe may have, a fraction of the
+// roughest lobe the path has already scattered from.
+fn min_roughness() -> f32 {
+    if ( camera.regularize == 0u ) {
+        return 0.0;
+    }
+
+    return REGULARIZATION * path_roughness;
+}
+
+// Perlin noise and the patterns built on it, "Ray Tracing: The Next Week".
+fn perlin_interp( c: array<array<array<vec3<f32>, 2>, 2>, 2>, u: f32, v: f32, w: f32 ) -> f32 {
+    // Hermite smoothing.
+    let uu = u * u * (3.0 - 2.0 * u);
+    let vv = v * v * (3.0 - 2.0 * v);
+    let ww = w * w * (3.0 - 2.0 * w);
+
+    var cells = c;
+    var accum = 0.0;
+    for ( var i=0; i<2; i=i+1 ) {
+        for ( var j=0; j<2; j=j+1 ) {
+            for ( var k=0; k<2; k=k+1 ) {
+                let fi = f32(i);
+                let fj = f32(j);
+                let fk = f32(k);
+                let weight = vec3<f32>(u - fi, v - fj, w - fk);
+                accum += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                    * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                    * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                    * dot(cells[i][j][k], weight);
+            }
+        }
+    }
+
+    return accum;
+}
+
+fn perlin_noise( p: vec3<f32> ) -> f32 {
+    let f = floor(p);
+    let u = p.x - f.x;
+    let v = p.y - f.y;
+    let w = p.z - f.z;
+
+    let i = vec3<i32>(f);
+
+    var c: array<array<array<vec3<f32>, 2>, 2>, 2>;
+    for ( var di=0; di<2; di=di+1 ) {
+        for ( var dj=0; dj<2; dj=dj+1 ) {
+            for ( var dk=0; dk<2; dk=dk+1 ) {
+                let hash = perlin.perm_x[(i.x + di) & 255]
+                    ^ perlin.perm_y[(i.y + dj) & 255]
+                    ^ perlin.perm_z[(i.z + dk) & 255];
+                c[di][dj][dk] = perlin.ranvec[hash].xyz;
+            }
+        }
+    }
+
+    return perlin_interp(c, u, v, w);
+}
+
+fn turbulence( p: vec3<f32>, depth: u32 ) -> f32 {
+    var accum = 0.0;
+    var temp_p = p;
+    var weight = 1.0;
+
+    for ( var i=0u; i<depth; i=i+1u ) {
+        accum += weight * perlin_noise(temp_p);
+        weight *= 0.5;
+        temp_p = temp_p * 2.0;
+    }
+
+    return abs(accum);
+}
+
+// The material color with its procedural pattern applied at p.
+fn procedural_color( m: material, p: vec3<f32> ) -> vec4<f32> {
+    let scaled = p * m.procedural_scale;
+
+    if ( m.procedural == 1 ) {
+        let sines = sin(scaled.x) * sin(scaled.y) * sin(scaled.z);
+        if ( sines < 0.0 ) {
+            return m.color;
+        }
+        return m.procedural_color;
+    } else if ( m.procedural == 2 ) {
+        return m.color * 0.5 * (1.0 + perlin_noise(scaled));
+    } else if ( m.procedural == 3 ) {
+        return m.color * turbulence(scaled, m.procedural_depth);
+    } else if ( m.procedural == 4 ) {
+        return m.color * 0.5 * (1.0 + sin(scaled.z + 10.0 * turbulence(p, m.procedural_depth)));
+    }
+
+    return m.color;
+}
+
+fn cone_width_at( r: ray, i: intersection ) -> f32 {
+    return r.cone_width + r.cone_spread * i.t;
+}
+
+// Texture lod from the ray cone footprint at the hit, "Texture Level of Detail
+// Strategies for Real-Time Ray Tracing", Akenine-Möller et al.
+fn texture_lod( r: ray, i: intersection ) -> f32 {
+    let cosine = max( abs(dot(normalize(r.dir), i.normal)), 0.01 );
+    let footprint = cone_width_at(r, i) / cosine / i.uv_density;
+    let texels = footprint * f32( textureDimensions(material_textures).x );
+    return max( log2(max(texels, 1e-8)), 0.0 );
+}
+
+fn sample_material_texture( layer: i32, uv: vec2<f32>, lod: f32 ) -> vec4<f32> {
+    return textureSampleLevel( material_textures, material_sampler, uv, layer, lod );
+}
+
+struct surface {
+    i: intersection,
+    m: material,
+}
+
+// Apply the material's textures at the hit.
+fn surface_at( r: ray, hit: intersection ) -> surface {
+    var i = hit;
+    var m = materials.m[i.material];
+    let lod = texture_lod(r, i);
+
+    if ( m.procedural > 0 ) {
+        m.color = procedural_color(m, i.position);
+    }
+
+    if ( m.base_color_texture >= 0 ) {
+        let texel = sample_material_texture(m.base_color_texture, i.uv, lod);
+        // Base color textures are sRGB encoded.
+        m.color *= vec4<f32>( pow(texel.xyz, vec3<f32>(2.2)), texel.w );
+    }
+
+    if ( m.metallic_roughness_texture >= 0 ) {
+        let texel = sample_material_texture(m.metallic_roughness_texture, i.uv, lod);
+        m.roughness *= texel.y;
+        m.metallic *= texel.z;
+    }
+
+    if ( m.emissive_texture >= 0 ) {
+        let texel = sample_material_texture(m.emissive_texture, i.uv, lod);
+        m.color *= vec4<f32>( pow(texel.xyz, vec3<f32>(2.2)), 1.0 );
+    }
+
+    if ( m.normal_map_texture >= 0 ) {
+        let texel = sample_material_texture(m.normal_map_texture, i.uv, lod).xyz * 2.0 - 1.0;
+
+        // Tangent along increasing u on the sphere, falling back at the poles.
+        var tangent = cross( vec3<f32>(0.0, 1.0, 0.0), i.normal );
+        if ( length(tangent) < 1e-4 ) {
+            tangent = vec3<f32>(1.0, 0.0, 0.0);
+        }
+        tangent = normalize(tangent);
+        let bitangent = cross(i.normal, tangent);
+
+        i.normal = normalize( tangent * texel.x + bitangent * texel.y + i.normal * texel.z );
+    }
+
+    return surface( i, m );
+}
+
+// Uniform on the unit sphere.
+fn random_unit_vector( u: vec2<f32> ) -> vec3<f32> {
+    let z = 1.0 - 2.0 * u.x;
+    let radius = sqrt( max(0.0, 1.0 - z*z) );
+    let phi = 2.0 * PI * u.y;
+    return vec3<f32>( radius * cos(phi), radius * sin(phi), z );
+}
+
+fn lambertian( r: ray, i: intersection, m: material, samp: ptr<function, sampler_state> ) -> shade {    
+    var offset = i.normal * EPSILON;
+    
+    var destination =  i.position + i.normal + random_unit_vector( sample_2d(samp) );
+    path_roughness = 1.0;
+
+    var e_origin = i.position;
+    var e_dir = normalize(destination - e_origin);
+
+    let c = m.color;
+    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u, cone_width_at(r, i), r.cone_spread, r.channel);
+
+    return shade( c, e );
+}
+
+fn reflect( v: vec3<f32>, n: vec3<f32> ) -> vec3<f32> {
+    return v - 2.0*dot(v,n) * n;
+}
+
+fn metallic( r: ray, i: intersection, m: material, samp: ptr<function, sampler_state> ) -> shade {
+    let c = m.color;
+    let offset = i.normal * EPSILON;
+    let e_origin = i.position + offset;
+    let reflected = normalize(reflect(r.dir, i.normal));
+    let fuzziness = max( m.fuzziness, min_roughness() );
+    path_roughness = max( path_roughness, fuzziness );
+    let noise = fuzziness*random_unit_vector( sample_2d(samp) );
+    let e_dir = normalize( reflected + noise );
+    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u, cone_width_at(r, i), r.cone_spread, r.channel);
+
+    return shade( c, e );
+}
+
+fn refract( uv: vec3<f32>, n: vec3<f32>, etai_over_etat: f32 ) -> vec3<f32> {
+    let cos_theta = min(dot(-uv, n), 1.0);
+    let r_out_perp =  etai_over_etat * (uv + cos_theta*n);
+    let l = length(r_out_perp);
+    let r_out_parallel = -sqrt(abs(1.0 - (l*l))) * n;
+    return normalize( r_out_perp + r_out_parallel );
+}
+
+fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
+    // Use Schlick's approximation for reflectance.
+    var r0 = (1.0-ref_idx) / (1.0+ref_idx);
+    r0 = r0 * r0;
+    return r0 + (1.0-r0)*pow((1.0 - cosine), 5.0);
+}
+
+// Wavelength in micrometers of each color channel, 1 based to match ray.channel.
+fn channel_wavelength( channel: u32 ) -> f32 {
+    if ( channel == 1u ) {
+        return 0.65;
+    } else if ( channel == 2u ) {
+        return 0.532;
+    }
+    return 0.45;
+}
+
+fn channel_mask( channel: u32 ) -> vec3<f32> {
+    return vec3<f32>( f32(channel == 1u), f32(channel == 2u), f32(channel == 3u) );
+}
+
+fn dielectric( r: ray, i: intersection, m: material, samp: ptr<function, sampler_state> ) -> shade {
+    // Drawn up front so each decision keeps its dimension whichever way the
+    // other goes.
+    let channel_sample = sample_1d(samp);
+    let reflect_sample = sample_1d(samp);
+
+    var attenuation = vec4<f32>(1.0);
+
+    // Leaving the medium, so the ray travelled t inside it. Beer-Lambert.
+    if ( i.front_face == 0u ) {
+        attenuation = vec4<f32>( pow(m.absorption.xyz, vec3<f32>(m.absorption_density * i.t)), 1.0 );
+    }
+
+    // Dispersion picks one color channel per path and traces it with that
+    // wavelength's index of refraction from Cauchy's equation.
+    var channel = r.channel;
+    var ior = m.index_of_refraction;
+    if ( m.cauchy_b > 0.0 ) {
+        if ( channel == 0u ) {
+            channel = 1u + min( u32(channel_sample * 3.0), 2u );
+            attenuation *= vec4<f32>( 3.0 * channel_mask(channel), 1.0 );
+        }
+
+        let wavelength = channel_wavelength(channel);
+        ior = m.index_of_refraction + m.cauchy_b / (wavelength * wavelength);
+    }
+
+    var refraction_ratio = ior;
+    if ( i.front_face == 1u ) {
+        refraction_ratio = 1.0/ior;
+     }
+
+    let unit_dir = normalize(r.dir);
+    let cos_theta = min(dot(-unit_dir, i.normal), 1.0);
+    let sin_theta = sqrt(1.0 - cos_theta*cos_theta);
+
+    let cannot_refract = refraction_ratio * sin_theta > 1.0;
+
+    var e_dir = vec3<f32>(0.0, 0.0, 0.0);
+    if ( cannot_refract || reflectance(cos_theta, refraction_ratio) > reflect_sample) {
+        e_dir = reflect(r.dir, i.normal);
+    } else {
+        e_dir = refract(unit_dir, i.normal, refraction_ratio);
+    }
+
+    // Glass has no roughness of its own, so regularizing blurs the direction
+    // like metal fuzz.
+    let roughness = min_roughness();
+    if ( roughness > 0.0 ) {
+        e_dir = normalize( e_dir + roughness * random_unit_vector( sample_2d(samp) ) );
+    }
+
+    let e_origin = i.position + i.normal * EPSILON;
+    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u, cone_width_at(r, i), r.cone_spread, channel);
+
+    return shade( attenuation, e );
+}
+
+fn luminance( c: vec3<f32> ) -> f32 {
+    return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn fresnel_schlick( f0: vec3<f32>, cosine: f32 ) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(1.0 - clamp(cosine, 0.0, 1.0), 5.0);
+}
+
+// Orthonormal basis around n, "Building an Orthonormal Basis, Revisited", Duff et al.
+fn tangent_frame( n: vec3<f32> ) -> mat3x3<f32> {
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    let t = vec3<f32>(1.0 + s * n.x * n.x * a, s * b, -s * n.x);
+    let bt = vec3<f32>(b, s + n.y * n.y * a, -n.y);
+    return mat3x3<f32>(t, bt, n);
+}
+
+// Smith Lambda for GGX, with cosine measured from the local z axis.
+fn smith_lambda( cosine: f32, alpha: f32 ) -> f32 {
+    let cos2 = cosine * cosine;
+    let tan2 = max(1.0 - cos2, 0.0) / max(cos2, 1e-7);
+    return (-1.0 + sqrt(1.0 + alpha * alpha * tan2)) * 0.5;
+}
+
+fn smith_g1( cosine: f32, alpha: f32 ) -> f32 {
+    return 1.0 / (1.0 + smith_lambda(cosine, alpha));
+}
+
+// Height-correlated masking-shadowing.
+fn smith_g2( cos_v: f32, cos_l: f32, alpha: f32 ) -> f32 {
+    return 1.0 / (1.0 + smith_lambda(cos_v, alpha) + smith_lambda(cos_l, alpha));
+}
+
+// Sample a GGX normal from the distribution of visible normals,
+// "Sampling the GGX Distribution of Visible Normals", Heitz 2018.
+// v is in the local frame with the surface normal along z.
+fn sample_ggx_vndf( v: vec3<f32>, alpha: f32, u: vec2<f32> ) -> vec3<f32> {
+    let vh = normalize(vec3<f32>(alpha * v.x, alpha * v.y, v.z));
+
+    let lensq = vh.x * vh.x + vh.y * vh.y;
+    var t1 = vec3<f32>(1.0, 0.0, 0.0);
+    if ( lensq > 0.0 ) {
+        t1 = vec3<f32>(-vh.y, vh.x, 0.0) / sqrt(lensq);
+    }
+    let t2 = cross(vh, t1);
+
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+
+    let nh = p1 * t1 + p2 * t2 + sqrt(max(0.0, 1.0 - p1 * p1 - p2 * p2)) * vh;
+    return normalize(vec3<f32>(alpha * nh.x, alpha * nh.y, max(0.0, nh.z)));
+}
+
+fn principled( r: ray, i: intersection, m: material, samp: ptr<function, sampler_state> ) -> shade {
+    let lobe_sample = sample_1d(samp);
+    let u = sample_2d(samp);
+
+    let frame = tangent_frame(i.normal);
+    let v = transpose(frame) * -normalize(r.dir);
+    let roughness = max( m.roughness, min_roughness() );
+    let alpha = max(roughness * roughness, 0.001);
+
+    // Same base reflectance as Bevy's StandardMaterial.
+    let dielectric_f0 = vec3<f32>(0.16 * m.specular * m.specular);
+    let f0 = mix(dielectric_f0, m.color.xyz, m.metallic);
+
+    // Pick the specular or diffuse lobe in proportion to their expected weight.
+    let fresnel_view = fresnel_schlick(f0, v.z);
+    let diffuse_albedo = m.color.xyz * (1.0 - m.metallic) * (vec3<f32>(1.0) - fresnel_view);
+    let specular_weight = luminance(fresnel_view);
+    let diffuse_weight = luminance(diffuse_albedo);
+    let specular_probability = specular_weight / max(specular_weight + diffuse_weight, 1e-4);
+
+    let e_origin = i.position + i.normal * EPSILON;
+    var l = vec3<f32>(0.0);
+    var weight = vec3<f32>(0.0);
+
+    if ( lobe_sample < specular_probability ) {
+        let h = sample_ggx_vndf(v, alpha, u);
+        l = reflect(-v, h);
+
+        if ( l.z > 0.0 ) {
+            // The VNDF pdf cancels D and G1(v), leaving F * G2 / G1(v).
+            let fresnel = fresnel_schlick(f0, dot(v, h));
+            weight = fresnel * smith_g2(v.z, l.z, alpha) / smith_g1(v.z, alpha);
+            weight = weight / specular_probability;
+        }
+
+        path_roughness = max( path_roughness, roughness );
+    } else {
+        // Cosine weighted hemisphere sample, so the Lambertian weight is the albedo.
+        let radius = sqrt(u.x);
+        let phi = 2.0 * PI * u.y;
+        l = vec3<f32>(radius * cos(phi), radius * sin(phi), sqrt(max(0.0, 1.0 - u.x)));
+        weight = diffuse_albedo / (1.0 - specular_probability);
+        path_roughness = 1.0;
+    }
+
+    if ( l.z <= 0.0 ) {
+        let no_extension = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces+1u, 0.0, 0.0, r.channel );
+        return shade( vec4<f32>(0.0, 0.0, 0.0, 1.0), no_extension );
+    }
+
+    let e_dir = normalize(frame * l);
+    let e = ray(e_origin, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u, cone_width_at(r, i), r.cone_spread, r.channel);
+
+    return shade( vec4<f32>(weight, 1.0), e );
+}
+
+// Sample a direction from the Henyey-Greenstein phase function around the
+// direction of travel. g = 0 is isotropic.
+fn sample_henyey_greenstein( forward: vec3<f32>, g: f32, u: vec2<f32> ) -> vec3<f32> {
+    var cos_theta = 1.0 - 2.0 * u.x;
+    if ( abs(g) > 1e-3 ) {
+        let s = (1.0 - g*g) / (1.0 - g + 2.0*g*u.x);
+        cos_theta = (1.0 + g*g - s*s) / (2.0*g);
+    }
+
+    let sin_theta = sqrt( max(0.0, 1.0 - cos_theta*cos_theta) );
+    let phi = 2.0 * PI * u.y;
+    return tangent_frame(forward) * vec3<f32>( sin_theta * cos(phi), sin_theta * sin(phi), cos_theta );
+}
+
+// The phase function is sampled exactly, so the weight is just the albedo.
+// Scattering in a medium counts as diffuse for regularization.
+fn volume( r: ray, i: intersection, m: material, samp: ptr<function, sampler_state> ) -> shade {
+    path_roughness = 1.0;
+    let e_dir = sample_henyey_greenstein( normalize(r.dir), m.anisotropy, sample_2d(samp) );
+    let e = ray(i.position, EPSILON, e_dir, VERY_FAR, r.pixel, r.bounces+1u, cone_width_at(r, i), r.cone_spread, r.channel);
+
+    return shade( m.color, e );
+}
+
+fn miss(r: ray) -> shade {
+    let unit = normalize(r.dir);
+    let t = 0.5 * unit.y + 1.0;
+    let sky_gradient = (1.0-t) * vec3<f32>(1.0) + t * vec3<f32>(0.5, 0.7, 1.0);
+
+    let no_extension = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces+1u, 0.0, 0.0, r.channel );
+
+    return shade( vec4<f32>(sky_gradient, 1.0), no_extension );
+}
+
+fn emissive(r: ray, m: material) -> shade {
+    let no_extension = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces+1u, 0.0, 0.0, r.channel );
+
+    return shade( m.color, no_extension );
+}
+
+fn absorb(r: ray) -> shade {
+    let no_extension = ray( vec3<f32>(VERY_FAR), EPSILON, vec3<f32>(VERY_FAR), VERY_FAR, r.pixel, r.bounces+1u, 0.0, 0.0, r.channel );
+
+    return shade( vec4<f32>(0.0, 0.0, 0.0, 1.0), no_extension );
+}
+
+// Scale light that arrived through at least one bounce down so no channel is
+// above max_radiance. Light seen directly is left alone.
+fn clamp_contribution( e: ray, light: vec3<f32> ) -> vec3<f32> {
+    let brightest = max( light.x, max(light.y, light.z) );
+    if ( camera.max_radiance <= 0.0 || e.bounces <= 1u || brightest <= camera.max_radiance ) {
+        return light;
+    }
+
+    return light * (camera.max_radiance / brightest);
+}
+
+fn max_bounces_reached( r: ray ) -> bool {
+    return r.bounces >= camera.max_bounces;
+}
+
+// Past the roulette depth, an extension ray survives with probability of its
+// path's throughput, and survivors are weighted up by the inverse so the image
+// stays unbiased. Returns 0 for paths that are terminated.
+fn roulette_weight( e: ray, throughput: vec3<f32> ) -> f32 {
+    if ( e.bounces < camera.roulette_depth ) {
+        return 1.0;
+    }
+
+    let survival = min( max( throughput.x, max(throughput.y, throughput.z) ), 1.0 );
+    var samp = bounce_sampler( e, DIM_ROULETTE );
+    if ( survival <= 0.0 || sample_1d(&samp) >= survival ) {
+        return 0.0;
+    }
+
+    return 1.0 / survival;
+}
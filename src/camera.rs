@@ -1,39 +1,390 @@
-use crate::RENDER_TARGET_SIZE;
+use crate::density_grid::GridMedium;
+use crate::medium::ConstantMedium;
+use crate::sphere::Sphere;
 use bevy::{
-    input::mouse::MouseMotion,
-    prelude::*,
-    render::extract_resource::{ExtractResource, ExtractResourcePlugin},
+    core_pipeline::clear_color::ClearColorConfig, input::mouse::MouseMotion, prelude::*,
+    render::camera::Viewport, window::WindowResized,
 };
 
 const CAMERA_SPEED: f32 = 10.0;
+// About 53 degrees across a 16:9 width. Bevy measures the fov vertically.
+const CAMERA_FOV: f32 = 0.548;
+const FISHEYE_FOV: f32 = std::f32::consts::PI;
+const ORTHOGRAPHIC_HEIGHT: f32 = 10.0;
+// Focus distance change per second while [ or ] is held, as a fraction of
+// the current distance.
+const FOCUS_SPEED: f32 = 1.0;
+const FSTOPS: [f32; 8] = [1.4, 2.0, 2.8, 4.0, 5.6, 8.0, 11.0, 16.0];
 
-// The ray trace camera will be extracted to the render world
-// so we can use it to maintain and update a uniform buffer.
-// This is not a Bevy camera bundle or entity.
-#[derive(Clone, ExtractResource)]
-pub struct RayTraceCamera {
-    pub render_width: u32, // These should live elsewhere...
-    pub render_height: u32,
+// Marks a Camera3d to be rendered by the ray tracer instead of rasterized.
+// The camera's GlobalTransform places the rays and its PerspectiveProjection
+// sets the field of view. The traced image is drawn over the camera's render
+// target after its main pass.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct RayTraced {
+    pub projection: RayTraceProjection,
+    pub filter: ReconstructionFilter,
+    pub sampler: SamplerKind,
 
-    pub transform: Transform,
+    // Scattering events a path may have before it is cut off.
+    pub max_bounces: u32,
+    // Bounces before Russian roulette starts terminating paths by throughput.
+    pub roulette_depth: u32,
+
+    // Average frames together for as long as the camera and these settings
+    // stay the same.
+    pub accumulate: bool,
+    pub adaptive: AdaptiveSampling,
+    pub fireflies: FireflySuppression,
+
+    // Thin lens. Distances are in world units, so with meters a 0.05 focal
+    // length is a 50mm lens.
+    pub focus_distance: f32,
+    pub focal_length: f32,
+    pub fstop: f32,
+    // Keep focus_distance on whatever is under the center of the screen.
+    pub autofocus: bool,
+}
+
+// The RayTraced camera the keyboard and mouse control. Tab moves it to the
+// next active camera.
+#[derive(Component)]
+pub struct ActiveCamera;
+
+// Whether the second camera shares the window with the first, toggled with X.
+#[derive(Default)]
+pub struct SplitScreen(pub bool);
+
+impl Default for RayTraced {
+    fn default() -> Self {
+        RayTraced {
+            projection: RayTraceProjection::default(),
+            filter: ReconstructionFilter::Gaussian,
+            sampler: SamplerKind::Sobol,
+            max_bounces: 8,
+            roulette_depth: 3,
+            accumulate: true,
+            adaptive: AdaptiveSampling::default(),
+            fireflies: FireflySuppression::default(),
+            focus_distance: 10.0,
+            focal_length: 0.1,
+            fstop: 2.0,
+            autofocus: true,
+        }
+    }
+}
+
+// While accumulating, stop tracing pixels whose mean is known well enough.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveSampling {
+    pub enabled: bool,
+    // A pixel has converged once the standard error of its mean is below this
+    // fraction of its luminance, plus a little so black pixels converge too.
+    pub threshold: f32,
+    // Frames every pixel is traced before it can converge.
+    pub min_samples: u32,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        AdaptiveSampling {
+            enabled: true,
+            threshold: 0.02,
+            min_samples: 16,
+        }
+    }
+}
+
+// Ways of keeping fireflies out of the image, each trading a little bias for
+// a lot less noise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FireflySuppression {
+    // Scale each path's light down so no channel is above max_radiance,
+    // unless it came straight from a light or the sky.
+    pub clamp: bool,
+    pub max_radiance: f32,
+    // Make glossy and specular lobes at least a fraction as rough as the
+    // roughest lobe the path has scattered from, so paths that went through a
+    // diffuse surface don't find small bright lights by chance.
+    pub regularize: bool,
+    // Drop samples in collect that are much brighter than the rest of the
+    // filter window.
+    pub reject_outliers: bool,
+}
+
+impl Default for FireflySuppression {
+    fn default() -> Self {
+        FireflySuppression {
+            clamp: true,
+            max_radiance: 10.0,
+            regularize: true,
+            reject_outliers: false,
+        }
+    }
+}
+
+impl RayTraced {
+    pub fn aperture_radius(&self) -> f32 {
+        self.focal_length / (2.0 * self.fstop)
+    }
+}
+
+// Which image axis a perspective field of view spans.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FovAxis {
+    Horizontal,
+    Vertical,
+}
+
+// How pixels map to camera rays. The discriminants are the projection ids the
+// shaders switch on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RayTraceProjection {
+    // Rays through a pinhole, or the thin lens when the aperture is open,
+    // with the fov of the camera's PerspectiveProjection spanning `axis`.
+    Perspective { axis: FovAxis },
+    // Parallel rays from a rectangle `height` world units tall.
+    Orthographic { height: f32 },
+    // The full sphere around the camera, longitude across the width.
+    Equirectangular,
+    // Angle from the view axis proportional to the distance from the image
+    // center, `fov` across a circle fitting the height.
+    Fisheye { fov: f32 },
+}
+
+impl RayTraceProjection {
+    pub fn id(&self) -> u32 {
+        match self {
+            RayTraceProjection::Perspective { .. } => 0,
+            RayTraceProjection::Orthographic { .. } => 1,
+            RayTraceProjection::Equirectangular => 2,
+            RayTraceProjection::Fisheye { .. } => 3,
+        }
+    }
+
+    // The next projection for the P key, each with its default settings.
+    fn next(&self) -> Self {
+        match self {
+            RayTraceProjection::Perspective { .. } => RayTraceProjection::Orthographic {
+                height: ORTHOGRAPHIC_HEIGHT,
+            },
+            RayTraceProjection::Orthographic { .. } => RayTraceProjection::Equirectangular,
+            RayTraceProjection::Equirectangular => RayTraceProjection::Fisheye { fov: FISHEYE_FOV },
+            RayTraceProjection::Fisheye { .. } => RayTraceProjection::default(),
+        }
+    }
+}
+
+impl Default for RayTraceProjection {
+    fn default() -> Self {
+        // Matches how Bevy reads PerspectiveProjection::fov.
+        RayTraceProjection::Perspective {
+            axis: FovAxis::Vertical,
+        }
+    }
+}
+
+// How the samples around a pixel are weighted into it, by their distance
+// from the pixel center. The discriminants are the filter ids collect
+// switches on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReconstructionFilter {
+    // Only the pixel's own samples, equally weighted. Radius 0.5.
+    Box,
+    // Linear falloff. Radius 1.
+    Tent,
+    // pbrt's Gaussian with alpha 2. Radius 1.5.
+    Gaussian,
+    // Mitchell-Netravali with B = C = 1/3, slightly sharpening. Radius 2.
+    Mitchell,
+}
+
+impl ReconstructionFilter {
+    pub fn id(&self) -> u32 {
+        match self {
+            ReconstructionFilter::Box => 0,
+            ReconstructionFilter::Tent => 1,
+            ReconstructionFilter::Gaussian => 2,
+            ReconstructionFilter::Mitchell => 3,
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            ReconstructionFilter::Box => ReconstructionFilter::Tent,
+            ReconstructionFilter::Tent => ReconstructionFilter::Gaussian,
+            ReconstructionFilter::Gaussian => ReconstructionFilter::Mitchell,
+            ReconstructionFilter::Mitchell => ReconstructionFilter::Box,
+        }
+    }
+}
+
+// Where the random numbers of a path come from. Every decision along a path
+// draws from its own dimension, so the sequences stay stratified per decision.
+// The discriminants are the sampler ids the shaders switch on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SamplerKind {
+    // A PCG hash of the pixel, sample index and dimension.
+    Independent,
+    // Sobol points with hash-based Owen scrambling, shuffled per pixel.
+    Sobol,
+    // Golden ratio and R2 sequences, each pixel rotated by a blue noise tile,
+    // which pushes the error to high frequencies at low sample counts.
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub fn id(&self) -> u32 {
+        match self {
+            SamplerKind::Independent => 0,
+            SamplerKind::Sobol => 1,
+            SamplerKind::BlueNoise => 2,
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            SamplerKind::Independent => SamplerKind::Sobol,
+            SamplerKind::Sobol => SamplerKind::BlueNoise,
+            SamplerKind::BlueNoise => SamplerKind::Independent,
+        }
+    }
 }
 
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(ExtractResourcePlugin::<RayTraceCamera>::default())
+        app.init_resource::<SplitScreen>()
             .add_startup_system(setup)
-            .add_system(update);
+            .add_system(toggle_split_screen)
+            .add_system(update_viewports.after(toggle_split_screen))
+            .add_system(switch_active_camera.after(update_viewports))
+            .add_system(update)
+            .add_system(adjust_focus)
+            .add_system(cycle_projection)
+            .add_system(cycle_filter)
+            .add_system(cycle_sampler)
+            .add_system(toggle_accumulation)
+            .add_system(toggle_firefly_suppression)
+            .add_system(autofocus.after(adjust_focus));
     }
 }
 
 fn setup(mut commands: Commands) {
-    commands.insert_resource(RayTraceCamera {
-        render_width: RENDER_TARGET_SIZE.0,
-        render_height: RENDER_TARGET_SIZE.1,
-        transform: Transform::from_xyz(13., 2., 3.).looking_at(Vec3::ZERO, Vec3::Y),
-    });
+    commands
+        .spawn_bundle(Camera3dBundle {
+            transform: Transform::from_xyz(13., 2., 3.).looking_at(Vec3::ZERO, Vec3::Y),
+            projection: PerspectiveProjection {
+                fov: CAMERA_FOV,
+                ..default()
+            }
+            .into(),
+            ..default()
+        })
+        .insert(RayTraced::default())
+        .insert(ActiveCamera);
+
+    // Only drawn in split screen, over the right half of the window. The first
+    // camera has already cleared the target.
+    commands
+        .spawn_bundle(Camera3dBundle {
+            camera: Camera {
+                priority: 1,
+                is_active: false,
+                ..default()
+            },
+            camera_3d: Camera3d {
+                clear_color: ClearColorConfig::None,
+                ..default()
+            },
+            transform: Transform::from_xyz(-6., 8., 10.).looking_at(Vec3::ZERO, Vec3::Y),
+            projection: PerspectiveProjection {
+                fov: CAMERA_FOV,
+                ..default()
+            }
+            .into(),
+            ..default()
+        })
+        .insert(RayTraced::default());
+}
+
+fn toggle_split_screen(keys: Res<Input<KeyCode>>, mut split_screen: ResMut<SplitScreen>) {
+    if keys.just_pressed(KeyCode::X) {
+        split_screen.0 = !split_screen.0;
+        info!("Split screen: {}", split_screen.0);
+    }
+}
+
+// Gives each camera its half of the window in split screen, and the whole
+// window to the first camera otherwise.
+fn update_viewports(
+    split_screen: Res<SplitScreen>,
+    windows: Res<Windows>,
+    mut resized: EventReader<WindowResized>,
+    mut cameras: Query<&mut Camera, With<RayTraced>>,
+) {
+    let resized = resized
+        .iter()
+        .any(|event| event.id == windows.primary().id());
+    if !split_screen.is_changed() && !resized {
+        return;
+    }
+
+    let window = windows.primary();
+    let half = UVec2::new(window.physical_width() / 2, window.physical_height());
+
+    let mut cameras: Vec<_> = cameras.iter_mut().collect();
+    cameras.sort_by_key(|camera| camera.priority);
+
+    for (index, mut camera) in cameras.into_iter().enumerate() {
+        if split_screen.0 {
+            camera.is_active = index < 2;
+            camera.viewport = Some(Viewport {
+                physical_position: UVec2::new(half.x * index as u32, 0),
+                physical_size: half,
+                ..default()
+            });
+        } else {
+            camera.is_active = index == 0;
+            camera.viewport = None;
+        }
+    }
+}
+
+// Tab hands the controls to the next active camera. The controls also move
+// back to an active camera when theirs is turned off.
+fn switch_active_camera(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    cameras: Query<(Entity, &Camera, Option<&ActiveCamera>), With<RayTraced>>,
+) {
+    let mut active: Vec<_> = cameras
+        .iter()
+        .filter(|(_, camera, _)| camera.is_active)
+        .collect();
+    active.sort_by_key(|(_, camera, _)| camera.priority);
+
+    if active.is_empty() {
+        return;
+    }
+
+    let current = active.iter().position(|(_, _, marker)| marker.is_some());
+    let next = match current {
+        Some(current) if keys.just_pressed(KeyCode::Tab) => (current + 1) % active.len(),
+        Some(_) => return,
+        None => 0,
+    };
+
+    for (entity, _, marker) in cameras.iter() {
+        if marker.is_some() {
+            commands.entity(entity).remove::<ActiveCamera>();
+        }
+    }
+
+    let (entity, _, _) = active[next];
+    commands.entity(entity).insert(ActiveCamera);
+    info!("Controlling camera {:?}", entity);
 }
 
 fn update(
@@ -41,37 +392,206 @@ fn update(
     mut mouse_motion: EventReader<MouseMotion>,
     keys: Res<Input<KeyCode>>,
     mouse_buttons: Res<Input<MouseButton>>,
-    mut rt_camera: ResMut<RayTraceCamera>,
+    mut cameras: Query<&mut Transform, (With<RayTraced>, With<ActiveCamera>)>,
 ) {
-    let mut camera_transform = &mut rt_camera.transform;
     let delta_time = time.delta_seconds();
+    let motion: Vec<Vec2> = mouse_motion.iter().map(|motion| motion.delta).collect();
 
-    if keys.pressed(KeyCode::W) {
-        let forward = camera_transform.forward() * CAMERA_SPEED * delta_time;
-        camera_transform.translation += forward;
+    for mut camera_transform in cameras.iter_mut() {
+        if keys.pressed(KeyCode::W) {
+            let forward = camera_transform.forward() * CAMERA_SPEED * delta_time;
+            camera_transform.translation += forward;
+        }
+
+        if keys.pressed(KeyCode::A) {
+            let left = camera_transform.left() * CAMERA_SPEED * delta_time;
+            camera_transform.translation += left;
+        }
+
+        if keys.pressed(KeyCode::S) {
+            let back = camera_transform.back() * CAMERA_SPEED * delta_time;
+            camera_transform.translation += back;
+        }
+
+        if keys.pressed(KeyCode::D) {
+            let right = camera_transform.right() * CAMERA_SPEED * delta_time;
+            camera_transform.translation += right;
+        }
+
+        if mouse_buttons.pressed(MouseButton::Right) {
+            for delta in motion.iter() {
+                let yaw = Quat::from_rotation_y(-delta.x * delta_time);
+                let pitch = Quat::from_rotation_x(-delta.y * delta_time);
+                camera_transform.rotation = yaw * camera_transform.rotation;
+                camera_transform.rotation = camera_transform.rotation * pitch;
+            }
+        }
     }
+}
 
-    if keys.pressed(KeyCode::A) {
-        let left = camera_transform.left() * CAMERA_SPEED * delta_time;
-        camera_transform.translation += left;
+// [ and ] move the focus, which turns autofocus off. - and = step through
+// f-stops, F toggles autofocus.
+fn adjust_focus(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    mut cameras: Query<&mut RayTraced, With<ActiveCamera>>,
+) {
+    let step = 1.0 + FOCUS_SPEED * time.delta_seconds();
+
+    for mut rt_camera in cameras.iter_mut() {
+        if keys.pressed(KeyCode::LBracket) {
+            rt_camera.focus_distance = (rt_camera.focus_distance / step).max(0.01);
+            rt_camera.autofocus = false;
+        }
+
+        if keys.pressed(KeyCode::RBracket) {
+            rt_camera.focus_distance *= step;
+            rt_camera.autofocus = false;
+        }
+
+        let stop = FSTOPS
+            .iter()
+            .position(|fstop| *fstop >= rt_camera.fstop)
+            .unwrap_or(FSTOPS.len() - 1);
+
+        if keys.just_pressed(KeyCode::Minus) {
+            rt_camera.fstop = FSTOPS[stop.saturating_sub(1)];
+            info!("Aperture: f/{}", rt_camera.fstop);
+        }
+
+        if keys.just_pressed(KeyCode::Equals) {
+            rt_camera.fstop = FSTOPS[(stop + 1).min(FSTOPS.len() - 1)];
+            info!("Aperture: f/{}", rt_camera.fstop);
+        }
+
+        if keys.just_pressed(KeyCode::F) {
+            rt_camera.autofocus = !rt_camera.autofocus;
+            info!("Autofocus: {}", rt_camera.autofocus);
+        }
     }
+}
 
-    if keys.pressed(KeyCode::S) {
-        let back = camera_transform.back() * CAMERA_SPEED * delta_time;
-        camera_transform.translation += back;
+// P steps through the projections.
+fn cycle_projection(
+    keys: Res<Input<KeyCode>>,
+    mut cameras: Query<&mut RayTraced, With<ActiveCamera>>,
+) {
+    if !keys.just_pressed(KeyCode::P) {
+        return;
+    }
+
+    for mut rt_camera in cameras.iter_mut() {
+        rt_camera.projection = rt_camera.projection.next();
+        info!("Projection: {:?}", rt_camera.projection);
     }
+}
 
-    if keys.pressed(KeyCode::D) {
-        let right = camera_transform.right() * CAMERA_SPEED * delta_time;
-        camera_transform.translation += right;
+// R steps through the reconstruction filters.
+fn cycle_filter(keys: Res<Input<KeyCode>>, mut cameras: Query<&mut RayTraced, With<ActiveCamera>>) {
+    if !keys.just_pressed(KeyCode::R) {
+        return;
     }
 
-    if mouse_buttons.pressed(MouseButton::Right) {
-        for motion in mouse_motion.iter() {
-            let yaw = Quat::from_rotation_y(-motion.delta.x * delta_time);
-            let pitch = Quat::from_rotation_x(-motion.delta.y * delta_time);
-            camera_transform.rotation = yaw * camera_transform.rotation;
-            camera_transform.rotation = camera_transform.rotation * pitch;
+    for mut rt_camera in cameras.iter_mut() {
+        rt_camera.filter = rt_camera.filter.next();
+        info!("Filter: {:?}", rt_camera.filter);
+    }
+}
+
+// N steps through the samplers.
+fn cycle_sampler(
+    keys: Res<Input<KeyCode>>,
+    mut cameras: Query<&mut RayTraced, With<ActiveCamera>>,
+) {
+    if !keys.just_pressed(KeyCode::N) {
+        return;
+    }
+
+    for mut rt_camera in cameras.iter_mut() {
+        rt_camera.sampler = rt_camera.sampler.next();
+        info!("Sampler: {:?}", rt_camera.sampler);
+    }
+}
+
+// C toggles accumulation, V adaptive sampling.
+fn toggle_accumulation(
+    keys: Res<Input<KeyCode>>,
+    mut cameras: Query<&mut RayTraced, With<ActiveCamera>>,
+) {
+    for mut rt_camera in cameras.iter_mut() {
+        if keys.just_pressed(KeyCode::C) {
+            rt_camera.accumulate = !rt_camera.accumulate;
+            info!("Accumulate: {}", rt_camera.accumulate);
+        }
+
+        if keys.just_pressed(KeyCode::V) {
+            rt_camera.adaptive.enabled = !rt_camera.adaptive.enabled;
+            info!("Adaptive sampling: {}", rt_camera.adaptive.enabled);
+        }
+    }
+}
+
+// 1 toggles clamping, 2 regularization and 3 outlier rejection.
+fn toggle_firefly_suppression(
+    keys: Res<Input<KeyCode>>,
+    mut cameras: Query<&mut RayTraced, With<ActiveCamera>>,
+) {
+    for mut rt_camera in cameras.iter_mut() {
+        let fireflies = &mut rt_camera.fireflies;
+
+        if keys.just_pressed(KeyCode::Key1) {
+            fireflies.clamp = !fireflies.clamp;
+            info!("Clamp radiance: {}", fireflies.clamp);
+        }
+
+        if keys.just_pressed(KeyCode::Key2) {
+            fireflies.regularize = !fireflies.regularize;
+            info!("Regularize roughness: {}", fireflies.regularize);
+        }
+
+        if keys.just_pressed(KeyCode::Key3) {
+            fireflies.reject_outliers = !fireflies.reject_outliers;
+            info!("Reject outliers: {}", fireflies.reject_outliers);
+        }
+    }
+}
+
+// Focus on the nearest object straight ahead of the camera. Media are
+// focused on where the ray enters their bounds.
+fn autofocus(
+    spheres: Query<(&Sphere, &GlobalTransform)>,
+    media: Query<(&ConstantMedium, &GlobalTransform)>,
+    grids: Query<(&GridMedium, &GlobalTransform)>,
+    mut cameras: Query<(&GlobalTransform, &mut RayTraced)>,
+) {
+    let center = |transform: &GlobalTransform| Transform::from(*transform).translation;
+
+    for (camera_transform, mut rt_camera) in cameras.iter_mut() {
+        if !rt_camera.autofocus {
+            continue;
+        }
+
+        let camera_transform = Transform::from(*camera_transform);
+        let origin = camera_transform.translation;
+        let dir = camera_transform.forward();
+
+        let spheres = spheres
+            .iter()
+            .filter_map(|(sphere, transform)| sphere.intersect(center(transform), origin, dir));
+        let media = media
+            .iter()
+            .filter_map(|(medium, transform)| medium.intersect(center(transform), origin, dir));
+        let grids = grids
+            .iter()
+            .filter_map(|(grid, transform)| grid.intersect(transform, origin, dir));
+
+        let nearest = spheres
+            .chain(media)
+            .chain(grids)
+            .fold(f32::INFINITY, f32::min);
+
+        if nearest.is_finite() {
+            rt_camera.focus_distance = nearest;
         }
     }
 }
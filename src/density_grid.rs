@@ -0,0 +1,364 @@
+use crate::ray_trace_materials::{MaterialCache, RayTraceMaterial, Reflectance};
+use bevy::{
+    asset::HandleId,
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        MainWorld, RenderApp, RenderStage,
+    },
+};
+use std::{fs, io, num::NonZeroU32, path::Path};
+
+// Grid loaded at startup. Without it a procedural puff of smoke is used instead.
+const DENSITY_GRID_PATH: &str = "assets/volumes/smoke.vol";
+const FALLBACK_GRID_SIZE: u32 = 64;
+
+// A 3D grid of densities normalized to 0..1, x varying fastest. Every
+// GridMedium samples this one grid.
+#[derive(Clone, ExtractResource)]
+pub struct DensityGrid {
+    pub size: UVec3,
+    pub data: Vec<u8>,
+}
+
+impl DensityGrid {
+    // Mitsuba's binary gridvol format, with float32 or uint8 encoding. Only
+    // the first channel is kept.
+    pub fn load_vol<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        if bytes.len() < 48 || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+            return Err(invalid("not a version 3 .vol file"));
+        }
+
+        let int = |offset: usize| {
+            i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        let encoding = int(4);
+        let (x, y, z) = (int(8), int(12), int(16));
+        let channels = int(20).max(1);
+        let voxels = x * y * z;
+
+        // Skip the header and the bounding box after it.
+        let body = &bytes[48..];
+        let densities: Vec<f32> = match encoding {
+            1 if body.len() >= voxels * channels * 4 => (0..voxels)
+                .map(|v| {
+                    let offset = v * channels * 4;
+                    f32::from_le_bytes(body[offset..offset + 4].try_into().unwrap())
+                })
+                .collect(),
+            3 if body.len() >= voxels * channels => (0..voxels)
+                .map(|v| body[v * channels] as f32 / 255.0)
+                .collect(),
+            1 | 3 => return Err(invalid("truncated .vol file")),
+            _ => return Err(invalid("only float32 and uint8 .vol files are supported")),
+        };
+
+        Ok(Self::normalized(
+            UVec3::new(x as u32, y as u32, z as u32),
+            &densities,
+        ))
+    }
+
+    // A lumpy ball of smoke, denser in the middle.
+    pub fn smoke_puff(resolution: u32) -> Self {
+        let size = UVec3::splat(resolution);
+        let mut densities = Vec::with_capacity((resolution * resolution * resolution) as usize);
+
+        for z in 0..resolution {
+            for y in 0..resolution {
+                for x in 0..resolution {
+                    let p =
+                        (Vec3::new(x as f32, y as f32, z as f32) + 0.5) / resolution as f32 - 0.5;
+                    let lumps = (p.x * 17.0).sin() * (p.y * 13.0).sin() * (p.z * 19.0).sin();
+                    let falloff = 1.0 - p.length() * 2.0;
+                    densities.push((falloff + 0.25 * lumps * falloff).max(0.0));
+                }
+            }
+        }
+
+        Self::normalized(size, &densities)
+    }
+
+    fn normalized(size: UVec3, densities: &[f32]) -> Self {
+        let max = densities.iter().cloned().fold(0.0, f32::max);
+        let scale = if max > 0.0 { 255.0 / max } else { 0.0 };
+
+        DensityGrid {
+            size,
+            data: densities
+                .iter()
+                .map(|d| (d.max(0.0) * scale).round() as u8)
+                .collect(),
+        }
+    }
+}
+
+// A heterogeneous volume filling the unit cube around the entity, placed,
+// rotated and stretched by its Transform. `density` is the extinction where
+// the grid is at its densest. Scatters with the Handle<RayTraceMaterial> on
+// the same entity, which should be Reflectance::Volume.
+#[derive(Component, Default, Clone, Debug)]
+pub struct GridMedium {
+    pub density: f32,
+}
+
+impl GridMedium {
+    // Distance along a normalized ray to the grid's bounding box.
+    pub fn intersect(&self, transform: &GlobalTransform, origin: Vec3, dir: Vec3) -> Option<f32> {
+        // Distances are the same in the unit cube's space, as long as the
+        // direction isn't normalized again.
+        let world_to_local = transform.compute_matrix().inverse();
+        let origin = world_to_local.transform_point3(origin);
+        let dir = world_to_local.transform_vector3(dir);
+
+        let t0 = (Vec3::splat(-0.5) - origin) / dir;
+        let t1 = (Vec3::splat(0.5) - origin) / dir;
+        let near = t0.min(t1).max_element();
+        let far = t0.max(t1).min_element();
+
+        if near > far || far <= 0.0 {
+            None
+        } else if near > 0.0 {
+            Some(near)
+        } else {
+            Some(far)
+        }
+    }
+}
+
+// Grid media pulled from the main world, waiting for their materials to be
+// mapped to indices in prepare.
+#[derive(Default)]
+struct ExtractedGridMedia {
+    grids: Vec<(GridMediumGPU, HandleId)>,
+}
+
+#[derive(ShaderType, Clone, Default, Debug)]
+struct GridMediumGPU {
+    world_to_local: Mat4,
+    density: f32,
+    material: u32,
+}
+
+#[derive(ShaderType, Clone, Default, Debug)]
+pub struct GridMediumListGPU {
+    grid_count: u32,
+    #[size(runtime)]
+    grids: Vec<GridMediumGPU>,
+}
+
+#[derive(Default)]
+pub struct GridMediumListStorage {
+    pub buffer: StorageBuffer<GridMediumListGPU>,
+}
+
+pub struct DensityGridTexture {
+    pub texture: Texture,
+    pub view: TextureView,
+    pub sampler: Sampler,
+}
+
+impl DensityGridTexture {
+    fn new(render_device: &RenderDevice, size: UVec3) -> Self {
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("density_grid"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: size.z,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D3,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        });
+
+        let view = texture.create_view(&TextureViewDescriptor {
+            label: Some("density_grid_view"),
+            ..default()
+        });
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("density_grid_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        DensityGridTexture {
+            texture,
+            view,
+            sampler,
+        }
+    }
+}
+
+impl FromWorld for DensityGridTexture {
+    fn from_world(world: &mut World) -> Self {
+        // Empty until the grid is extracted.
+        DensityGridTexture::new(world.resource::<RenderDevice>(), UVec3::ONE)
+    }
+}
+
+pub fn init_grid_media(mut commands: Commands, mut materials: ResMut<Assets<RayTraceMaterial>>) {
+    let grid = DensityGrid::load_vol(DENSITY_GRID_PATH).unwrap_or_else(|err| {
+        info!(
+            "No density grid at {} ({}), using a procedural one",
+            DENSITY_GRID_PATH, err
+        );
+        DensityGrid::smoke_puff(FALLBACK_GRID_SIZE)
+    });
+    commands.insert_resource(grid);
+
+    commands
+        .spawn()
+        .insert_bundle(TransformBundle::from(
+            Transform::from_xyz(-2.0, 1.0, 2.5).with_scale(Vec3::splat(1.6)),
+        ))
+        .insert(GridMedium { density: 8.0 })
+        .insert(materials.add(RayTraceMaterial {
+            reflectance: Reflectance::Volume,
+            color: Color::rgba(0.8, 0.8, 0.8, 1.0),
+            anisotropy: 0.3,
+            ..default()
+        }));
+}
+
+pub struct DensityGridPlugin;
+
+impl Plugin for DensityGridPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(DensityGrid {
+            size: UVec3::ONE,
+            data: vec![0],
+        })
+        .add_plugin(ExtractResourcePlugin::<DensityGrid>::default())
+        .add_startup_system(init_grid_media);
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .init_resource::<ExtractedGridMedia>()
+                .insert_resource(GridMediumListStorage::default())
+                .init_resource::<DensityGridTexture>()
+                .add_system_to_stage(RenderStage::Extract, extract)
+                .add_system_to_stage(RenderStage::Prepare, prepare)
+                .add_system_to_stage(RenderStage::Prepare, prepare_texture);
+        }
+    }
+}
+
+fn extract(mut world: ResMut<MainWorld>, mut extracted: ResMut<ExtractedGridMedia>) {
+    let mut query = world.query::<(&GridMedium, &GlobalTransform, &Handle<RayTraceMaterial>)>();
+
+    extracted.grids.clear();
+
+    for (grid, transform, material) in query.iter(&world) {
+        extracted.grids.push((
+            GridMediumGPU {
+                world_to_local: transform.compute_matrix().inverse(),
+                density: grid.density,
+                material: 0,
+            },
+            material.id,
+        ));
+    }
+}
+
+fn prepare(
+    mut extracted: ResMut<ExtractedGridMedia>,
+    materials: Res<MaterialCache>,
+    mut grid_list_storage: ResMut<GridMediumListStorage>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    let gpu = grid_list_storage.buffer.get_mut();
+    gpu.grid_count = extracted.grids.len() as u32;
+    gpu.grids.clear();
+    for (mut grid, material) in extracted.grids.drain(..) {
+        grid.material = materials.index_of(material);
+        gpu.grids.push(grid);
+    }
+
+    grid_list_storage
+        .buffer
+        .write_buffer(&render_device, &render_queue);
+}
+
+fn prepare_texture(
+    grid: Res<DensityGrid>,
+    mut texture: ResMut<DensityGridTexture>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    if !grid.is_changed() {
+        return;
+    }
+
+    println!("uploading {:?} density grid", grid.size);
+    *texture = DensityGridTexture::new(&render_device, grid.size);
+
+    render_queue.write_texture(
+        ImageCopyTexture {
+            texture: &texture.texture,
+            mip_level: 0,
+            origin: Origin3d::ZERO,
+            aspect: TextureAspect::All,
+        },
+        &grid.data,
+        ImageDataLayout {
+            offset: 0,
+            bytes_per_row: NonZeroU32::new(grid.size.x),
+            rows_per_image: NonZeroU32::new(grid.size.y),
+        },
+        Extent3d {
+            width: grid.size.x,
+            height: grid.size.y,
+            depth_or_array_layers: grid.size.z,
+        },
+    );
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+pub fn describe_texture(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D3,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+pub fn describe_sampler(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+        count: None,
+    }
+}
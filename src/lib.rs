@@ -1,40 +1,68 @@
 mod camera;
+mod density_grid;
 mod input;
+mod medium;
 mod plugin;
+mod ray_trace_accumulation;
 mod ray_trace_camera;
+mod ray_trace_debug;
+mod ray_trace_diagnostics;
 mod ray_trace_globals;
 mod ray_trace_intersection;
 mod ray_trace_materials;
+pub mod ray_trace_mode;
 mod ray_trace_node;
 mod ray_trace_output;
+pub mod ray_trace_pass;
 mod ray_trace_pipeline;
+mod ray_trace_procedural;
+mod ray_trace_profiling;
+mod ray_trace_queue;
 mod ray_trace_rays;
+mod ray_trace_readback;
+mod ray_trace_sampler;
+mod ray_trace_stats;
+mod ray_trace_textures;
+mod ray_trace_throughput;
 mod sphere;
 
 use bevy::{
+    asset::AssetServerSettings,
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     prelude::*,
     window::{WindowDescriptor, WindowMode},
 };
 
 use camera::CameraPlugin;
+use density_grid::DensityGridPlugin;
 use input::InputPlugin;
+use medium::MediumRenderPlugin;
 use plugin::RayTracePlugin;
 use sphere::SphereRenderPlugin;
 
-pub const RENDER_TARGET_SIZE: (u32, u32) = (1920, 1080);
+pub const WINDOW_SIZE: (u32, u32) = (1920, 1080);
+// Samples traced per pixel each frame. At 1, the reconstruction filter only
+// spreads a frame's samples over neighbouring pixels; a pixel gathers several
+// samples of its own by accumulating frames.
 pub const SAMPLES_PER_RAY: usize = 1;
 
 pub fn entry() {
     App::new()
         .insert_resource(WindowDescriptor {
             title: "bevy_raytrace".to_string(),
-            width: RENDER_TARGET_SIZE.0 as f32,
-            height: RENDER_TARGET_SIZE.1 as f32,
+            width: WINDOW_SIZE.0 as f32,
+            height: WINDOW_SIZE.1 as f32,
             resizable: true,
             mode: WindowMode::BorderlessFullscreen,
             ..default()
         })
+        // Hot reload shaders and .rtmat.ron materials in debug builds. The
+        // filesystem_watcher feature only compiles the watcher in, so release
+        // builds load assets once as before.
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: cfg!(debug_assertions),
+            ..default()
+        })
         .insert_resource(ClearColor(Color::rgba(0.35, 0.35, 0.35, 1.0)))
         .add_plugins(DefaultPlugins)
         .add_plugin(LogDiagnosticsPlugin::default())
@@ -43,12 +71,7 @@ pub fn entry() {
         .add_plugin(InputPlugin)
         .add_plugin(RayTracePlugin)
         .add_plugin(SphereRenderPlugin)
-        .add_startup_system(init_camera)
+        .add_plugin(MediumRenderPlugin)
+        .add_plugin(DensityGridPlugin)
         .run();
 }
-
-pub fn init_camera(mut commands: Commands) {
-    // A bevy camera that simply stares at the origin and our render target sprite.
-    // This will never move and is not the ray trace camera.
-    commands.spawn_bundle(Camera2dBundle::default());
-}
@@ -0,0 +1,177 @@
+use crate::ray_trace_materials::{MaterialCache, RayTraceMaterial, Reflectance};
+use crate::sphere::intersect_sphere;
+use bevy::{
+    asset::HandleId,
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        MainWorld, RenderApp, RenderStage,
+    },
+};
+
+#[derive(ShaderType, Clone, Default, Debug)]
+struct MediumGPU {
+    center: Vec3,
+    radius: f32,
+    density: f32,
+    material: u32,
+}
+
+#[derive(ShaderType, Clone, Default, Debug)]
+pub struct MediumListGPU {
+    fog_density: f32,
+    fog_height: f32,
+    fog_falloff: f32,
+    fog_material: u32,
+    medium_count: u32,
+    #[size(runtime)]
+    media: Vec<MediumGPU>,
+}
+
+#[derive(Default)]
+pub struct MediumListStorage {
+    pub buffer: StorageBuffer<MediumListGPU>,
+}
+
+// A volume of constant density bounded by a sphere, the ConstantMedium from
+// "Ray Tracing: The Next Week". Scatters with the Handle<RayTraceMaterial> on
+// the same entity, which should be Reflectance::Volume with the albedo as
+// its color.
+#[derive(Component, Default, Clone, Debug)]
+pub struct ConstantMedium {
+    radius: f32,
+    density: f32,
+}
+
+impl ConstantMedium {
+    // Distance along a normalized ray to its bounding sphere.
+    pub fn intersect(&self, center: Vec3, origin: Vec3, dir: Vec3) -> Option<f32> {
+        intersect_sphere(center, self.radius, origin, dir)
+    }
+}
+
+// Media pulled from the main world, waiting for their materials to be mapped
+// to indices in prepare.
+#[derive(Default)]
+struct ExtractedMedia {
+    media: Vec<(MediumGPU, HandleId)>,
+}
+
+// Fog filling the whole world, with `density` at `height` falling off
+// exponentially above it by `falloff` per unit. Zero density turns it off.
+#[derive(Default, Clone, ExtractResource)]
+pub struct HeightFog {
+    pub density: f32,
+    pub height: f32,
+    pub falloff: f32,
+    pub material: Handle<RayTraceMaterial>,
+}
+
+pub fn init_media(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<RayTraceMaterial>>,
+    mut fog: ResMut<HeightFog>,
+) {
+    commands
+        .spawn()
+        .insert_bundle(TransformBundle::from(Transform::from_xyz(2.0, 0.7, 2.5)))
+        .insert(ConstantMedium {
+            radius: 0.7,
+            density: 2.0,
+        })
+        .insert(materials.add(RayTraceMaterial {
+            reflectance: Reflectance::Volume,
+            color: Color::rgba(0.8, 0.8, 0.8, 1.0),
+            anisotropy: 0.0,
+            ..default()
+        }));
+
+    // Off until the density is raised.
+    *fog = HeightFog {
+        density: 0.0,
+        height: 0.0,
+        falloff: 0.5,
+        material: materials.add(RayTraceMaterial {
+            reflectance: Reflectance::Volume,
+            color: Color::rgba(0.9, 0.9, 0.9, 1.0),
+            anisotropy: 0.6,
+            ..default()
+        }),
+    };
+}
+
+pub struct MediumRenderPlugin;
+
+impl Plugin for MediumRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HeightFog>()
+            .add_plugin(ExtractResourcePlugin::<HeightFog>::default())
+            .add_startup_system(init_media);
+
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .init_resource::<ExtractedMedia>()
+                .insert_resource(MediumListStorage::default())
+                .add_system_to_stage(RenderStage::Extract, extract)
+                .add_system_to_stage(RenderStage::Prepare, prepare);
+        }
+    }
+}
+
+fn extract(mut world: ResMut<MainWorld>, mut extracted: ResMut<ExtractedMedia>) {
+    let mut query = world.query::<(&ConstantMedium, &GlobalTransform, &Handle<RayTraceMaterial>)>();
+
+    extracted.media.clear();
+
+    for (medium, transform, material) in query.iter(&world) {
+        extracted.media.push((
+            MediumGPU {
+                center: Transform::from(*transform).translation,
+                radius: medium.radius,
+                density: medium.density,
+                material: 0,
+            },
+            material.id,
+        ));
+    }
+}
+
+fn prepare(
+    fog: Res<HeightFog>,
+    mut extracted: ResMut<ExtractedMedia>,
+    materials: Res<MaterialCache>,
+    mut medium_list_storage: ResMut<MediumListStorage>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    let gpu = medium_list_storage.buffer.get_mut();
+    gpu.fog_density = fog.density;
+    gpu.fog_height = fog.height;
+    gpu.fog_falloff = fog.falloff;
+    gpu.fog_material = materials.index_of(fog.material.id);
+    gpu.medium_count = extracted.media.len() as u32;
+    gpu.media.clear();
+    for (mut medium, material) in extracted.media.drain(..) {
+        medium.material = materials.index_of(material);
+        gpu.media.push(medium);
+    }
+
+    medium_list_storage
+        .buffer
+        .write_buffer(&render_device, &render_queue);
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
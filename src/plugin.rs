@@ -4,23 +4,42 @@ use bevy::{
         render_graph::RenderGraph, render_resource::*, renderer::RenderDevice, RenderApp,
         RenderStage,
     },
+    utils::HashMap,
 };
 
+use crate::density_grid::{DensityGridTexture, GridMediumListStorage};
+use crate::medium::MediumListStorage;
+use crate::ray_trace_accumulation::{PixelStatsGPUStorage, RayTraceAccumulationPlugin};
 use crate::ray_trace_camera::{CameraGPUStorage, RayTraceCameraPlugin};
+use crate::ray_trace_debug::RayTraceDebugPlugin;
+use crate::ray_trace_diagnostics::RayTraceDiagnosticsPlugin;
 use crate::ray_trace_globals::{GlobalsGPUStorage, RayTraceGlobalsPlugin};
 use crate::ray_trace_intersection::{IntersectionGPUStorage, RayTraceIntersectionsPlugin};
 use crate::ray_trace_materials::{MaterialGPUStorage, RayTraceMaterialsPlugin};
+use crate::ray_trace_mode::RayTraceModePlugin;
 use crate::ray_trace_node::RayTraceNode;
 use crate::ray_trace_output::RayTraceOutputPlugin;
+use crate::ray_trace_pass::RayTracePasses;
 use crate::ray_trace_pipeline::*;
+use crate::ray_trace_procedural::{PerlinGPUStorage, RayTraceProceduralPlugin};
+use crate::ray_trace_profiling::RayTraceProfilingPlugin;
+use crate::ray_trace_queue::{
+    DispatchIndirectStorage, MaterialQueueGPUStorage, RayQueueGPUStorage, RayTraceQueuePlugin,
+};
 use crate::ray_trace_rays::{RayBufGPUStorage, RayTraceRaysPlugin};
+use crate::ray_trace_sampler::{BlueNoiseGPUStorage, RayTraceSamplerPlugin};
+use crate::ray_trace_stats::{RayStatsGPUStorage, RayTraceStatsPlugin};
+use crate::ray_trace_textures::{MaterialTextureArray, RayTraceTexturesPlugin};
+use crate::ray_trace_throughput::{RayTraceThroughputPlugin, ThroughputGPUStorage};
 use crate::sphere::ObjectListStorage;
 
 pub struct RayTracePlugin;
 
-pub struct CameraGlobalsBindGroup(pub BindGroup);
-pub struct RaysIntersectionsBindGroup(pub BindGroup);
+// Keyed by RayTraced camera entity.
+pub struct CameraGlobalsBindGroup(pub HashMap<Entity, BindGroup>);
+pub struct RaysIntersectionsBindGroup(pub HashMap<Entity, BindGroup>);
 pub struct ObjectsMaterialsBindGroup(pub BindGroup);
+pub struct DispatchBindGroup(pub HashMap<Entity, BindGroup>);
 
 impl Plugin for RayTracePlugin {
     fn build(&self, app: &mut App) {
@@ -28,15 +47,29 @@ impl Plugin for RayTracePlugin {
             .add_plugin(RayTraceGlobalsPlugin)
             .add_plugin(RayTraceRaysPlugin)
             .add_plugin(RayTraceIntersectionsPlugin)
+            .add_plugin(RayTraceThroughputPlugin)
+            .add_plugin(RayTraceAccumulationPlugin)
+            .add_plugin(RayTraceQueuePlugin)
             .add_plugin(RayTraceMaterialsPlugin)
-            .add_plugin(RayTraceOutputPlugin);
+            .add_plugin(RayTraceTexturesPlugin)
+            .add_plugin(RayTraceProceduralPlugin)
+            .add_plugin(RayTraceSamplerPlugin)
+            .add_plugin(RayTraceModePlugin)
+            .add_plugin(RayTraceDebugPlugin)
+            .add_plugin(RayTraceDiagnosticsPlugin)
+            .add_plugin(RayTraceStatsPlugin)
+            .add_plugin(RayTraceOutputPlugin)
+            .add_plugin(RayTraceProfilingPlugin);
 
         let render_app = app.sub_app_mut(RenderApp);
         render_app
+            .init_resource::<RayTracePasses>()
             .init_resource::<RayTracePipeline>()
+            .add_system_to_stage(RenderStage::Prepare, queue_pass_pipelines)
             .add_system_to_stage(RenderStage::Queue, queue_camera_globals)
             .add_system_to_stage(RenderStage::Queue, queue_rays_intersections)
-            .add_system_to_stage(RenderStage::Queue, queue_objects_materials);
+            .add_system_to_stage(RenderStage::Queue, queue_objects_materials)
+            .add_system_to_stage(RenderStage::Queue, queue_dispatch);
 
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
         render_graph.add_node("raytrace", RayTraceNode::default());
@@ -51,56 +84,134 @@ fn queue_camera_globals(
     pipeline: Res<RayTracePipeline>,
     camera: Res<CameraGPUStorage>,
     globals: Res<GlobalsGPUStorage>,
+    blue_noise: Res<BlueNoiseGPUStorage>,
+    stats: Res<RayStatsGPUStorage>,
     render_device: Res<RenderDevice>,
 ) {
-    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-        label: Some("camera_globals_bind_group"),
-        layout: &pipeline.bind_groups.camera_globals,
-        entries: &[
-            BindGroupEntry {
-                binding: 0,
-                resource: camera.buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: globals.buffer.binding().unwrap(),
-            },
-        ],
-    });
+    let mut bind_groups = HashMap::default();
 
-    commands.insert_resource(CameraGlobalsBindGroup(bind_group));
+    for (entity, camera) in camera.buffers.iter() {
+        // Views are left out until every buffer they bind is prepared.
+        let (globals, stats) = match (globals.buffers.get(entity), stats.buffers.get(entity)) {
+            (Some(globals), Some(stats)) => (globals, stats),
+            _ => continue,
+        };
+
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("camera_globals_bind_group"),
+            layout: &pipeline.bind_groups.camera_globals,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: camera.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: globals.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: blue_noise.buffer.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: stats.buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        bind_groups.insert(*entity, bind_group);
+    }
+
+    commands.insert_resource(CameraGlobalsBindGroup(bind_groups));
 }
 
+#[allow(clippy::too_many_arguments)]
 fn queue_rays_intersections(
     mut commands: Commands,
     pipeline: Res<RayTracePipeline>,
     rays: Res<RayBufGPUStorage>,
     intersections: Res<IntersectionGPUStorage>,
+    throughputs: Res<ThroughputGPUStorage>,
+    pixel_stats: Res<PixelStatsGPUStorage>,
+    queues: Res<RayQueueGPUStorage>,
+    material_queues: Res<MaterialQueueGPUStorage>,
     render_device: Res<RenderDevice>,
 ) {
-    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-        label: Some("rays_intersections_bind_group"),
-        layout: &pipeline.bind_groups.rays_intersections,
-        entries: &[
-            BindGroupEntry {
-                binding: 0,
-                resource: rays.buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: intersections.buffer.binding().unwrap(),
-            },
-        ],
-    });
+    let mut bind_groups = HashMap::default();
+
+    for (entity, rays) in rays.buffers.iter() {
+        let (intersections, queues, material_queues, throughputs, pixel_stats) = match (
+            intersections.buffers.get(entity),
+            queues.buffers.get(entity),
+            material_queues.buffers.get(entity),
+            throughputs.buffers.get(entity),
+            pixel_stats.buffers.get(entity),
+        ) {
+            (
+                Some(intersections),
+                Some(queues),
+                Some(material_queues),
+                Some(throughputs),
+                Some(pixel_stats),
+            ) => (
+                intersections,
+                queues,
+                material_queues,
+                throughputs,
+                pixel_stats,
+            ),
+            _ => continue,
+        };
+
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("rays_intersections_bind_group"),
+            layout: &pipeline.bind_groups.rays_intersections,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: rays.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: intersections.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: queues.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: material_queues.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: throughputs.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: pixel_stats.binding().unwrap(),
+                },
+            ],
+        });
 
-    commands.insert_resource(RaysIntersectionsBindGroup(bind_group));
+        bind_groups.insert(*entity, bind_group);
+    }
+
+    commands.insert_resource(RaysIntersectionsBindGroup(bind_groups));
 }
 
+#[allow(clippy::too_many_arguments)]
 fn queue_objects_materials(
     mut commands: Commands,
     pipeline: Res<RayTracePipeline>,
     objects: Res<ObjectListStorage>,
     materials: Res<MaterialGPUStorage>,
+    textures: Res<MaterialTextureArray>,
+    perlin: Res<PerlinGPUStorage>,
+    media: Res<MediumListStorage>,
+    grid_media: Res<GridMediumListStorage>,
+    density_grid: Res<DensityGridTexture>,
     render_device: Res<RenderDevice>,
 ) {
     let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
@@ -115,8 +226,60 @@ fn queue_objects_materials(
                 binding: 1,
                 resource: materials.buffer.binding().unwrap(),
             },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(&textures.view),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::Sampler(&textures.sampler),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: perlin.buffer.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: media.buffer.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: grid_media.buffer.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: BindingResource::TextureView(&density_grid.view),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: BindingResource::Sampler(&density_grid.sampler),
+            },
         ],
     });
 
     commands.insert_resource(ObjectsMaterialsBindGroup(bind_group));
 }
+
+fn queue_dispatch(
+    mut commands: Commands,
+    pipeline: Res<RayTracePipeline>,
+    dispatch: Res<DispatchIndirectStorage>,
+    render_device: Res<RenderDevice>,
+) {
+    let mut bind_groups = HashMap::default();
+
+    for (entity, buffer) in dispatch.buffers.iter() {
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("dispatch_bind_group"),
+            layout: &pipeline.bind_groups.dispatch,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+
+        bind_groups.insert(*entity, bind_group);
+    }
+
+    commands.insert_resource(DispatchBindGroup(bind_groups));
+}
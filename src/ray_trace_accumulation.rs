@@ -0,0 +1,87 @@
+use crate::ray_trace_camera::ExtractedRayTraceViews;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+    utils::HashMap,
+};
+
+// Running statistics of one pixel's filtered color over the frames
+// accumulated so far, updated by collect with Welford's algorithm.
+#[derive(ShaderType, Clone, Default, Debug)]
+pub struct PixelStatsGPU {
+    mean: Vec3,
+    // Frames that traced this pixel.
+    samples: u32,
+    // Sum of squared differences from the mean, per channel.
+    m2: Vec3,
+    // The accumulated frame the pixel converged on, counting from 1, or 0
+    // while it still needs samples. Generate stops tracing it from the next
+    // frame on.
+    converged_at: u32,
+}
+
+// One stats buffer per RayTraced camera entity, one entry per pixel.
+#[derive(Default)]
+pub struct PixelStatsGPUStorage {
+    pub buffers: HashMap<Entity, StorageBuffer<Vec<PixelStatsGPU>>>,
+}
+
+pub struct RayTraceAccumulationPlugin;
+
+impl Plugin for RayTraceAccumulationPlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<PixelStatsGPUStorage>()
+            .add_system_to_stage(RenderStage::Prepare, prepare);
+    }
+}
+
+fn prepare(
+    views: Res<ExtractedRayTraceViews>,
+    mut stats: ResMut<PixelStatsGPUStorage>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    stats.buffers.retain(|entity, _| views.contains(*entity));
+
+    // Only allocated here. Collect resets the stats itself on the first
+    // accumulated frame, which a resize always is.
+    for view in views.views.iter() {
+        let pixel_count = view.pixel_count() as usize;
+        let buffer = stats.buffers.entry(view.entity).or_default();
+
+        if buffer.get().len() != pixel_count {
+            buffer.get_mut().clear();
+            buffer
+                .get_mut()
+                .append(&mut vec![PixelStatsGPU::default(); pixel_count]);
+
+            buffer.write_buffer(&render_device, &render_queue);
+
+            println!(
+                "Pixel Stats Buffer {:?}: {:?} {:?}",
+                view.entity,
+                pixel_count,
+                buffer.get().size()
+            );
+        }
+    }
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    }
+}
@@ -1,15 +1,21 @@
 use bevy::{
     prelude::*,
     render::{
+        camera::Projection,
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
-        RenderApp, RenderStage,
+        MainWorld, RenderApp, RenderStage,
     },
+    utils::HashMap,
 };
 
-use crate::camera::RayTraceCamera;
+use crate::camera::{FovAxis, RayTraceProjection, RayTraced};
+use crate::ray_trace_debug::DebugView;
+use crate::SAMPLES_PER_RAY;
 
-const CAMERA_FOV: f32 = 1.5708;
+// Primary rays for each projection, imported by generate and the megakernel
+// as bevy_raytrace::camera_rays.
+pub const CAMERA_RAYS_SHADER: &str = "shaders/camera_rays.wgsl";
 
 #[derive(Copy, Clone, Debug, ShaderType)]
 pub struct CameraGPU {
@@ -17,54 +23,236 @@ pub struct CameraGPU {
     pub forward: Vec3,
     pub fov: f32,
     pub up: Vec3,
-    pub image_plane_distance: f32,
+    pub focus_distance: f32,
     pub right: Vec3,
-    pub lens_focal_length: f32,
+    pub aperture_radius: f32,
     pub position: Vec3,
-    pub fstop: f32,
+    pub projection: u32,
+    pub fov_axis: u32,
+    pub orthographic_height: f32,
+    pub pixel_filter: u32,
+    pub sampler_kind: u32,
+    pub max_bounces: u32,
+    pub roulette_depth: u32,
+    // 0 when adaptive sampling is off.
+    pub adaptive_threshold: f32,
+    pub min_samples: u32,
+    // 0 when clamping is off.
+    pub max_radiance: f32,
+    pub regularize: u32,
+    pub reject_outliers: u32,
 }
 
+// One uniform buffer per RayTraced camera entity.
 #[derive(Default)]
 pub struct CameraGPUStorage {
-    pub buffer: DynamicUniformBuffer<CameraGPU>,
+    pub buffers: HashMap<Entity, UniformBuffer<CameraGPU>>,
 }
 
+// A RayTraced camera pulled from the main world.
+#[derive(Clone)]
+pub struct RayTraceView {
+    // The camera entity, which keeps its id in the render world.
+    pub entity: Entity,
+    // Physical size of the camera's viewport, or of its whole render target
+    // if it has none. The view's image and buffers are this size.
+    pub size: UVec2,
+    pub transform: Transform,
+    // From the camera's PerspectiveProjection.
+    pub fov: f32,
+    pub settings: RayTraced,
+    pub debug_view: DebugView,
+    // Frames already averaged into the view's image, 0 when it starts over.
+    pub accumulated_frames: u32,
+}
+
+impl RayTraceView {
+    fn same_image(&self, other: &RayTraceView) -> bool {
+        self.size == other.size
+            && self.transform == other.transform
+            && self.fov == other.fov
+            && self.settings == other.settings
+            && self.debug_view.accumulated() == other.debug_view.accumulated()
+    }
+
+    pub fn pixel_count(&self) -> u32 {
+        self.size.x * self.size.y
+    }
+
+    // Every sample of every pixel gets a slot in the view's ray buffers.
+    pub fn ray_count(&self) -> u32 {
+        self.pixel_count() * SAMPLES_PER_RAY as u32
+    }
+
+    // Debug views of the first hit stop every path there.
+    pub fn max_bounces(&self) -> u32 {
+        if self.debug_view.first_hit() {
+            0
+        } else {
+            self.settings.max_bounces
+        }
+    }
+}
+
+// The views the ray tracer renders this frame, one per active RayTraced
+// camera. Every per-view buffer is keyed by the view's entity, and dropped
+// once its camera is gone.
+#[derive(Default)]
+pub struct ExtractedRayTraceViews {
+    pub views: Vec<RayTraceView>,
+}
+
+impl ExtractedRayTraceViews {
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.views.iter().any(|view| view.entity == entity)
+    }
+}
+
+// Keeps the camera_rays module loaded, as SamplerShader does the sampler.
+pub struct CameraRaysShader(pub Handle<Shader>);
+
 pub struct RayTraceCameraPlugin;
 
 impl Plugin for RayTraceCameraPlugin {
     fn build(&self, app: &mut App) {
+        let shader = app.world.resource::<AssetServer>().load(CAMERA_RAYS_SHADER);
+        app.insert_resource(CameraRaysShader(shader));
+
         let render_app = app.sub_app_mut(RenderApp);
         render_app
+            .init_resource::<ExtractedRayTraceViews>()
             .init_resource::<CameraGPUStorage>()
+            .add_system_to_stage(RenderStage::Extract, extract)
             .add_system_to_stage(RenderStage::Prepare, prepare);
     }
 }
 
+fn extract(
+    mut world: ResMut<MainWorld>,
+    mut extracted: ResMut<ExtractedRayTraceViews>,
+    mut history: Local<HashMap<Entity, RayTraceView>>,
+) {
+    let mut query = world.query_filtered::<
+        (Entity, &Camera, &GlobalTransform, &Projection, &RayTraced),
+        With<Camera3d>,
+    >();
+
+    extracted.views.clear();
+
+    let debug_view = *world.resource::<DebugView>();
+
+    for (entity, camera, transform, projection, settings) in query.iter(&world) {
+        if !camera.is_active {
+            continue;
+        }
+
+        // Unknown until the render target exists, and zero while a window is
+        // minimized.
+        let size = match camera.physical_viewport_size() {
+            Some(size) if size.x > 0 && size.y > 0 => size,
+            _ => continue,
+        };
+
+        let mut view = RayTraceView {
+            entity,
+            size,
+            transform: Transform::from(*transform),
+            fov: match projection {
+                Projection::Perspective(perspective) => perspective.fov,
+                // Only the perspective fov is used, fall back to Bevy's default.
+                Projection::Orthographic(_) => PerspectiveProjection::default().fov,
+            },
+            settings: settings.clone(),
+            debug_view,
+            accumulated_frames: 0,
+        };
+
+        // Keep accumulating only while nothing about the view has changed.
+        if let Some(last) = history.get(&entity) {
+            if view.settings.accumulate && view.same_image(last) {
+                view.accumulated_frames = last.accumulated_frames + 1;
+            }
+        }
+
+        extracted.views.push(view);
+    }
+
+    history.clear();
+    history.extend(
+        extracted
+            .views
+            .iter()
+            .map(|view| (view.entity, view.clone())),
+    );
+}
+
 fn prepare(
-    camera: Res<RayTraceCamera>,
+    extracted: Res<ExtractedRayTraceViews>,
     mut camera_gpu: ResMut<CameraGPUStorage>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
-    camera_gpu.buffer.clear();
+    camera_gpu
+        .buffers
+        .retain(|entity, _| extracted.contains(*entity));
 
-    let transform = camera.transform;
+    for view in extracted.views.iter() {
+        let gpu = camera_gpu_for(view);
 
-    camera_gpu.buffer.push(CameraGPU {
+        let buffer = camera_gpu
+            .buffers
+            .entry(view.entity)
+            .or_insert_with(|| UniformBuffer::from(gpu));
+        buffer.set(gpu);
+        buffer.write_buffer(&render_device, &render_queue);
+    }
+}
+
+fn camera_gpu_for(view: &RayTraceView) -> CameraGPU {
+    let camera = &view.settings;
+    let transform = view.transform;
+
+    // Fields a projection doesn't use are left at zero.
+    let (fov, fov_axis, orthographic_height) = match camera.projection {
+        RayTraceProjection::Perspective { axis } => (view.fov, axis, 0.0),
+        RayTraceProjection::Orthographic { height } => (0.0, FovAxis::Horizontal, height),
+        RayTraceProjection::Equirectangular => (0.0, FovAxis::Horizontal, 0.0),
+        RayTraceProjection::Fisheye { fov } => (fov, FovAxis::Horizontal, 0.0),
+    };
+
+    CameraGPU {
         transform: transform.compute_matrix(),
         forward: transform.forward(),
         up: transform.up(),
         right: transform.right(),
         position: transform.translation,
-        fov: CAMERA_FOV,
-        image_plane_distance: 10.0,
-        lens_focal_length: 0.1, // millimeters
-        fstop: 1.0 / 32.0,
-    });
-
-    camera_gpu
-        .buffer
-        .write_buffer(&render_device, &render_queue);
+        fov,
+        focus_distance: camera.focus_distance,
+        aperture_radius: camera.aperture_radius(),
+        projection: camera.projection.id(),
+        fov_axis: match fov_axis {
+            FovAxis::Horizontal => 0,
+            FovAxis::Vertical => 1,
+        },
+        orthographic_height,
+        pixel_filter: camera.filter.id(),
+        sampler_kind: camera.sampler.id(),
+        max_bounces: view.max_bounces(),
+        roulette_depth: camera.roulette_depth,
+        adaptive_threshold: if camera.adaptive.enabled {
+            camera.adaptive.threshold
+        } else {
+            0.0
+        },
+        min_samples: camera.adaptive.min_samples,
+        max_radiance: if camera.fireflies.clamp {
+            camera.fireflies.max_radiance
+        } else {
+            0.0
+        },
+        regularize: camera.fireflies.regularize as u32,
+        reject_outliers: camera.fireflies.reject_outliers as u32,
+    }
 }
 
 pub fn describe(binding: u32) -> BindGroupLayoutEntry {
@@ -0,0 +1,99 @@
+use bevy::{
+    prelude::*,
+    render::extract_resource::{ExtractResource, ExtractResourcePlugin},
+};
+
+// What the output shows instead of the rendered image, to check the data a
+// frame is built from. Cycled with G.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ExtractResource)]
+pub enum DebugView {
+    None,
+    // How many frames traced each pixel, relative to the frames accumulated:
+    // red for every frame, blue for none.
+    SampleCount,
+    // The views below are written per sample by the shade_debug pass, then
+    // filtered and accumulated like the image.
+    Normals,
+    // Distance along the camera's forward axis.
+    Depth,
+    // Base color with textures applied.
+    Albedo,
+    // Material index hashed to a color.
+    MaterialId,
+    Uv,
+    // Heatmap of how many times each path bounced.
+    Bounces,
+}
+
+impl Default for DebugView {
+    fn default() -> Self {
+        DebugView::None
+    }
+}
+
+impl DebugView {
+    // Matches the DEBUG_* constants in the shaders.
+    pub fn id(self) -> u32 {
+        match self {
+            DebugView::None => 0,
+            DebugView::SampleCount => 1,
+            DebugView::Normals => 2,
+            DebugView::Depth => 3,
+            DebugView::Albedo => 4,
+            DebugView::MaterialId => 5,
+            DebugView::Uv => 6,
+            DebugView::Bounces => 7,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            DebugView::None => DebugView::SampleCount,
+            DebugView::SampleCount => DebugView::Normals,
+            DebugView::Normals => DebugView::Depth,
+            DebugView::Depth => DebugView::Albedo,
+            DebugView::Albedo => DebugView::MaterialId,
+            DebugView::MaterialId => DebugView::Uv,
+            DebugView::Uv => DebugView::Bounces,
+            DebugView::Bounces => DebugView::None,
+        }
+    }
+
+    // Views that only need the first hit, so paths stop there.
+    pub fn first_hit(self) -> bool {
+        matches!(
+            self,
+            DebugView::Normals
+                | DebugView::Depth
+                | DebugView::Albedo
+                | DebugView::MaterialId
+                | DebugView::Uv
+        )
+    }
+
+    // The values accumulated across frames. Sample counts are shown from
+    // the image's own statistics, so switching to them keeps accumulating.
+    pub fn accumulated(self) -> Self {
+        match self {
+            DebugView::SampleCount => DebugView::None,
+            view => view,
+        }
+    }
+}
+
+pub struct RayTraceDebugPlugin;
+
+impl Plugin for RayTraceDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DebugView>()
+            .add_plugin(ExtractResourcePlugin::<DebugView>::default())
+            .add_system(cycle_debug_view);
+    }
+}
+
+fn cycle_debug_view(keys: Res<Input<KeyCode>>, mut view: ResMut<DebugView>) {
+    if keys.just_pressed(KeyCode::G) {
+        *view = view.next();
+        info!("Debug view: {:?}", *view);
+    }
+}
@@ -0,0 +1,167 @@
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics, FrameTimeDiagnosticsPlugin},
+    prelude::*,
+    render::RenderApp,
+};
+use std::sync::{Arc, Mutex};
+
+use crate::ray_trace_stats::RayStatsEnabled;
+
+const OVERLAY_FONT: &str = "fonts/DejaVuSansMono.ttf";
+const OVERLAY_FONT_SIZE: f32 = 16.0;
+const HISTORY_LENGTH: usize = 20;
+
+// Ids are the name's hash under this prefix, since measurements are named
+// in the render world and only known once the first one arrives.
+const DIAGNOSTIC_ID_PREFIX: u128 = 0x7a3c_91e4_52d8_4b06 << 64;
+
+// A value read back from the GPU.
+#[derive(Clone, Debug)]
+pub struct Measurement {
+    pub name: String,
+    pub suffix: &'static str,
+    pub value: f64,
+}
+
+// Measurements read back in the render world, waiting to be added to the
+// main world's Diagnostics. Both worlds hold the same list.
+#[derive(Clone, Default)]
+pub struct RayTraceMeasurements(Arc<Mutex<Vec<Measurement>>>);
+
+impl RayTraceMeasurements {
+    pub fn push(&self, name: String, suffix: &'static str, value: f64) {
+        self.0.lock().unwrap().push(Measurement {
+            name,
+            suffix,
+            value,
+        });
+    }
+
+    fn take(&self) -> Vec<Measurement> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+// The ray tracer's diagnostics, in the order they were first measured.
+#[derive(Default)]
+pub struct RayTraceDiagnostics {
+    pub ids: Vec<DiagnosticId>,
+}
+
+#[derive(Component)]
+struct DiagnosticsOverlay;
+
+pub struct RayTraceDiagnosticsPlugin;
+
+impl Plugin for RayTraceDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        let measurements = RayTraceMeasurements::default();
+
+        app.insert_resource(measurements.clone())
+            .init_resource::<RayTraceDiagnostics>()
+            .add_startup_system(spawn_overlay)
+            .add_system(record_measurements)
+            .add_system(toggle_overlay)
+            .add_system(update_overlay.after(record_measurements));
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.insert_resource(measurements);
+    }
+}
+
+fn diagnostic_id(name: &str) -> DiagnosticId {
+    // FNV-1a, stable across runs unlike the std hasher.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in name.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    DiagnosticId::from_u128(DIAGNOSTIC_ID_PREFIX | hash as u128)
+}
+
+fn record_measurements(
+    measurements: Res<RayTraceMeasurements>,
+    mut ray_trace: ResMut<RayTraceDiagnostics>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    for measurement in measurements.take() {
+        let id = diagnostic_id(&measurement.name);
+
+        if diagnostics.get(id).is_none() {
+            diagnostics.add(
+                Diagnostic::new(id, measurement.name, HISTORY_LENGTH)
+                    .with_suffix(measurement.suffix),
+            );
+            ray_trace.ids.push(id);
+        }
+
+        diagnostics.add_measurement(id, || measurement.value);
+    }
+}
+
+fn spawn_overlay(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(OVERLAY_FONT),
+                    font_size: OVERLAY_FONT_SIZE,
+                    color: Color::WHITE,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(8.0),
+                    left: Val::Px(8.0),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(DiagnosticsOverlay);
+}
+
+// Shows or hides the overlay with O. Rays are only counted while it's shown.
+fn toggle_overlay(
+    keys: Res<Input<KeyCode>>,
+    mut overlay: Query<&mut Visibility, With<DiagnosticsOverlay>>,
+    mut stats: ResMut<RayStatsEnabled>,
+) {
+    if keys.just_pressed(KeyCode::O) {
+        for mut visibility in overlay.iter_mut() {
+            visibility.is_visible = !visibility.is_visible;
+            stats.0 = visibility.is_visible;
+            info!("Diagnostics overlay: {}", visibility.is_visible);
+        }
+    }
+}
+
+fn update_overlay(
+    diagnostics: Res<Diagnostics>,
+    ray_trace: Res<RayTraceDiagnostics>,
+    mut overlay: Query<(&mut Text, &Visibility), With<DiagnosticsOverlay>>,
+) {
+    for (mut text, visibility) in overlay.iter_mut() {
+        if !visibility.is_visible {
+            continue;
+        }
+
+        let ids =
+            std::iter::once(FrameTimeDiagnosticsPlugin::FPS).chain(ray_trace.ids.iter().copied());
+
+        let mut lines = String::new();
+        for diagnostic in ids.filter_map(|id| diagnostics.get(id)) {
+            if let Some(average) = diagnostic.average() {
+                lines.push_str(&format!(
+                    "{:<24}{:>10.3}{}\n",
+                    diagnostic.name, average, diagnostic.suffix
+                ));
+            }
+        }
+
+        text.sections[0].value = lines;
+    }
+}
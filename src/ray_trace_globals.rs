@@ -1,4 +1,6 @@
-use crate::{RENDER_TARGET_SIZE, SAMPLES_PER_RAY};
+use crate::ray_trace_camera::{ExtractedRayTraceViews, RayTraceView};
+use crate::ray_trace_queue::MATERIAL_QUEUE_COUNT;
+use crate::SAMPLES_PER_RAY;
 use bevy::{
     prelude::*,
     render::{
@@ -6,6 +8,7 @@ use bevy::{
         renderer::{RenderDevice, RenderQueue},
         RenderApp, RenderStage,
     },
+    utils::HashMap,
 };
 
 #[derive(ShaderType, Clone, Default, Debug)]
@@ -14,31 +17,49 @@ pub struct GlobalsGPU {
     pub render_width: u32,
     pub render_height: u32,
     pub samples_per_ray: u32,
+    // Frames already averaged into the view's image, 0 when it starts over.
+    pub accumulated_frames: u32,
+    pub debug_view: u32,
 
     // Atomics
     pub clear_index: u32,
     pub generate_index: u32,
     pub intersect_index: u32,
-    pub shade_index: u32,
     pub collect_index: u32,
+    pub compact_index: u32,
+    pub queue_count: u32,
+
+    // Number of entries in the ray queue, copied from queue_count by the dispatch pass.
+    pub alive_count: u32,
+
+    // Atomics, one per material queue: how many hits intersect binned into the
+    // queue, and how far the queue's shade kernel has got through them.
+    pub material_counts: [u32; MATERIAL_QUEUE_COUNT],
+    pub shade_indices: [u32; MATERIAL_QUEUE_COUNT],
 }
 
 impl GlobalsGPU {
-    fn reset(&mut self) {
-        self.render_width = RENDER_TARGET_SIZE.0;
-        self.render_height = RENDER_TARGET_SIZE.1;
+    fn reset(&mut self, view: &RayTraceView) {
+        self.render_width = view.size.x;
+        self.render_height = view.size.y;
         self.samples_per_ray = SAMPLES_PER_RAY as u32;
         self.clear_index = 0;
         self.generate_index = 0;
         self.intersect_index = 0;
-        self.shade_index = 0;
         self.collect_index = 0;
+        self.compact_index = 0;
+        self.queue_count = 0;
+        self.alive_count = 0;
+        self.material_counts = [0; MATERIAL_QUEUE_COUNT];
+        self.shade_indices = [0; MATERIAL_QUEUE_COUNT];
     }
 }
 
+// Views are traced one after another, so each needs its own counters reset
+// at the start of the frame. Keyed by RayTraced camera entity.
 #[derive(Default)]
 pub struct GlobalsGPUStorage {
-    pub buffer: StorageBuffer<GlobalsGPU>,
+    pub buffers: HashMap<Entity, StorageBuffer<GlobalsGPU>>,
 }
 
 pub struct RayTraceGlobalsPlugin;
@@ -47,22 +68,29 @@ impl Plugin for RayTraceGlobalsPlugin {
     fn build(&self, app: &mut App) {
         let render_app = app.sub_app_mut(RenderApp);
         render_app
-            .init_resource::<GlobalsGPU>()
             .init_resource::<GlobalsGPUStorage>()
             .add_system_to_stage(RenderStage::Prepare, prepare);
     }
 }
 
 fn prepare(
+    views: Res<ExtractedRayTraceViews>,
     mut globals: ResMut<GlobalsGPUStorage>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
     mut frame: Local<u32>,
 ) {
-    globals.buffer.get_mut().reset();
-    globals.buffer.get_mut().frame = *frame;
+    globals.buffers.retain(|entity, _| views.contains(*entity));
 
-    globals.buffer.write_buffer(&render_device, &render_queue);
+    for view in views.views.iter() {
+        let buffer = globals.buffers.entry(view.entity).or_default();
+        buffer.get_mut().reset(view);
+        buffer.get_mut().frame = *frame;
+        buffer.get_mut().accumulated_frames = view.accumulated_frames;
+        buffer.get_mut().debug_view = view.debug_view.id();
+
+        buffer.write_buffer(&render_device, &render_queue);
+    }
 
     *frame += 1;
 }
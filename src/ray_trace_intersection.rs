@@ -1,4 +1,4 @@
-use crate::{RENDER_TARGET_SIZE, SAMPLES_PER_RAY};
+use crate::ray_trace_camera::ExtractedRayTraceViews;
 use bevy::{
     prelude::*,
     render::{
@@ -6,27 +6,46 @@ use bevy::{
         renderer::{RenderDevice, RenderQueue},
         RenderApp, RenderStage,
     },
+    utils::HashMap,
 };
 
+// Closest hits against the scene, imported by intersect and the megakernel as
+// bevy_raytrace::intersection.
+pub const INTERSECTION_SHADER: &str = "shaders/intersection.wgsl";
+
 #[derive(ShaderType, Clone, Default, Debug)]
 pub struct IntersectionGPU {
+    // The light the path has brought back to the camera so far.
     color: Vec4,
     point: Vec3,
     t: f32,
     normal: Vec3,
     material: u32,
     front_face: u32,
+    uv: Vec2,
+    // World space length covered by one unit of uv, for texture lod.
+    uv_density: f32,
 }
 
+// One intersection buffer per RayTraced camera entity.
 #[derive(Default)]
 pub struct IntersectionGPUStorage {
-    pub buffer: StorageBuffer<Vec<IntersectionGPU>>,
+    pub buffers: HashMap<Entity, StorageBuffer<Vec<IntersectionGPU>>>,
 }
 
+// Keeps the intersection module loaded, as SamplerShader does the sampler.
+pub struct IntersectionShader(pub Handle<Shader>);
+
 pub struct RayTraceIntersectionsPlugin;
 
 impl Plugin for RayTraceIntersectionsPlugin {
     fn build(&self, app: &mut App) {
+        let shader = app
+            .world
+            .resource::<AssetServer>()
+            .load(INTERSECTION_SHADER);
+        app.insert_resource(IntersectionShader(shader));
+
         let render_app = app.sub_app_mut(RenderApp);
         render_app
             .init_resource::<IntersectionGPUStorage>()
@@ -35,29 +54,35 @@ impl Plugin for RayTraceIntersectionsPlugin {
 }
 
 fn prepare(
+    views: Res<ExtractedRayTraceViews>,
     mut intersections: ResMut<IntersectionGPUStorage>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
-    // Allocate as many intersections as we have rays.
-    let ray_count = (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1) as usize * SAMPLES_PER_RAY;
+    intersections
+        .buffers
+        .retain(|entity, _| views.contains(*entity));
+
+    for view in views.views.iter() {
+        // Allocate as many intersections as we have rays.
+        let ray_count = view.ray_count() as usize;
+        let buffer = intersections.buffers.entry(view.entity).or_default();
 
-    if intersections.buffer.get().len() != ray_count {
-        intersections.buffer.get_mut().clear();
-        intersections
-            .buffer
-            .get_mut()
-            .append(&mut vec![IntersectionGPU::default(); ray_count]);
+        if buffer.get().len() != ray_count {
+            buffer.get_mut().clear();
+            buffer
+                .get_mut()
+                .append(&mut vec![IntersectionGPU::default(); ray_count]);
 
-        intersections
-            .buffer
-            .write_buffer(&render_device, &render_queue);
+            buffer.write_buffer(&render_device, &render_queue);
 
-        println!(
-            "Intersection Buffer: {:?} {:?}",
-            ray_count,
-            intersections.buffer.get().size()
-        );
+            println!(
+                "Intersection Buffer {:?}: {:?} {:?}",
+                view.entity,
+                ray_count,
+                buffer.get().size()
+            );
+        }
     }
 }
 
@@ -1,19 +1,39 @@
 use bevy::{
+    asset::{AssetLoader, AssetPath, HandleId, LoadContext, LoadedAsset},
     prelude::*,
+    reflect::{FromReflect, TypeUuid},
     render::{
-        extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_resource::*,
         renderer::{RenderDevice, RenderQueue},
-        RenderApp, RenderStage,
+        MainWorld, RenderApp, RenderStage,
     },
+    utils::{BoxedFuture, HashMap, HashSet},
 };
-use indexmap::IndexMap;
+use serde::Deserialize;
+use std::{fmt, path::PathBuf};
 
-#[derive(Clone, Debug)]
+use crate::ray_trace_textures::MaterialTextureLayers;
+
+// The material lobes, imported by the shade passes and the megakernel as
+// bevy_raytrace::shading.
+pub const SHADING_SHADER: &str = "shaders/shading.wgsl";
+
+// Bevy 0.8 can't reflect enum variants, so both enums are reflected as opaque
+// values: an inspector can swap the whole value but not edit its fields.
+#[derive(Clone, Debug, PartialEq, Deserialize, Reflect, FromReflect)]
+#[reflect_value(PartialEq)]
 pub enum Reflectance {
     Lambertian,
     Metallic,
     Dielectric,
+    // Emits color and ends the path.
+    Emissive,
+    // Metallic-roughness model matching Bevy's StandardMaterial: a GGX
+    // specular lobe over a Lambertian base.
+    Principled,
+    // Phase function of a participating medium: scatters with the color as
+    // albedo, in a Henyey-Greenstein lobe around the ray.
+    Volume,
 }
 
 impl Default for Reflectance {
@@ -22,12 +42,96 @@ impl Default for Reflectance {
     }
 }
 
-#[derive(Default, Clone, Debug)]
+// Procedural patterns evaluated at the hit position, replacing the material
+// color. Noise based patterns scale the material color.
+#[derive(Clone, Debug, PartialEq, Deserialize, Reflect, FromReflect)]
+#[reflect_value(PartialEq)]
+pub enum ProceduralTexture {
+    None,
+    // Alternates between the material color and `even`.
+    Checker { scale: f32, even: Color },
+    Noise { scale: f32 },
+    Turbulence { scale: f32, depth: u32 },
+    Marble { scale: f32, depth: u32 },
+}
+
+impl Default for ProceduralTexture {
+    fn default() -> Self {
+        ProceduralTexture::None
+    }
+}
+
+// An asset, so entities reference it with a Handle<RayTraceMaterial> and it
+// can be loaded from .rtmat.ron files. Editing it through Assets, or saving
+// the file, re-uploads it. Reflected so inspectors can edit it in place.
+#[derive(Default, Clone, Debug, Deserialize, Reflect, TypeUuid)]
+#[uuid = "6b0f4d6e-2a5c-4c1f-9d51-3f0c7e8a9b12"]
+#[serde(default)]
 pub struct RayTraceMaterial {
     pub color: Color,
     pub reflectance: Reflectance,
     pub fuzziness: f32,
     pub index_of_refraction: f32,
+
+    // Principled only.
+    pub metallic: f32,
+    pub roughness: f32, // perceptual, squared to get GGX alpha
+    pub specular: f32,  // 0.5 is 4% reflectance at normal incidence
+
+    // Optional textures, sampled with the uv of the hit. Given as paths in
+    // the textures section of a .rtmat.ron file.
+    #[serde(skip)]
+    pub base_color_texture: Option<Handle<Image>>,
+    #[serde(skip)]
+    pub metallic_roughness_texture: Option<Handle<Image>>, // roughness in g, metallic in b
+    #[serde(skip)]
+    pub emissive_texture: Option<Handle<Image>>,
+    #[serde(skip)]
+    pub normal_map_texture: Option<Handle<Image>>,
+
+    pub procedural: ProceduralTexture,
+
+    // Dielectric only. Light travelling through the medium is tinted to
+    // `absorption` after 1 / `absorption_density` units of distance.
+    pub absorption: Color,
+    pub absorption_density: f32,
+    // Cauchy's B coefficient in square micrometers, with index_of_refraction
+    // as A. Zero disables dispersion.
+    pub cauchy_b: f32,
+
+    // Volume only. Henyey-Greenstein asymmetry, 0 is isotropic, positive
+    // scatters forward.
+    pub anisotropy: f32,
+}
+
+impl RayTraceMaterial {
+    pub fn textures(&self) -> impl Iterator<Item = &Handle<Image>> {
+        [
+            &self.base_color_texture,
+            &self.metallic_roughness_texture,
+            &self.emissive_texture,
+            &self.normal_map_texture,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+impl From<&StandardMaterial> for RayTraceMaterial {
+    fn from(material: &StandardMaterial) -> Self {
+        RayTraceMaterial {
+            color: material.base_color,
+            reflectance: Reflectance::Principled,
+            metallic: material.metallic,
+            roughness: material.perceptual_roughness,
+            specular: material.reflectance,
+            base_color_texture: material.base_color_texture.clone(),
+            metallic_roughness_texture: material.metallic_roughness_texture.clone(),
+            emissive_texture: material.emissive_texture.clone(),
+            normal_map_texture: material.normal_map_texture.clone(),
+            ..default()
+        }
+    }
 }
 
 #[derive(ShaderType, Clone, Default, Debug)]
@@ -39,7 +143,74 @@ pub struct MaterialGPU {
     // air: 1.0
     // glass: 1.3-1.7
     // diamond: 2.4
-    pad2: i32,
+    metallic: f32,
+    roughness: f32,
+    specular: f32,
+    // Layers in the material texture array, -1 for none.
+    base_color_texture: i32,
+    metallic_roughness_texture: i32,
+    emissive_texture: i32,
+    normal_map_texture: i32,
+    procedural: i32,
+    procedural_scale: f32,
+    procedural_color: Vec4,
+    procedural_depth: u32,
+    absorption_density: f32,
+    cauchy_b: f32,
+    anisotropy: f32,
+    absorption: Vec4,
+}
+
+impl MaterialGPU {
+    fn new(mat: &RayTraceMaterial, layers: &MaterialTextureLayers) -> Self {
+        let (procedural, procedural_scale, procedural_color, procedural_depth) =
+            match mat.procedural {
+                ProceduralTexture::None => (0, 1.0, Color::WHITE, 0),
+                ProceduralTexture::Checker { scale, even } => (1, scale, even, 0),
+                ProceduralTexture::Noise { scale } => (2, scale, Color::WHITE, 0),
+                ProceduralTexture::Turbulence { scale, depth } => (3, scale, Color::WHITE, depth),
+                ProceduralTexture::Marble { scale, depth } => (4, scale, Color::WHITE, depth),
+            };
+
+        MaterialGPU {
+            reflectance: match mat.reflectance {
+                Reflectance::Lambertian => 0,
+                Reflectance::Metallic => 1,
+                Reflectance::Dielectric => 2,
+                Reflectance::Emissive => 3,
+                Reflectance::Principled => 4,
+                Reflectance::Volume => 5,
+            },
+            color: Vec4::new(mat.color.r(), mat.color.g(), mat.color.b(), mat.color.a()),
+            fuzziness: mat.fuzziness,
+            index_of_refraction: mat.index_of_refraction,
+            metallic: mat.metallic,
+            roughness: mat.roughness,
+            specular: mat.specular,
+            base_color_texture: layers.layer_of(&mat.base_color_texture),
+            metallic_roughness_texture: layers.layer_of(&mat.metallic_roughness_texture),
+            emissive_texture: layers.layer_of(&mat.emissive_texture),
+            normal_map_texture: layers.layer_of(&mat.normal_map_texture),
+            procedural,
+            procedural_scale,
+            procedural_color: Vec4::new(
+                procedural_color.r(),
+                procedural_color.g(),
+                procedural_color.b(),
+                procedural_color.a(),
+            ),
+            procedural_depth,
+            absorption_density: mat.absorption_density,
+            cauchy_b: mat.cauchy_b,
+            anisotropy: mat.anisotropy,
+            absorption: Vec4::new(
+                mat.absorption.r(),
+                mat.absorption.g(),
+                mat.absorption.b(),
+                mat.absorption.a(),
+            ),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -47,114 +218,323 @@ pub struct MaterialGPUStorage {
     pub buffer: StorageBuffer<Vec<MaterialGPU>>,
 }
 
-#[derive(Default, Clone, ExtractResource)]
+// Layout of a .rtmat.ron file. Textures are asset paths, loaded alongside.
+#[derive(Deserialize)]
+struct RayTraceMaterialFile {
+    material: RayTraceMaterial,
+    #[serde(default)]
+    textures: MaterialTexturePaths,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct MaterialTexturePaths {
+    base_color: Option<String>,
+    metallic_roughness: Option<String>,
+    emissive: Option<String>,
+    normal_map: Option<String>,
+}
+
+#[derive(Default)]
+pub struct RayTraceMaterialLoader;
+
+impl AssetLoader for RayTraceMaterialLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let file: RayTraceMaterialFile = ron::de::from_bytes(bytes)?;
+            let mut material = file.material;
+            let mut dependencies = Vec::new();
+
+            let mut texture = |path: Option<String>| {
+                path.map(|path| {
+                    let path = AssetPath::from(PathBuf::from(path));
+                    dependencies.push(path.clone());
+                    load_context.get_handle(path)
+                })
+            };
+            material.base_color_texture = texture(file.textures.base_color);
+            material.metallic_roughness_texture = texture(file.textures.metallic_roughness);
+            material.emissive_texture = texture(file.textures.emissive);
+            material.normal_map_texture = texture(file.textures.normal_map);
+
+            load_context
+                .set_default_asset(LoadedAsset::new(material).with_dependencies(dependencies));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["rtmat.ron"]
+    }
+}
+
+// A material's slot in the GPU material buffer, which is what SphereGPU and
+// the media store the index of. Slots are reused once their asset is
+// removed, so the generation tells an id for the old material from one for
+// the new.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialId {
+    index: u32,
+    generation: u32,
+}
+
+impl MaterialId {
+    // Always holds the missing material, for handles that have not loaded
+    // yet or whose asset has been removed.
+    pub const MISSING: MaterialId = MaterialId {
+        index: 0,
+        generation: 0,
+    };
+
+    pub fn index(self) -> u32 {
+        self.index
+    }
+}
+
+#[derive(Debug)]
+pub enum MaterialError {
+    NotLoaded(HandleId),
+    // The id's slot was freed, and may hold another material since.
+    Stale(MaterialId),
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaterialError::NotLoaded(handle) => {
+                write!(f, "material {:?} is not loaded", handle)
+            }
+            MaterialError::Stale(id) => {
+                write!(f, "material slot {} was freed", id.index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MaterialError {}
+
+// Materials created, edited or removed since the render world last looked,
+// gathered from asset events in the main world.
+#[derive(Default)]
+struct ChangedMaterials {
+    modified: HashSet<HandleId>,
+    removed: HashSet<HandleId>,
+}
+
+#[derive(Default)]
+struct MaterialSlot {
+    // None while the slot is free.
+    material: Option<RayTraceMaterial>,
+    // Bumped each time the slot is freed.
+    generation: u32,
+}
+
+// The render world's slots in the GPU material buffer, one per loaded asset.
 pub struct MaterialCache {
-    pub materials: IndexMap<String, RayTraceMaterial>,
+    // Indexed by MaterialId::index.
+    slots: Vec<MaterialSlot>,
+    ids: HashMap<HandleId, MaterialId>,
+    free: Vec<u32>,
+    // Slots that need uploading.
+    changed: HashSet<u32>,
+}
+
+impl Default for MaterialCache {
+    fn default() -> Self {
+        MaterialCache {
+            slots: vec![MaterialSlot {
+                material: Some(missing_material()),
+                generation: 0,
+            }],
+            ids: HashMap::default(),
+            free: Vec::new(),
+            changed: [MaterialId::MISSING.index].into_iter().collect(),
+        }
+    }
 }
 
 impl MaterialCache {
-    pub fn get(&self, key: &str) -> RayTraceMaterial {
-        self.materials.get(key).unwrap().clone()
+    // Add or replace the material for an asset, keeping its slot if it has one.
+    fn insert(&mut self, handle: HandleId, material: RayTraceMaterial) -> MaterialId {
+        let id = match self.ids.get(&handle) {
+            Some(id) => *id,
+            None => {
+                let index = self.free.pop().unwrap_or_else(|| {
+                    self.slots.push(MaterialSlot::default());
+                    self.slots.len() as u32 - 1
+                });
+                let id = MaterialId {
+                    index,
+                    generation: self.slots[index as usize].generation,
+                };
+                self.ids.insert(handle, id);
+                id
+            }
+        };
+
+        self.slots[id.index as usize].material = Some(material);
+        self.changed.insert(id.index);
+        id
+    }
+
+    // The slot goes on the free list for the next insert, and ids handed out
+    // for it go stale. Anything still pointing at it renders as the missing
+    // material until the slot is reused.
+    fn remove(&mut self, handle: HandleId) -> Result<RayTraceMaterial, MaterialError> {
+        let id = self
+            .ids
+            .remove(&handle)
+            .ok_or(MaterialError::NotLoaded(handle))?;
+
+        let slot = &mut self.slots[id.index as usize];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        self.changed.insert(id.index);
+        Ok(slot.material.take().unwrap())
     }
 
-    pub fn get_index_of(&self, key: &str) -> u32 {
-        self.materials.get_index_of(key).unwrap() as u32
+    pub fn id(&self, handle: HandleId) -> Result<MaterialId, MaterialError> {
+        self.ids
+            .get(&handle)
+            .copied()
+            .ok_or(MaterialError::NotLoaded(handle))
     }
 
-    pub fn len(&self) -> usize {
-        self.materials.len()
+    // The material an id was handed out for, if its slot hasn't been freed
+    // since.
+    pub fn get(&self, id: MaterialId) -> Result<&RayTraceMaterial, MaterialError> {
+        let slot = &self.slots[id.index as usize];
+        match &slot.material {
+            Some(material) if slot.generation == id.generation => Ok(material),
+            _ => Err(MaterialError::Stale(id)),
+        }
+    }
+
+    // The index to give the GPU for a handle, falling back to the missing
+    // material. Objects look their material up by handle every frame, so
+    // they never keep an index into a slot that has been reused.
+    pub fn index_of(&self, handle: HandleId) -> u32 {
+        match self.id(handle) {
+            Ok(id) if self.get(id).is_ok() => id.index(),
+            _ => MaterialId::MISSING.index(),
+        }
     }
 }
 
+// Keeps the shading module loaded, as SamplerShader does the sampler.
+pub struct ShadingShader(pub Handle<Shader>);
+
 pub struct RayTraceMaterialsPlugin;
 
 impl Plugin for RayTraceMaterialsPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(init_materials_cache())
-            .add_plugin(ExtractResourcePlugin::<MaterialCache>::default());
+        let shader = app.world.resource::<AssetServer>().load(SHADING_SHADER);
+        app.insert_resource(ShadingShader(shader));
+
+        app.add_asset::<RayTraceMaterial>()
+            .register_type::<RayTraceMaterial>()
+            .register_type::<Reflectance>()
+            .register_type::<ProceduralTexture>()
+            .init_asset_loader::<RayTraceMaterialLoader>()
+            .init_resource::<ChangedMaterials>()
+            .add_system(track_changes);
 
         let render_app = app.sub_app_mut(RenderApp);
         render_app
+            .init_resource::<MaterialCache>()
             .init_resource::<MaterialGPUStorage>()
+            .add_system_to_stage(RenderStage::Extract, extract)
             .add_system_to_stage(RenderStage::Prepare, prepare);
     }
 }
 
-fn init_materials_cache() -> MaterialCache {
-    let mut cache = MaterialCache::default();
-
-    cache.materials.insert(
-        "ground".to_string(),
-        RayTraceMaterial {
-            reflectance: Reflectance::Lambertian,
-            color: Color::rgba(0.5, 0.5, 0.5, 1.0),
-            fuzziness: 1.0,
-            index_of_refraction: 0.0,
-        },
-    );
+fn track_changes(
+    mut events: EventReader<AssetEvent<RayTraceMaterial>>,
+    mut changed: ResMut<ChangedMaterials>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed.removed.remove(&handle.id);
+                changed.modified.insert(handle.id);
+            }
+            AssetEvent::Removed { handle } => {
+                changed.modified.remove(&handle.id);
+                changed.removed.insert(handle.id);
+            }
+        }
+    }
+}
 
-    cache.materials.insert(
-        "center".to_string(),
-        RayTraceMaterial {
-            reflectance: Reflectance::Lambertian,
-            color: Color::rgba(0.7, 0.3, 0.3, 1.0),
-            fuzziness: 1.0,
-            index_of_refraction: 0.0,
-        },
-    );
+fn extract(mut main_world: ResMut<MainWorld>, mut cache: ResMut<MaterialCache>) {
+    main_world.resource_scope(|world, mut changed: Mut<ChangedMaterials>| {
+        let assets = world.resource::<Assets<RayTraceMaterial>>();
 
-    cache.materials.insert(
-        "left".to_string(),
-        RayTraceMaterial {
-            reflectance: Reflectance::Metallic,
-            color: Color::rgba(0.8, 0.8, 0.8, 1.0),
-            fuzziness: 0.1,
-            index_of_refraction: 1.5,
-        },
-    );
+        for handle in changed.removed.drain() {
+            if let Err(err) = cache.remove(handle) {
+                warn!("Removing material: {}", err);
+            }
+        }
 
-    cache.materials.insert(
-        "right".to_string(),
-        RayTraceMaterial {
-            reflectance: Reflectance::Metallic,
-            color: Color::rgba(0.7, 0.6, 0.5, 1.0),
-            fuzziness: 0.0,
-            index_of_refraction: 1.5,
-        },
-    );
+        for handle in changed.modified.drain() {
+            if let Some(material) = assets.get(&Handle::weak(handle)) {
+                cache.insert(handle, material.clone());
+            }
+        }
+    });
+}
 
-    cache
+// Shown wherever a material is missing.
+fn missing_material() -> RayTraceMaterial {
+    RayTraceMaterial {
+        color: Color::FUCHSIA,
+        ..default()
+    }
 }
 
 fn prepare(
-    cache: Res<MaterialCache>,
+    mut cache: ResMut<MaterialCache>,
+    layers: Res<MaterialTextureLayers>,
     mut materials: ResMut<MaterialGPUStorage>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
-    let material_count = cache.len();
-
-    // At the moment, the cache only grows so this is okay...
-    if materials.buffer.get().len() != material_count {
-        //materials.buffer.get_mut().material_count = material_count as u32;
-        materials.buffer.get_mut().clear();
-
-        for (_, mat) in cache.materials.iter() {
-            materials.buffer.get_mut().push(MaterialGPU {
-                reflectance: match mat.reflectance {
-                    Reflectance::Lambertian => 0,
-                    Reflectance::Metallic => 1,
-                    Reflectance::Dielectric => 2,
-                },
-                color: Vec4::new(mat.color.r(), mat.color.g(), mat.color.b(), mat.color.a()),
-                fuzziness: mat.fuzziness,
-                index_of_refraction: mat.index_of_refraction,
-                pad2: 0,
-            });
-        }
+    let material_count = cache.slots.len();
+    let resized = materials.buffer.get().len() != material_count;
+
+    // Texture layers are handed out as materials reference images, so every
+    // material has to be encoded again when they change.
+    let changed: Vec<u32> = if resized || layers.is_changed() {
+        cache.changed.clear();
+        (0..material_count as u32).collect()
+    } else {
+        cache.changed.drain().collect()
+    };
+
+    if changed.is_empty() {
+        return;
+    }
 
-        materials.buffer.write_buffer(&render_device, &render_queue);
+    materials
+        .buffer
+        .get_mut()
+        .resize(material_count, MaterialGPU::default());
 
+    for index in changed {
+        let gpu = match &cache.slots[index as usize].material {
+            Some(mat) => MaterialGPU::new(mat, &layers),
+            None => MaterialGPU::new(&missing_material(), &layers),
+        };
+        materials.buffer.get_mut()[index as usize] = gpu;
+    }
+
+    materials.buffer.write_buffer(&render_device, &render_queue);
+
+    if resized {
         println!(
             "Materials Buffer: {:?} {:?}",
             material_count,
@@ -175,3 +555,114 @@ pub fn describe(binding: u32) -> BindGroupLayoutEntry {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(color: Color) -> RayTraceMaterial {
+        RayTraceMaterial { color, ..default() }
+    }
+
+    #[test]
+    fn removed_slots_are_reused() {
+        let mut cache = MaterialCache::default();
+        let red = HandleId::random::<RayTraceMaterial>();
+        let blue = HandleId::random::<RayTraceMaterial>();
+
+        let red_id = cache.insert(red, material(Color::RED));
+        assert_ne!(red_id.index(), MaterialId::MISSING.index());
+
+        cache.remove(red).unwrap();
+        assert_eq!(cache.index_of(red), MaterialId::MISSING.index());
+
+        let blue_id = cache.insert(blue, material(Color::BLUE));
+        assert_eq!(blue_id.index(), red_id.index());
+        assert_eq!(cache.slots.len(), 2);
+    }
+
+    #[test]
+    fn reused_slots_invalidate_old_ids() {
+        let mut cache = MaterialCache::default();
+        let red = HandleId::random::<RayTraceMaterial>();
+        let blue = HandleId::random::<RayTraceMaterial>();
+
+        let red_id = cache.insert(red, material(Color::RED));
+        cache.remove(red).unwrap();
+        assert!(matches!(cache.get(red_id), Err(MaterialError::Stale(_))));
+
+        let blue_id = cache.insert(blue, material(Color::BLUE));
+        assert_ne!(blue_id, red_id);
+        assert!(matches!(cache.get(red_id), Err(MaterialError::Stale(_))));
+        assert_eq!(cache.get(blue_id).unwrap().color, Color::BLUE);
+    }
+
+    #[test]
+    fn modified_materials_keep_their_slot() {
+        let mut cache = MaterialCache::default();
+        let handle = HandleId::random::<RayTraceMaterial>();
+
+        let first = cache.insert(handle, material(Color::RED));
+        let second = cache.insert(handle, material(Color::GREEN));
+        assert_eq!(first, second);
+        assert_eq!(cache.get(second).unwrap().color, Color::GREEN);
+    }
+
+    #[test]
+    fn parses_shipped_material_files() {
+        let bytes = include_bytes!("../assets/materials/ground.rtmat.ron");
+        let file: RayTraceMaterialFile = ron::de::from_bytes(bytes).unwrap();
+
+        assert!(matches!(file.material.reflectance, Reflectance::Lambertian));
+        assert!(matches!(
+            file.material.procedural,
+            ProceduralTexture::Checker { scale, .. } if scale == 10.0
+        ));
+        assert!(file.textures.base_color.is_none());
+    }
+
+    #[test]
+    fn parses_textures_and_fills_defaults() {
+        let file: RayTraceMaterialFile = ron::de::from_str(
+            r#"(
+                material: (
+                    reflectance: Principled,
+                    roughness: 0.4,
+                ),
+                textures: (
+                    base_color: Some("textures/brick.png"),
+                    normal_map: Some("textures/brick_normal.png"),
+                ),
+            )"#,
+        )
+        .unwrap();
+
+        assert!(matches!(file.material.reflectance, Reflectance::Principled));
+        assert_eq!(file.material.roughness, 0.4);
+        assert_eq!(file.material.metallic, 0.0);
+        assert!(matches!(file.material.procedural, ProceduralTexture::None));
+        assert_eq!(
+            file.textures.base_color.as_deref(),
+            Some("textures/brick.png")
+        );
+        assert!(file.textures.emissive.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_reflectance() {
+        let file = ron::de::from_str::<RayTraceMaterialFile>("(material: (reflectance: Chrome))");
+        assert!(file.is_err());
+    }
+
+    #[test]
+    fn removing_unknown_handles_fails() {
+        let mut cache = MaterialCache::default();
+        let handle = HandleId::random::<RayTraceMaterial>();
+
+        assert!(matches!(
+            cache.remove(handle),
+            Err(MaterialError::NotLoaded(_))
+        ));
+        assert!(cache.free.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use bevy::{
+    prelude::*,
+    render::extract_resource::{ExtractResource, ExtractResourcePlugin},
+};
+
+// Which pipeline the ray trace node runs. Toggled with M so both can be
+// compared on the same scene.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ExtractResource)]
+pub enum RayTraceMode {
+    // Separate generate, intersect and shade passes per bounce.
+    Wavefront,
+    // One pass that traces a whole path per thread.
+    Megakernel,
+}
+
+impl Default for RayTraceMode {
+    fn default() -> Self {
+        RayTraceMode::Wavefront
+    }
+}
+
+// How a compute thread picks the ray it works on. Toggled with I.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ExtractResource)]
+pub enum RayTraceIndexing {
+    // Threads take the next ray from an atomic counter in the globals.
+    AtomicCounter,
+    // Threads use their global_invocation_id.
+    InvocationId,
+}
+
+impl Default for RayTraceIndexing {
+    fn default() -> Self {
+        RayTraceIndexing::AtomicCounter
+    }
+}
+
+impl RayTraceIndexing {
+    pub fn shader_defs(self) -> Vec<String> {
+        match self {
+            RayTraceIndexing::AtomicCounter => vec![],
+            RayTraceIndexing::InvocationId => vec!["INVOCATION_ID_INDEXING".to_string()],
+        }
+    }
+}
+
+pub struct RayTraceModePlugin;
+
+impl Plugin for RayTraceModePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RayTraceMode>()
+            .init_resource::<RayTraceIndexing>()
+            .add_plugin(ExtractResourcePlugin::<RayTraceMode>::default())
+            .add_plugin(ExtractResourcePlugin::<RayTraceIndexing>::default())
+            .add_system(toggle_mode)
+            .add_system(toggle_indexing);
+    }
+}
+
+fn toggle_mode(keys: Res<Input<KeyCode>>, mut mode: ResMut<RayTraceMode>) {
+    if keys.just_pressed(KeyCode::M) {
+        *mode = match *mode {
+            RayTraceMode::Wavefront => RayTraceMode::Megakernel,
+            RayTraceMode::Megakernel => RayTraceMode::Wavefront,
+        };
+
+        info!("Ray trace mode: {:?}", *mode);
+    }
+}
+
+fn toggle_indexing(keys: Res<Input<KeyCode>>, mut indexing: ResMut<RayTraceIndexing>) {
+    if keys.just_pressed(KeyCode::I) {
+        *indexing = match *indexing {
+            RayTraceIndexing::AtomicCounter => RayTraceIndexing::InvocationId,
+            RayTraceIndexing::InvocationId => RayTraceIndexing::AtomicCounter,
+        };
+
+        info!("Ray trace indexing: {:?}", *indexing);
+    }
+}
@@ -1,9 +1,10 @@
-use crate::plugin::{
-    CameraGlobalsBindGroup, ObjectsMaterialsBindGroup, RaysIntersectionsBindGroup,
-};
-use crate::ray_trace_output::OutputImageBindGroup;
+use crate::ray_trace_camera::{ExtractedRayTraceViews, RayTraceView};
+use crate::ray_trace_mode::RayTraceMode;
+use crate::ray_trace_pass::{RayTraceDispatch, RayTracePass, RayTracePasses, RayTracePhase};
 use crate::ray_trace_pipeline::*;
-use crate::{RENDER_TARGET_SIZE, SAMPLES_PER_RAY};
+use crate::ray_trace_profiling::{RayTraceProfiler, TimedPass};
+use crate::ray_trace_queue::DispatchIndirectStorage;
+use crate::ray_trace_stats::{RayStatsEnabled, RayStatsGPUStorage};
 use bevy::{
     prelude::*,
     render::{
@@ -13,8 +14,6 @@ use bevy::{
     },
 };
 
-const WORKGROUP_SIZE: u32 = 128;
-
 enum RayTraceState {
     Loading,
     Ready,
@@ -33,135 +32,157 @@ impl Default for RayTraceNode {
 }
 
 impl RayTraceNode {
-    fn clear<'a>(&self, world: &'a World, pass: &mut ComputePass<'a>) {
-        let num_dispatch =
-            (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1 * SAMPLES_PER_RAY as u32) / WORKGROUP_SIZE;
-
-        let camera_globals = &world.resource::<CameraGlobalsBindGroup>().0;
-        let rays_intersections = &world.resource::<RaysIntersectionsBindGroup>().0;
-        let output = &world.resource::<OutputImageBindGroup>().0;
-
+    fn dispatch<'a>(
+        &self,
+        world: &'a World,
+        pass: &mut ComputePass<'a>,
+        rt_pass: &RayTracePass,
+        view: &RayTraceView,
+    ) {
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<RayTracePipeline>();
 
-        pass.set_bind_group(0, camera_globals, &[]);
-        pass.set_bind_group(1, rays_intersections, &[]);
-        pass.set_bind_group(2, output, &[]);
+        // Passes registered after the others may not be compiled yet, so
+        // they're left out until they are.
+        let pipeline = match pipeline
+            .get(rt_pass.name)
+            .and_then(|id| pipeline_cache.get_compute_pipeline(id))
+        {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        // steps() leaves out views that aren't prepared, but don't dispatch
+        // with anything missing either way.
+        let bind_groups: Option<Vec<&BindGroup>> = rt_pass
+            .bind_groups
+            .iter()
+            .map(|bind_group| bind_group.get(world, view.entity))
+            .collect();
+        let bind_groups = match bind_groups {
+            Some(bind_groups) => bind_groups,
+            None => return,
+        };
+        let indirect = world
+            .resource::<DispatchIndirectStorage>()
+            .buffers
+            .get(&view.entity);
 
-        let pipeline = pipeline_cache
-            .get_compute_pipeline(pipeline.pipelines.clear)
-            .unwrap();
         pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(num_dispatch, 1, 1);
-    }
-
-    fn prepass<'a>(&self, world: &'a World, pass: &mut ComputePass<'a>) {
-        let camera_globals = &world.resource::<CameraGlobalsBindGroup>().0;
-        let rays_intersections = &world.resource::<RaysIntersectionsBindGroup>().0;
-        let output = &world.resource::<OutputImageBindGroup>().0;
 
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<RayTracePipeline>();
-
-        pass.set_bind_group(0, camera_globals, &[]);
-        pass.set_bind_group(1, rays_intersections, &[]);
-        pass.set_bind_group(2, output, &[]);
+        for (index, bind_group) in bind_groups.into_iter().enumerate() {
+            pass.set_bind_group(index as u32, bind_group, &[]);
+        }
 
-        let pipeline = pipeline_cache
-            .get_compute_pipeline(pipeline.pipelines.prepass)
-            .unwrap();
-        pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(1, 1, 1);
+        match (rt_pass.dispatch, indirect) {
+            (RayTraceDispatch::Indirect(queue), Some(indirect)) => {
+                pass.dispatch_workgroups_indirect(indirect, queue.dispatch_offset());
+            }
+            (RayTraceDispatch::Indirect(_), None) => {}
+            (dispatch, _) => pass.dispatch_workgroups(dispatch.workgroups(view).unwrap(), 1, 1),
+        }
     }
 
-    fn generate<'a>(&self, world: &'a World, pass: &mut ComputePass<'a>) {
-        let num_dispatch =
-            (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1 * SAMPLES_PER_RAY as u32) / WORKGROUP_SIZE;
-
-        let camera_globals = &world.resource::<CameraGlobalsBindGroup>().0;
-        let rays_intersections = &world.resource::<RaysIntersectionsBindGroup>().0;
-
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<RayTracePipeline>();
-
-        pass.set_bind_group(0, camera_globals, &[]);
-        pass.set_bind_group(1, rays_intersections, &[]);
-
-        let pipeline = pipeline_cache
-            .get_compute_pipeline(pipeline.pipelines.generate)
-            .unwrap();
-        pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(num_dispatch, 1, 1);
+    // Whether the view has everything its passes bind. A view added this
+    // frame may be missing some of its buffers or bind groups until the next.
+    fn is_prepared(&self, world: &World, view: Entity) -> bool {
+        world
+            .resource::<DispatchIndirectStorage>()
+            .buffers
+            .contains_key(&view)
+            && world
+                .resource::<RayTracePasses>()
+                .iter()
+                .flat_map(|pass| pass.bind_groups.iter())
+                .all(|bind_group| bind_group.get(world, view).is_some())
     }
 
-    fn intersect<'a>(&self, world: &'a World, pass: &mut ComputePass<'a>) {
-        let num_dispatch =
-            (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1 * SAMPLES_PER_RAY as u32) / WORKGROUP_SIZE;
+    // Every dispatch of the frame, in order. Views are traced one after
+    // another, each with its own buffers and queues.
+    fn steps<'a>(&self, world: &'a World) -> Vec<RayTraceStep<'a>> {
+        let passes = world.resource::<RayTracePasses>();
+        let mode = *world.resource::<RayTraceMode>();
+        let mut steps = Vec::new();
 
-        let camera_globals = &world.resource::<CameraGlobalsBindGroup>().0;
-        let rays_intersections = &world.resource::<RaysIntersectionsBindGroup>().0;
-        let objects_materials = &world.resource::<ObjectsMaterialsBindGroup>().0;
-
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<RayTracePipeline>();
-
-        pass.set_bind_group(0, camera_globals, &[]);
-        pass.set_bind_group(1, rays_intersections, &[]);
-        pass.set_bind_group(2, objects_materials, &[]);
-
-        let pipeline = pipeline_cache
-            .get_compute_pipeline(pipeline.pipelines.intersect)
-            .unwrap();
-        pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(num_dispatch, 1, 1);
-    }
+        for view in world.resource::<ExtractedRayTraceViews>().views.iter() {
+            if !self.is_prepared(world, view.entity) {
+                continue;
+            }
 
-    fn shade<'a>(&self, world: &'a World, pass: &mut ComputePass<'a>) {
-        let num_dispatch =
-            (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1 * SAMPLES_PER_RAY as u32) / WORKGROUP_SIZE;
+            let phase = |phase: RayTracePhase, bounce: Option<u32>| {
+                passes
+                    .phase(phase, mode)
+                    .map(move |pass| RayTraceStep { pass, view, bounce })
+            };
 
-        let camera_globals = &world.resource::<CameraGlobalsBindGroup>().0;
-        let rays_intersections = &world.resource::<RaysIntersectionsBindGroup>().0;
-        let objects_materials = &world.resource::<ObjectsMaterialsBindGroup>().0;
+            steps.extend(phase(RayTracePhase::Setup, None));
 
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<RayTracePipeline>();
+            // Each bounce intersects and shades once, so the last one only
+            // gathers light from where the final scatter went.
+            for bounce in 0..=view.max_bounces() {
+                steps.extend(phase(RayTracePhase::Bounce, Some(bounce)));
+            }
 
-        pass.set_bind_group(0, camera_globals, &[]);
-        pass.set_bind_group(1, rays_intersections, &[]);
-        pass.set_bind_group(2, objects_materials, &[]);
-        // also shadow rays
+            steps.extend(phase(RayTracePhase::Resolve, None));
+        }
 
-        let pipeline = pipeline_cache
-            .get_compute_pipeline(pipeline.pipelines.shade)
-            .unwrap();
-        pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(num_dispatch, 1, 1);
+        steps
     }
 
-    fn collect<'a>(&self, world: &'a World, pass: &mut ComputePass<'a>) {
-        let num_dispatch =
-            (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1 * SAMPLES_PER_RAY as u32) / WORKGROUP_SIZE;
-
-        let camera_globals = &world.resource::<CameraGlobalsBindGroup>().0;
-        let rays_intersections = &world.resource::<RaysIntersectionsBindGroup>().0;
-        let output = &world.resource::<OutputImageBindGroup>().0;
-
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<RayTracePipeline>();
-
-        pass.set_bind_group(0, camera_globals, &[]);
-        pass.set_bind_group(1, rays_intersections, &[]);
-        pass.set_bind_group(2, output, &[]);
+    // Records the steps with a timestamp before the first and after each, if
+    // the profiler takes this frame. Returns the profiled frame's number.
+    fn record_timed<'a>(
+        &self,
+        world: &'a World,
+        encoder: &mut CommandEncoder,
+        steps: &[RayTraceStep<'a>],
+    ) -> Option<u32> {
+        let timed = steps
+            .iter()
+            .map(|step| TimedPass {
+                name: step.pass.name,
+                view: step.view.entity,
+                bounce: step.bounce,
+            })
+            .collect();
+
+        let queries = world.resource::<RayTraceProfiler>().begin_frame(timed)?;
+
+        if queries.inside_passes {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            queries.write_pass_timestamp(&mut pass, 0);
+            for (index, step) in steps.iter().enumerate() {
+                self.dispatch(world, &mut pass, step.pass, step.view);
+                queries.write_pass_timestamp(&mut pass, index as u32 + 1);
+            }
+        } else {
+            // Timestamps can only be written between compute passes, so each
+            // dispatch gets its own. Ending a pass makes the GPU finish its
+            // dispatch before the next one starts, so profiled frames lose
+            // the overlap between dispatches and run a little slower than
+            // unprofiled ones.
+            queries.write_timestamp(encoder, 0);
+            for (index, step) in steps.iter().enumerate() {
+                {
+                    let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                    self.dispatch(world, &mut pass, step.pass, step.view);
+                }
+                queries.write_timestamp(encoder, index as u32 + 1);
+            }
+        }
 
-        let pipeline = pipeline_cache
-            .get_compute_pipeline(pipeline.pipelines.collect)
-            .unwrap();
-        pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(num_dispatch, 1, 1);
+        queries.resolve(encoder);
+        Some(queries.frame())
     }
 }
 
+struct RayTraceStep<'a> {
+    pass: &'a RayTracePass,
+    view: &'a RayTraceView,
+    // None for setup and resolve passes.
+    bounce: Option<u32>,
+}
+
 fn is_pipeline_ready(pipeline_cache: &PipelineCache, pipeline: CachedComputePipelineId) -> bool {
     if let CachedPipelineState::Ok(_) = pipeline_cache.get_compute_pipeline_state(pipeline) {
         true
@@ -174,22 +195,21 @@ impl render_graph::Node for RayTraceNode {
     fn update(&mut self, world: &mut World) {
         let pipeline = world.resource::<RayTracePipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
-
-        // if the corresponding pipeline has loaded, transition to the next stage
-        match self.state {
-            RayTraceState::Loading => {
-                if is_pipeline_ready(pipeline_cache, pipeline.pipelines.clear)
-                    && is_pipeline_ready(pipeline_cache, pipeline.pipelines.prepass)
-                    && is_pipeline_ready(pipeline_cache, pipeline.pipelines.generate)
-                    && is_pipeline_ready(pipeline_cache, pipeline.pipelines.intersect)
-                    && is_pipeline_ready(pipeline_cache, pipeline.pipelines.shade)
-                    && is_pipeline_ready(pipeline_cache, pipeline.pipelines.collect)
-                {
-                    self.state = RayTraceState::Ready;
-                }
-            }
-            RayTraceState::Ready => {}
-        }
+        let passes = world.resource::<RayTracePasses>();
+
+        // Passes can be registered at any time, so keep checking that every pass
+        // has its pipeline loaded before running the graph.
+        let ready = passes.iter().all(|pass| {
+            pipeline
+                .get(pass.name)
+                .map_or(false, |id| is_pipeline_ready(pipeline_cache, id))
+        });
+
+        self.state = if ready {
+            RayTraceState::Ready
+        } else {
+            RayTraceState::Loading
+        };
     }
 
     fn run(
@@ -202,21 +222,24 @@ impl render_graph::Node for RayTraceNode {
             RayTraceState::Loading => {}
 
             RayTraceState::Ready => {
-                let mut pass = render_context
-                    .command_encoder
-                    .begin_compute_pass(&ComputePassDescriptor::default());
-
-                self.clear(world, &mut pass);
+                let steps = self.steps(world);
+                let encoder = &mut render_context.command_encoder;
 
-                self.generate(world, &mut pass);
+                let profiled_frame = self.record_timed(world, encoder, &steps);
 
-                for _ in 0..3 {
-                    self.prepass(world, &mut pass);
-                    self.intersect(world, &mut pass);
-                    self.shade(world, &mut pass);
+                if profiled_frame.is_none() {
+                    let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                    for step in steps.iter() {
+                        self.dispatch(world, &mut pass, step.pass, step.view);
+                    }
                 }
 
-                self.collect(world, &mut pass);
+                // The passes only count while stats are enabled.
+                if world.resource::<RayStatsEnabled>().0 {
+                    for stats in world.resource::<RayStatsGPUStorage>().buffers.values() {
+                        stats.copy_to_readback(encoder, profiled_frame);
+                    }
+                }
             }
         }
 
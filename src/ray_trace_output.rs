@@ -1,119 +1,297 @@
 use bevy::{
+    core_pipeline::core_3d,
     prelude::*,
     render::{
-        extract_resource::{ExtractResource, ExtractResourcePlugin},
-        render_asset::RenderAssets,
+        camera::ExtractedCamera,
+        render_graph::{self, RenderGraph, SlotInfo, SlotType},
         render_resource::*,
-        renderer::RenderDevice,
+        renderer::{RenderContext, RenderDevice},
+        texture::BevyDefault,
+        view::ViewTarget,
         RenderApp, RenderStage,
     },
-    window::WindowResized,
+    utils::HashMap,
 };
+use std::borrow::Cow;
 
+use crate::ray_trace_camera::ExtractedRayTraceViews;
 use crate::ray_trace_pipeline::RayTracePipeline;
-use crate::RENDER_TARGET_SIZE;
 
-#[derive(Component)]
-pub struct RenderTarget;
+const BLIT_NODE: &str = "raytrace_blit";
 
-#[derive(Clone, Deref, ExtractResource)]
-pub struct RayTraceOutputImage(Handle<Image>);
+// The texture a view is traced into, one texel per pixel of the camera's
+// viewport. Drawn into the viewport by the blit node.
+pub struct OutputTexture {
+    pub view: TextureView,
+    pub size: UVec2,
+}
+
+// Keyed by RayTraced camera entity.
+#[derive(Default)]
+pub struct OutputTextures {
+    pub views: HashMap<Entity, OutputTexture>,
+}
+
+pub struct OutputImageBindGroup(pub HashMap<Entity, BindGroup>);
+
+// Draws the output image over the RayTraced camera's render target.
+pub struct BlitPipeline {
+    layout: BindGroupLayout,
+    pipeline: CachedRenderPipelineId,
+}
 
-pub struct OutputImageBindGroup(pub BindGroup);
+pub struct BlitBindGroup(pub HashMap<Entity, BindGroup>);
 
 pub struct RayTraceOutputPlugin;
 
 impl Plugin for RayTraceOutputPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(ExtractResourcePlugin::<RayTraceOutputImage>::default())
-            .add_startup_system(init_output)
-            .add_system(on_window_resized);
-
         let render_app = app.sub_app_mut(RenderApp);
-        render_app.add_system_to_stage(RenderStage::Queue, queue);
+        render_app
+            .init_resource::<OutputTextures>()
+            .init_resource::<BlitPipeline>()
+            .add_system_to_stage(RenderStage::Prepare, prepare)
+            .add_system_to_stage(RenderStage::Queue, queue);
+
+        // Run in the 3d graph of every camera, after the main pass so the
+        // traced image lands on top of whatever was rasterized.
+        let blit = BlitNode::new(&mut render_app.world);
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        let core_3d = render_graph
+            .get_sub_graph_mut(core_3d::graph::NAME)
+            .unwrap();
+        let input_node = core_3d.input_node().unwrap().id;
+        core_3d.add_node(BLIT_NODE, blit);
+        core_3d
+            .add_slot_edge(
+                input_node,
+                core_3d::graph::input::VIEW_ENTITY,
+                BLIT_NODE,
+                BlitNode::IN_VIEW,
+            )
+            .unwrap();
+        core_3d
+            .add_node_edge(core_3d::graph::node::MAIN_PASS, BLIT_NODE)
+            .unwrap();
+        // Keep UI, like the diagnostics overlay, on top of the traced image.
+        core_3d
+            .add_node_edge(BLIT_NODE, bevy::ui::draw_ui_graph::node::UI_PASS)
+            .unwrap();
     }
 }
 
-fn vf_to_u8(v: &[f32]) -> &[u8] {
-    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, v.len() * 4) }
-}
+fn prepare(
+    views: Res<ExtractedRayTraceViews>,
+    mut outputs: ResMut<OutputTextures>,
+    render_device: Res<RenderDevice>,
+) {
+    outputs.views.retain(|entity, _| views.contains(*entity));
 
-fn init_output(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
-    // Create an image of the size of the screen and attach it to a sprite with the same size.
-    // This will become the render target for the compute pipeline.
-    // todo: resize the render target and sprite when the screen is resized.
-
-    let fill = vec![0f32, 0f32, 0f32, 1f32];
-    let fill = vf_to_u8(&fill[..]);
-
-    let mut image = Image::new_fill(
-        Extent3d {
-            width: RENDER_TARGET_SIZE.0,
-            height: RENDER_TARGET_SIZE.1,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        fill,
-        TextureFormat::Rgba32Float,
-    );
-
-    image.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
-
-    let image = images.add(image);
-
-    commands
-        .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(
-                    RENDER_TARGET_SIZE.0 as f32,
-                    RENDER_TARGET_SIZE.1 as f32,
-                )),
-                ..default()
+    for view in views.views.iter() {
+        // Made again whenever the viewport changes size, e.g. with the window.
+        if let Some(output) = outputs.views.get(&view.entity) {
+            if output.size == view.size {
+                continue;
+            }
+        }
+
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("ray_trace_output"),
+            size: Extent3d {
+                width: view.size.x,
+                height: view.size.y,
+                depth_or_array_layers: 1,
             },
-            texture: image.clone(),
-            ..default()
-        })
-        .insert(RenderTarget);
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+        });
 
-    commands.insert_resource(RayTraceOutputImage(image));
+        outputs.views.insert(
+            view.entity,
+            OutputTexture {
+                view: texture.create_view(&TextureViewDescriptor::default()),
+                size: view.size,
+            },
+        );
+    }
 }
 
-fn on_window_resized(
-    mut event: EventReader<WindowResized>,
-    mut query: Query<&mut Sprite, With<RenderTarget>>,
-) {
-    if event.is_empty() || query.is_empty() {
-        return;
-    }
+impl FromWorld for BlitPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let layout =
+            world
+                .resource::<RenderDevice>()
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: Some("blit_layout_descriptor"),
+                    entries: &[BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::FRAGMENT,
+                        // Rgba32Float can't be filtered, so the shader loads texels.
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: false },
+                            view_dimension: TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    }],
+                });
 
-    // Resize the sprite to fit the window.
-    for e in event.iter() {
-        let mut sprite = query.single_mut();
-        sprite.custom_size = Some(Vec2::new(e.width, e.height));
-    }
+        let shader = world.resource::<AssetServer>().load("shaders/blit.wgsl");
 
-    // Also need to resize the render target to some aspect ratio of the sprite size...
+        // The main pass resolves into ViewTarget::view, which has Bevy's
+        // default format and a single sample.
+        let pipeline =
+            world
+                .resource_mut::<PipelineCache>()
+                .queue_render_pipeline(RenderPipelineDescriptor {
+                    label: Some(Cow::from("blit")),
+                    layout: Some(vec![layout.clone()]),
+                    vertex: VertexState {
+                        shader: shader.clone(),
+                        shader_defs: vec![],
+                        entry_point: Cow::from("vertex"),
+                        buffers: vec![],
+                    },
+                    fragment: Some(FragmentState {
+                        shader,
+                        shader_defs: vec![],
+                        entry_point: Cow::from("fragment"),
+                        targets: vec![Some(ColorTargetState {
+                            format: TextureFormat::bevy_default(),
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: MultisampleState::default(),
+                });
+
+        BlitPipeline { layout, pipeline }
+    }
 }
 
 fn queue(
     mut commands: Commands,
     pipeline: Res<RayTracePipeline>,
-    gpu_images: Res<RenderAssets<Image>>,
-    output_image: Res<RayTraceOutputImage>,
+    blit_pipeline: Res<BlitPipeline>,
+    outputs: Res<OutputTextures>,
     render_device: Res<RenderDevice>,
 ) {
-    let view = &gpu_images[&output_image.0];
-    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-        label: Some("output_bind_group"),
-        layout: &pipeline.bind_groups.output,
-        entries: &[BindGroupEntry {
-            binding: 0,
-            resource: BindingResource::TextureView(&view.texture_view),
-        }],
-    });
+    let mut output_bind_groups = HashMap::default();
+    let mut blit_bind_groups = HashMap::default();
+
+    for (entity, output) in outputs.views.iter() {
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("output_bind_group"),
+            layout: &pipeline.bind_groups.output,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&output.view),
+            }],
+        });
+        output_bind_groups.insert(*entity, bind_group);
 
-    commands.insert_resource(OutputImageBindGroup(bind_group));
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("blit_bind_group"),
+            layout: &blit_pipeline.layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&output.view),
+            }],
+        });
+        blit_bind_groups.insert(*entity, bind_group);
+    }
+
+    commands.insert_resource(OutputImageBindGroup(output_bind_groups));
+    commands.insert_resource(BlitBindGroup(blit_bind_groups));
+}
+
+pub struct BlitNode {
+    query: QueryState<(&'static ViewTarget, &'static ExtractedCamera)>,
+}
+
+impl BlitNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    fn new(world: &mut World) -> Self {
+        BlitNode {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl render_graph::Node for BlitNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(BlitNode::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let view_entity = graph.get_input_entity(BlitNode::IN_VIEW)?;
+
+        // Cameras that aren't RayTraced are left as rasterized.
+        let bind_group = match world.resource::<BlitBindGroup>().0.get(&view_entity) {
+            Some(bind_group) => bind_group,
+            None => return Ok(()),
+        };
+
+        let (target, camera) = match self.query.get_manual(world, view_entity) {
+            Ok(query) => query,
+            Err(_) => return Ok(()),
+        };
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline =
+            match pipeline_cache.get_render_pipeline(world.resource::<BlitPipeline>().pipeline) {
+                Some(pipeline) => pipeline,
+                None => return Ok(()),
+            };
+
+        let mut pass = render_context
+            .command_encoder
+            .begin_render_pass(&RenderPassDescriptor {
+                label: Some("raytrace_blit_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+
+        // Cameras sharing a target each only cover their own viewport.
+        if let Some(viewport) = &camera.viewport {
+            pass.set_viewport(
+                viewport.physical_position.x as f32,
+                viewport.physical_position.y as f32,
+                viewport.physical_size.x as f32,
+                viewport.physical_size.y as f32,
+                viewport.depth.start,
+                viewport.depth.end,
+            );
+        }
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
 }
 
 pub fn describe<'a>() -> BindGroupLayoutDescriptor<'a> {
@@ -0,0 +1,619 @@
+use crate::plugin::{
+    CameraGlobalsBindGroup, DispatchBindGroup, ObjectsMaterialsBindGroup,
+    RaysIntersectionsBindGroup,
+};
+use crate::ray_trace_camera::RayTraceView;
+use crate::ray_trace_mode::RayTraceMode;
+use crate::ray_trace_output::OutputImageBindGroup;
+use crate::ray_trace_pipeline::RayTraceBindGroups;
+use crate::ray_trace_queue::RayQueue;
+use bevy::{prelude::*, render::render_resource::*};
+use std::fmt;
+
+pub const WORKGROUP_SIZE: u32 = 128;
+
+// Enough workgroups to cover every invocation. The last workgroup may be
+// partially used, so shaders must bounds check their index.
+pub fn workgroup_count(invocations: u32) -> u32 {
+    (invocations + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE
+}
+
+// The bind groups a pass can ask for. A pass lists these in the order of its
+// @group() indices, so the position in RayTracePass::bind_groups is the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayTraceBindGroup {
+    CameraGlobals,
+    RaysIntersections,
+    ObjectsMaterials,
+    Output,
+    Dispatch,
+}
+
+impl RayTraceBindGroup {
+    pub fn layout(self, layouts: &RayTraceBindGroups) -> BindGroupLayout {
+        match self {
+            RayTraceBindGroup::CameraGlobals => layouts.camera_globals.clone(),
+            RayTraceBindGroup::RaysIntersections => layouts.rays_intersections.clone(),
+            RayTraceBindGroup::ObjectsMaterials => layouts.objects_materials.clone(),
+            RayTraceBindGroup::Output => layouts.output.clone(),
+            RayTraceBindGroup::Dispatch => layouts.dispatch.clone(),
+        }
+    }
+
+    // Only the objects and materials are shared, everything else is per view.
+    // None for a view whose bind group hasn't been queued yet.
+    pub fn get(self, world: &World, view: Entity) -> Option<&BindGroup> {
+        match self {
+            RayTraceBindGroup::CameraGlobals => {
+                world.resource::<CameraGlobalsBindGroup>().0.get(&view)
+            }
+            RayTraceBindGroup::RaysIntersections => {
+                world.resource::<RaysIntersectionsBindGroup>().0.get(&view)
+            }
+            RayTraceBindGroup::ObjectsMaterials => {
+                Some(&world.resource::<ObjectsMaterialsBindGroup>().0)
+            }
+            RayTraceBindGroup::Output => world.resource::<OutputImageBindGroup>().0.get(&view),
+            RayTraceBindGroup::Dispatch => world.resource::<DispatchBindGroup>().0.get(&view),
+        }
+    }
+}
+
+// How many workgroups a pass is launched with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayTraceDispatch {
+    // A single invocation, for passes that only touch the globals.
+    Single,
+    // One invocation per ray in the view's ray buffer.
+    PerRay,
+    // One invocation per pixel of the view.
+    PerPixel,
+    // One invocation per entry in a ray queue. The workgroup count is read
+    // from the queue's slot in the DispatchIndirectStorage buffer.
+    Indirect(RayQueue),
+}
+
+impl RayTraceDispatch {
+    // None when the workgroup count is only known on the GPU.
+    pub fn workgroups(self, view: &RayTraceView) -> Option<u32> {
+        match self {
+            RayTraceDispatch::Single => Some(1),
+            RayTraceDispatch::PerRay => Some(workgroup_count(view.ray_count())),
+            RayTraceDispatch::PerPixel => Some(workgroup_count(view.pixel_count())),
+            RayTraceDispatch::Indirect(_) => None,
+        }
+    }
+}
+
+// Setup passes run once per frame, bounce passes run once per bounce and
+// resolve passes run once after the last bounce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayTracePhase {
+    Setup,
+    Bounce,
+    Resolve,
+}
+
+// Everything needed to build and dispatch one compute pass of the ray tracer.
+#[derive(Clone, Debug)]
+pub struct RayTracePass {
+    pub name: &'static str,
+    pub shader: &'static str,
+    pub entry_point: &'static str,
+    pub phase: RayTracePhase,
+    // Only run the pass in this mode, or in every mode if None.
+    pub mode: Option<RayTraceMode>,
+    pub bind_groups: Vec<RayTraceBindGroup>,
+    pub dispatch: RayTraceDispatch,
+    pub shader_defs: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum PassError {
+    NotFound(String),
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassError::NotFound(name) => write!(f, "no ray trace pass named {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for PassError {}
+
+// The ordered list of passes the node runs. This lives in the render app, so
+// other plugins can add their own passes, e.g. a debug view after shading:
+//
+//     app.sub_app_mut(RenderApp)
+//         .world
+//         .resource_mut::<RayTracePasses>()
+//         .insert_after("shade_medium", my_debug_pass)?;
+pub struct RayTracePasses {
+    passes: Vec<RayTracePass>,
+}
+
+impl RayTracePasses {
+    pub fn iter(&self) -> impl Iterator<Item = &RayTracePass> {
+        self.passes.iter()
+    }
+
+    pub fn phase(
+        &self,
+        phase: RayTracePhase,
+        mode: RayTraceMode,
+    ) -> impl Iterator<Item = &RayTracePass> {
+        self.passes
+            .iter()
+            .filter(move |pass| pass.phase == phase && pass.mode.map_or(true, |m| m == mode))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RayTracePass> {
+        self.passes.iter().find(|pass| pass.name == name)
+    }
+
+    pub fn push(&mut self, pass: RayTracePass) {
+        self.passes.push(pass);
+    }
+
+    pub fn insert_before(&mut self, before: &str, pass: RayTracePass) -> Result<(), PassError> {
+        let index = self.index_of(before)?;
+        self.passes.insert(index, pass);
+        Ok(())
+    }
+
+    pub fn insert_after(&mut self, after: &str, pass: RayTracePass) -> Result<(), PassError> {
+        let index = self.index_of(after)?;
+        self.passes.insert(index + 1, pass);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RayTracePass> {
+        let index = self.passes.iter().position(|pass| pass.name == name)?;
+        Some(self.passes.remove(index))
+    }
+
+    fn index_of(&self, name: &str) -> Result<usize, PassError> {
+        self.passes
+            .iter()
+            .position(|pass| pass.name == name)
+            .ok_or_else(|| PassError::NotFound(name.to_string()))
+    }
+}
+
+impl Default for RayTracePasses {
+    fn default() -> Self {
+        use RayTraceBindGroup::*;
+
+        Self {
+            passes: vec![
+                RayTracePass {
+                    name: "clear",
+                    shader: "shaders/clear.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Setup,
+                    mode: None,
+                    bind_groups: vec![CameraGlobals, RaysIntersections, Output],
+                    dispatch: RayTraceDispatch::PerRay,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "generate",
+                    shader: "shaders/generate.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Setup,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections],
+                    dispatch: RayTraceDispatch::PerRay,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "megakernel",
+                    shader: "shaders/megakernel.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Setup,
+                    mode: Some(RayTraceMode::Megakernel),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::PerRay,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "prepass",
+                    shader: "shaders/prepass.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, Output],
+                    dispatch: RayTraceDispatch::Single,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "compact",
+                    shader: "shaders/compact.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections],
+                    dispatch: RayTraceDispatch::PerRay,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "dispatch",
+                    shader: "shaders/dispatch.wgsl",
+                    entry_point: "dispatch_alive",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, Dispatch],
+                    dispatch: RayTraceDispatch::Single,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "intersect",
+                    shader: "shaders/intersect.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Alive),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "dispatch_shade",
+                    shader: "shaders/dispatch.wgsl",
+                    entry_point: "dispatch_shade",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, Dispatch],
+                    dispatch: RayTraceDispatch::Single,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_miss",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_miss",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Miss),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_diffuse",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_diffuse",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Diffuse),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_metal",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_metal",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Metal),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_dielectric",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_dielectric",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Dielectric),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_emissive",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_emissive",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Emissive),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_principled",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_principled",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Principled),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_medium",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_medium",
+                    phase: RayTracePhase::Bounce,
+                    mode: Some(RayTraceMode::Wavefront),
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::Indirect(RayQueue::Medium),
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "shade_debug",
+                    shader: "shaders/shade.wgsl",
+                    entry_point: "shade_debug",
+                    phase: RayTracePhase::Resolve,
+                    mode: None,
+                    bind_groups: vec![CameraGlobals, RaysIntersections, ObjectsMaterials],
+                    dispatch: RayTraceDispatch::PerRay,
+                    shader_defs: vec![],
+                },
+                RayTracePass {
+                    name: "collect",
+                    shader: "shaders/collect.wgsl",
+                    entry_point: "main",
+                    phase: RayTracePhase::Resolve,
+                    mode: None,
+                    bind_groups: vec![CameraGlobals, RaysIntersections, Output],
+                    dispatch: RayTraceDispatch::PerPixel,
+                    shader_defs: vec![],
+                },
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray_trace_mode::RayTraceIndexing;
+
+    // Resolutions that don't divide into workgroups, with a few sample counts.
+    const SIZES: [(u32, u32); 6] = [
+        (1366, 768),
+        (333, 177),
+        (1, 1),
+        (127, 1),
+        (129, 3),
+        (1921, 1081),
+    ];
+
+    #[test]
+    fn workgroup_count_covers_every_invocation() {
+        for (width, height) in SIZES {
+            for samples in [1, 2, 3, 16] {
+                let invocations = width * height * samples;
+                let groups = workgroup_count(invocations);
+
+                assert!(groups * WORKGROUP_SIZE >= invocations);
+                // The last group always has at least one invocation to run.
+                assert!((groups - 1) * WORKGROUP_SIZE < invocations);
+            }
+        }
+    }
+
+    #[test]
+    fn workgroup_count_is_exact_for_multiples() {
+        assert_eq!(workgroup_count(0), 0);
+        assert_eq!(workgroup_count(WORKGROUP_SIZE), 1);
+        assert_eq!(workgroup_count(WORKGROUP_SIZE * 60), 60);
+        assert_eq!(workgroup_count(WORKGROUP_SIZE * 60 + 1), 61);
+    }
+
+    fn view(width: u32, height: u32) -> RayTraceView {
+        RayTraceView {
+            entity: Entity::from_raw(0),
+            size: UVec2::new(width, height),
+            transform: Transform::default(),
+            fov: 1.0,
+            settings: default(),
+            debug_view: default(),
+            accumulated_frames: 0,
+        }
+    }
+
+    // The index each invocation of the dispatch works on. With invocation id
+    // indexing it's the invocation's own id. With the atomic counter each
+    // invocation takes the next value, in whatever order the workgroups run,
+    // here last to first.
+    fn indices(workgroups: u32, indexing: RayTraceIndexing) -> Vec<u32> {
+        let invocations = 0..workgroups * WORKGROUP_SIZE;
+        match indexing {
+            RayTraceIndexing::InvocationId => invocations.collect(),
+            RayTraceIndexing::AtomicCounter => {
+                let mut counter = 0;
+                let mut indices = vec![0; invocations.len()];
+                for group in (0..workgroups).rev() {
+                    for local in 0..WORKGROUP_SIZE {
+                        indices[(group * WORKGROUP_SIZE + local) as usize] = counter;
+                        counter += 1;
+                    }
+                }
+                indices
+            }
+        }
+    }
+
+    const INDEXINGS: [RayTraceIndexing; 2] = [
+        RayTraceIndexing::AtomicCounter,
+        RayTraceIndexing::InvocationId,
+    ];
+
+    #[test]
+    fn per_ray_dispatch_writes_every_sample_once() {
+        for (width, height) in SIZES {
+            let view = view(width, height);
+            let workgroups = RayTraceDispatch::PerRay.workgroups(&view).unwrap();
+
+            for indexing in INDEXINGS {
+                let mut written = vec![0u8; view.ray_count() as usize];
+                for index in indices(workgroups, indexing) {
+                    // As clear.wgsl and generate.wgsl index the ray buffers.
+                    if index >= view.ray_count() {
+                        continue;
+                    }
+                    let x = index % width;
+                    let y = (index / width) % height;
+                    let sample = index / (width * height);
+
+                    written[((sample * height + y) * width + x) as usize] += 1;
+                }
+
+                assert!(
+                    written.iter().all(|&count| count == 1),
+                    "{}x{} {:?}",
+                    width,
+                    height,
+                    indexing
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn per_pixel_dispatch_writes_every_pixel_once() {
+        for (width, height) in SIZES {
+            let view = view(width, height);
+            let workgroups = RayTraceDispatch::PerPixel.workgroups(&view).unwrap();
+
+            for indexing in INDEXINGS {
+                let mut written = vec![0u8; view.pixel_count() as usize];
+                for index in indices(workgroups, indexing) {
+                    // As collect.wgsl indexes the output.
+                    if index >= width * height {
+                        continue;
+                    }
+                    let x = index % width;
+                    let y = index / width;
+                    assert!(y < height);
+
+                    written[(y * width + x) as usize] += 1;
+                }
+
+                assert!(
+                    written.iter().all(|&count| count == 1),
+                    "{}x{} {:?}",
+                    width,
+                    height,
+                    indexing
+                );
+            }
+        }
+    }
+
+    fn names<'a>(passes: impl Iterator<Item = &'a RayTracePass>) -> Vec<&'static str> {
+        passes.map(|pass| pass.name).collect()
+    }
+
+    // A copy of one of the default passes under another name.
+    fn pass_like(like: &str, name: &'static str) -> RayTracePass {
+        RayTracePass {
+            name,
+            ..RayTracePasses::default().get(like).unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn default_passes_run_in_order() {
+        let passes = RayTracePasses::default();
+        let phase = |phase, mode| names(passes.phase(phase, mode));
+
+        use RayTraceMode::*;
+        use RayTracePhase::*;
+        assert_eq!(phase(Setup, Wavefront), ["clear", "generate"]);
+        assert_eq!(phase(Setup, Megakernel), ["clear", "megakernel"]);
+        assert_eq!(
+            phase(Bounce, Wavefront),
+            [
+                "prepass",
+                "compact",
+                "dispatch",
+                "intersect",
+                "dispatch_shade",
+                "shade_miss",
+                "shade_diffuse",
+                "shade_metal",
+                "shade_dielectric",
+                "shade_emissive",
+                "shade_principled",
+                "shade_medium",
+            ]
+        );
+        assert!(phase(Bounce, Megakernel).is_empty());
+        assert_eq!(phase(Resolve, Wavefront), ["shade_debug", "collect"]);
+        assert_eq!(phase(Resolve, Megakernel), ["shade_debug", "collect"]);
+    }
+
+    #[test]
+    fn insert_places_the_pass_next_to_another() {
+        let mut passes = RayTracePasses::default();
+        passes
+            .insert_before("intersect", pass_like("intersect", "cull"))
+            .unwrap();
+        passes
+            .insert_after("shade_medium", pass_like("shade_medium", "shade_fur"))
+            .unwrap();
+
+        let bounce = names(passes.phase(RayTracePhase::Bounce, RayTraceMode::Wavefront));
+        let position = |name| bounce.iter().position(|pass| *pass == name).unwrap();
+        assert_eq!(position("cull") + 1, position("intersect"));
+        assert_eq!(position("shade_medium") + 1, position("shade_fur"));
+        assert_eq!(bounce.last(), Some(&"shade_fur"));
+    }
+
+    #[test]
+    fn push_appends_to_its_phase() {
+        let mut passes = RayTracePasses::default();
+        passes.push(pass_like("collect", "tonemap"));
+
+        let resolve = names(passes.phase(RayTracePhase::Resolve, RayTraceMode::Wavefront));
+        assert_eq!(resolve, ["shade_debug", "collect", "tonemap"]);
+    }
+
+    #[test]
+    fn insert_next_to_a_missing_pass_fails() {
+        let mut passes = RayTracePasses::default();
+        let before = names(passes.iter());
+
+        let result = passes.insert_before("missing", pass_like("intersect", "cull"));
+        assert!(matches!(result, Err(PassError::NotFound(name)) if name == "missing"));
+        let result = passes.insert_after("missing", pass_like("intersect", "cull"));
+        assert!(matches!(result, Err(PassError::NotFound(name)) if name == "missing"));
+
+        assert_eq!(names(passes.iter()), before);
+    }
+
+    #[test]
+    fn remove_takes_the_pass_out() {
+        let mut passes = RayTracePasses::default();
+
+        let removed = passes.remove("shade_debug").unwrap();
+        assert_eq!(removed.name, "shade_debug");
+        assert!(passes.get("shade_debug").is_none());
+        assert!(passes.remove("shade_debug").is_none());
+
+        let resolve = names(passes.phase(RayTracePhase::Resolve, RayTraceMode::Megakernel));
+        assert_eq!(resolve, ["collect"]);
+    }
+
+    #[test]
+    fn passes_only_run_in_their_mode() {
+        let mut passes = RayTracePasses::default();
+        passes
+            .insert_after(
+                "megakernel",
+                RayTracePass {
+                    mode: None,
+                    ..pass_like("megakernel", "sky")
+                },
+            )
+            .unwrap();
+
+        let setup = |mode| names(passes.phase(RayTracePhase::Setup, mode));
+        assert_eq!(setup(RayTraceMode::Wavefront), ["clear", "generate", "sky"]);
+        assert_eq!(
+            setup(RayTraceMode::Megakernel),
+            ["clear", "megakernel", "sky"]
+        );
+    }
+}
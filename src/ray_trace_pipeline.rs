@@ -1,6 +1,10 @@
+use crate::ray_trace_mode::RayTraceIndexing;
+use crate::ray_trace_pass::RayTracePasses;
+use crate::ray_trace_stats::RayStatsEnabled;
 use bevy::{
     prelude::*,
     render::{render_resource::*, renderer::RenderDevice},
+    utils::HashMap,
 };
 use std::borrow::Cow;
 
@@ -9,162 +13,39 @@ pub struct RayTraceBindGroups {
     pub rays_intersections: BindGroupLayout,
     pub objects_materials: BindGroupLayout,
     pub output: BindGroupLayout,
+    pub dispatch: BindGroupLayout,
 }
 
-pub struct RayTracePipelines {
-    pub clear: CachedComputePipelineId,
-    pub prepass: CachedComputePipelineId,
-    pub generate: CachedComputePipelineId,
-    pub intersect: CachedComputePipelineId,
-    pub shade: CachedComputePipelineId,
-    pub collect: CachedComputePipelineId,
-    // connect: CachedComputePipelineId,
+// The toggles every pass is compiled with, on top of its own shader defs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineVariant {
+    pub indexing: RayTraceIndexing,
+    // Whether the passes count rays into the stats buffers.
+    pub stats: bool,
+}
+
+impl PipelineVariant {
+    pub fn shader_defs(self) -> Vec<String> {
+        let mut shader_defs = self.indexing.shader_defs();
+        if self.stats {
+            shader_defs.push("RAY_STATS".to_string());
+        }
+        shader_defs
+    }
 }
 
 pub struct RayTracePipeline {
-    pub pipelines: RayTracePipelines,
+    // Keyed by RayTracePass::name and the variant the pipeline was built
+    // with, so toggling back reuses the pipelines already built.
+    pub pipelines: HashMap<(&'static str, PipelineVariant), CachedComputePipelineId>,
     pub bind_groups: RayTraceBindGroups,
+    // The variant the node dispatches with.
+    pub variant: PipelineVariant,
 }
 
 impl RayTracePipeline {
-    fn create_pipelines(world: &mut World, bind_groups: &RayTraceBindGroups) -> RayTracePipelines {
-        RayTracePipelines {
-            clear: RayTracePipeline::create_clear_pipeline(world, bind_groups),
-            prepass: RayTracePipeline::create_prepass_pipeline(world, bind_groups),
-            generate: RayTracePipeline::create_generate_pipeline(world, bind_groups),
-            intersect: RayTracePipeline::create_intersect_pipeline(world, bind_groups),
-            shade: RayTracePipeline::create_shade_pipeline(world, bind_groups),
-            collect: RayTracePipeline::create_collect_pipeline(world, bind_groups),
-        }
-    }
-
-    fn create_clear_pipeline(
-        world: &mut World,
-        bind_groups: &RayTraceBindGroups,
-    ) -> CachedComputePipelineId {
-        let shader = world.resource::<AssetServer>().load("shaders/clear.wgsl");
-
-        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
-
-        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("clear")),
-            layout: Some(vec![
-                bind_groups.camera_globals.clone(),
-                bind_groups.rays_intersections.clone(),
-                bind_groups.output.clone(),
-            ]),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("main"),
-        })
-    }
-
-    fn create_prepass_pipeline(
-        world: &mut World,
-        bind_groups: &RayTraceBindGroups,
-    ) -> CachedComputePipelineId {
-        let shader = world.resource::<AssetServer>().load("shaders/prepass.wgsl");
-
-        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
-
-        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("prepass")),
-            layout: Some(vec![
-                bind_groups.camera_globals.clone(),
-                bind_groups.rays_intersections.clone(),
-                bind_groups.output.clone(),
-            ]),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("main"),
-        })
-    }
-
-    fn create_generate_pipeline(
-        world: &mut World,
-        bind_groups: &RayTraceBindGroups,
-    ) -> CachedComputePipelineId {
-        let shader = world
-            .resource::<AssetServer>()
-            .load("shaders/generate.wgsl");
-
-        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
-
-        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("generate")),
-            layout: Some(vec![
-                bind_groups.camera_globals.clone(),
-                bind_groups.rays_intersections.clone(),
-            ]),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("main"),
-        })
-    }
-
-    fn create_intersect_pipeline(
-        world: &mut World,
-        bind_groups: &RayTraceBindGroups,
-    ) -> CachedComputePipelineId {
-        let shader = world
-            .resource::<AssetServer>()
-            .load("shaders/intersect.wgsl");
-
-        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
-
-        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("intersect")),
-            layout: Some(vec![
-                bind_groups.camera_globals.clone(),
-                bind_groups.rays_intersections.clone(),
-                bind_groups.objects_materials.clone(),
-            ]),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("main"),
-        })
-    }
-
-    fn create_shade_pipeline(
-        world: &mut World,
-        bind_groups: &RayTraceBindGroups,
-    ) -> CachedComputePipelineId {
-        let shader = world.resource::<AssetServer>().load("shaders/shade.wgsl");
-
-        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
-
-        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("shade")),
-            layout: Some(vec![
-                bind_groups.camera_globals.clone(),
-                bind_groups.rays_intersections.clone(),
-                bind_groups.objects_materials.clone(),
-            ]),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("main"),
-        })
-    }
-
-    fn create_collect_pipeline(
-        world: &mut World,
-        bind_groups: &RayTraceBindGroups,
-    ) -> CachedComputePipelineId {
-        let shader = world.resource::<AssetServer>().load("shaders/collect.wgsl");
-
-        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
-
-        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("collect")),
-            layout: Some(vec![
-                bind_groups.camera_globals.clone(),
-                bind_groups.rays_intersections.clone(),
-                bind_groups.output.clone(),
-            ]),
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("main"),
-        })
+    pub fn get(&self, name: &'static str) -> Option<CachedComputePipelineId> {
+        self.pipelines.get(&(name, self.variant)).copied()
     }
 }
 
@@ -178,6 +59,8 @@ impl FromWorld for RayTracePipeline {
                 entries: &[
                     crate::ray_trace_camera::describe(0),
                     crate::ray_trace_globals::describe(1),
+                    crate::ray_trace_sampler::describe(2),
+                    crate::ray_trace_stats::describe(3),
                 ],
             }),
 
@@ -187,6 +70,10 @@ impl FromWorld for RayTracePipeline {
                     entries: &[
                         crate::ray_trace_rays::describe(0),
                         crate::ray_trace_intersection::describe(1),
+                        crate::ray_trace_queue::describe(2),
+                        crate::ray_trace_queue::describe(3),
+                        crate::ray_trace_throughput::describe(4),
+                        crate::ray_trace_accumulation::describe(5),
                     ],
                 },
             ),
@@ -196,17 +83,74 @@ impl FromWorld for RayTracePipeline {
                 entries: &[
                     crate::sphere::describe(0),
                     crate::ray_trace_materials::describe(1),
+                    crate::ray_trace_textures::describe_texture(2),
+                    crate::ray_trace_textures::describe_sampler(3),
+                    crate::ray_trace_procedural::describe(4),
+                    crate::medium::describe(5),
+                    crate::density_grid::describe(6),
+                    crate::density_grid::describe_texture(7),
+                    crate::density_grid::describe_sampler(8),
                 ],
             }),
 
             output: render_device.create_bind_group_layout(&crate::ray_trace_output::describe()),
-        };
 
-        let pipelines = RayTracePipeline::create_pipelines(world, &bind_groups);
+            dispatch: render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("dispatch_layout_descriptor"),
+                entries: &[crate::ray_trace_queue::describe_dispatch(0)],
+            }),
+        };
 
         RayTracePipeline {
             bind_groups,
-            pipelines,
+            pipelines: HashMap::default(),
+            variant: PipelineVariant {
+                indexing: RayTraceIndexing::default(),
+                stats: RayStatsEnabled::default().0,
+            },
+        }
+    }
+}
+
+// Queue a compute pipeline for every registered pass that doesn't have one yet
+// in the current variant. Running this every frame lets passes be registered
+// after the plugin is built.
+pub fn queue_pass_pipelines(
+    passes: Res<RayTracePasses>,
+    indexing: Res<RayTraceIndexing>,
+    stats: Res<RayStatsEnabled>,
+    mut pipeline: ResMut<RayTracePipeline>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    asset_server: Res<AssetServer>,
+) {
+    let variant = PipelineVariant {
+        indexing: *indexing,
+        stats: stats.0,
+    };
+    pipeline.variant = variant;
+
+    for pass in passes.iter() {
+        if pipeline.pipelines.contains_key(&(pass.name, variant)) {
+            continue;
         }
+
+        let layout = pass
+            .bind_groups
+            .iter()
+            .map(|bind_group| bind_group.layout(&pipeline.bind_groups))
+            .collect();
+
+        let mut shader_defs = pass.shader_defs.clone();
+        shader_defs.extend(variant.shader_defs());
+
+        let id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some(Cow::from(pass.name)),
+            layout: Some(layout),
+            shader: asset_server.load(pass.shader),
+            shader_defs,
+            entry_point: Cow::from(pass.entry_point),
+        });
+
+        pipeline.pipelines.insert((pass.name, variant), id);
     }
 }
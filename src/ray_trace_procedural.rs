@@ -0,0 +1,94 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp,
+    },
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+// Perlin noise tables from "Ray Tracing: The Next Week". They are generated
+// once from a fixed seed so the noise is the same every run.
+const PERLIN_POINT_COUNT: usize = 256;
+const PERLIN_SEED: u64 = 0x5eed;
+
+#[derive(ShaderType, Clone, Debug)]
+pub struct PerlinGPU {
+    // Random unit vectors, w unused.
+    ranvec: [Vec4; PERLIN_POINT_COUNT],
+    perm_x: [u32; PERLIN_POINT_COUNT],
+    perm_y: [u32; PERLIN_POINT_COUNT],
+    perm_z: [u32; PERLIN_POINT_COUNT],
+}
+
+impl PerlinGPU {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut ranvec = [Vec4::ZERO; PERLIN_POINT_COUNT];
+        for v in ranvec.iter_mut() {
+            let dir = Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            );
+            *v = dir.normalize_or_zero().extend(0.0);
+        }
+
+        PerlinGPU {
+            ranvec,
+            perm_x: Self::permutation(&mut rng),
+            perm_y: Self::permutation(&mut rng),
+            perm_z: Self::permutation(&mut rng),
+        }
+    }
+
+    fn permutation(rng: &mut StdRng) -> [u32; PERLIN_POINT_COUNT] {
+        let mut perm = [0u32; PERLIN_POINT_COUNT];
+        for (i, p) in perm.iter_mut().enumerate() {
+            *p = i as u32;
+        }
+        perm.shuffle(rng);
+        perm
+    }
+}
+
+pub struct PerlinGPUStorage {
+    pub buffer: StorageBuffer<PerlinGPU>,
+}
+
+impl FromWorld for PerlinGPUStorage {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+
+        // The tables never change, so upload them once.
+        let mut buffer = StorageBuffer::from(PerlinGPU::new(PERLIN_SEED));
+        buffer.write_buffer(render_device, render_queue);
+
+        PerlinGPUStorage { buffer }
+    }
+}
+
+pub struct RayTraceProceduralPlugin;
+
+impl Plugin for RayTraceProceduralPlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.init_resource::<PerlinGPUStorage>();
+    }
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
@@ -0,0 +1,216 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+    utils::HashMap,
+};
+use indexmap::IndexMap;
+use std::sync::Mutex;
+// Bevy doesn't re-export query sets.
+use wgpu::{QuerySet, QuerySetDescriptor, QueryType};
+
+use crate::ray_trace_diagnostics::RayTraceMeasurements;
+use crate::ray_trace_readback::Readback;
+use crate::ray_trace_stats::RayTraceGPUTimes;
+
+// Frames dispatching more passes than this, minus the timestamp before the
+// first, aren't profiled.
+const MAX_TIMESTAMPS: u32 = 1024;
+// Bytes per resolved timestamp, a u64 tick count.
+const QUERY_SIZE: u32 = 8;
+
+// A pass the profiler times, ending at the timestamp after it.
+#[derive(Clone, Copy, Debug)]
+pub struct TimedPass {
+    pub name: &'static str,
+    pub view: Entity,
+    // None for setup and resolve passes.
+    pub bounce: Option<u32>,
+}
+
+// What was recorded in the last profiled frame, numbered so the ray stats
+// copied in the same frame can be matched with its times.
+#[derive(Default)]
+struct ProfiledFrame {
+    number: u32,
+    passes: Vec<TimedPass>,
+}
+
+pub struct TimestampQueries {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback: Readback,
+    // Nanoseconds per timestamp tick.
+    period: f32,
+    // Whether timestamps can be written between dispatches of one compute
+    // pass, rather than only between passes.
+    pub inside_passes: bool,
+    // Read back once the buffer maps.
+    profiled: Mutex<ProfiledFrame>,
+}
+
+impl TimestampQueries {
+    pub fn frame(&self) -> u32 {
+        self.profiled.lock().unwrap().number
+    }
+
+    pub fn write_timestamp(&self, encoder: &mut CommandEncoder, index: u32) {
+        encoder.write_timestamp(&self.query_set, index);
+    }
+
+    // Only when inside_passes is set.
+    pub fn write_pass_timestamp<'a>(&'a self, pass: &mut ComputePass<'a>, index: u32) {
+        pass.write_timestamp(&self.query_set, index);
+    }
+
+    pub fn resolve(&self, encoder: &mut CommandEncoder) {
+        let count = self.profiled.lock().unwrap().passes.len() as u32 + 1;
+        encoder.resolve_query_set(&self.query_set, 0..count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(
+            &self.resolve_buffer,
+            0,
+            self.readback.buffer(),
+            0,
+            (count * QUERY_SIZE) as u64,
+        );
+    }
+}
+
+// Times every pass the node dispatches with GPU timestamps. Results are
+// read back a few frames late, and frames are skipped while the last one is
+// still on its way.
+pub struct RayTraceProfiler {
+    // None when the adapter can't write timestamps.
+    queries: Option<TimestampQueries>,
+}
+
+impl RayTraceProfiler {
+    // Where to record this frame's timestamps, one before the first pass and
+    // one after each. None if the frame isn't profiled.
+    pub fn begin_frame(&self, passes: Vec<TimedPass>) -> Option<&TimestampQueries> {
+        let queries = self.queries.as_ref()?;
+        if passes.len() as u32 + 1 > MAX_TIMESTAMPS || !queries.readback.begin() {
+            return None;
+        }
+
+        let mut profiled = queries.profiled.lock().unwrap();
+        profiled.number = profiled.number.wrapping_add(1);
+        profiled.passes = passes;
+        Some(queries)
+    }
+}
+
+impl FromWorld for RayTraceProfiler {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+
+        let features = render_device.features();
+        if !features.contains(WgpuFeatures::TIMESTAMP_QUERY) {
+            println!("GPU pass timings disabled: the adapter doesn't support timestamp queries");
+            return RayTraceProfiler { queries: None };
+        }
+
+        let query_set = render_device
+            .wgpu_device()
+            .create_query_set(&QuerySetDescriptor {
+                label: Some("ray_trace_timestamps"),
+                ty: QueryType::Timestamp,
+                count: MAX_TIMESTAMPS,
+            });
+
+        let size = (MAX_TIMESTAMPS * QUERY_SIZE) as u64;
+        let resolve_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("ray_trace_timestamps_resolve"),
+            size,
+            usage: BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        RayTraceProfiler {
+            queries: Some(TimestampQueries {
+                query_set,
+                resolve_buffer,
+                readback: Readback::new(render_device, "ray_trace_timestamps_readback", size),
+                period: render_queue.get_timestamp_period(),
+                inside_passes: features.contains(WgpuFeatures::WRITE_TIMESTAMP_INSIDE_PASSES),
+                profiled: Mutex::new(ProfiledFrame::default()),
+            }),
+        }
+    }
+}
+
+pub struct RayTraceProfilingPlugin;
+
+impl Plugin for RayTraceProfilingPlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<RayTraceProfiler>()
+            .init_resource::<RayTraceGPUTimes>()
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                read_timestamps.before(crate::ray_trace_stats::prepare),
+            )
+            .add_system_to_stage(RenderStage::Cleanup, map_timestamps);
+    }
+}
+
+fn map_timestamps(profiler: Res<RayTraceProfiler>) {
+    if let Some(queries) = &profiler.queries {
+        queries.readback.map();
+    }
+}
+
+fn read_timestamps(
+    profiler: Res<RayTraceProfiler>,
+    measurements: Res<RayTraceMeasurements>,
+    mut gpu_times: ResMut<RayTraceGPUTimes>,
+) {
+    if let Some(queries) = &profiler.queries {
+        let profiled = queries.profiled.lock().unwrap();
+        let passes = &profiled.passes;
+        let ticks: Vec<u64> = match queries.readback.read(|data| {
+            data.chunks_exact(QUERY_SIZE as usize)
+                .take(passes.len() + 1)
+                .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                .collect()
+        }) {
+            Some(ticks) => ticks,
+            None => return,
+        };
+
+        // Summed over bounces and views, then over each bounce's passes.
+        let mut total = 0.0;
+        let mut by_pass = IndexMap::new();
+        let mut by_bounce = IndexMap::new();
+        let mut by_view = HashMap::default();
+        for (pass, interval) in passes.iter().zip(ticks.windows(2)) {
+            let ms = interval[1].saturating_sub(interval[0]) as f64 * queries.period as f64
+                / 1_000_000.0;
+
+            total += ms;
+            *by_pass.entry(pass.name).or_insert(0.0) += ms;
+            if let Some(bounce) = pass.bounce {
+                *by_bounce.entry(bounce).or_insert(0.0) += ms;
+            }
+            *by_view.entry(pass.view).or_insert(0.0) += ms / 1000.0;
+        }
+
+        *gpu_times = RayTraceGPUTimes {
+            frame: profiled.number,
+            views: by_view,
+        };
+
+        measurements.push("gpu_ray_trace".to_string(), "ms", total);
+        for (name, ms) in by_pass {
+            measurements.push(format!("gpu_{}", name), "ms", ms);
+        }
+        for (bounce, ms) in by_bounce {
+            measurements.push(format!("gpu_bounce_{}", bounce), "ms", ms);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::ray_trace_camera::ExtractedRayTraceViews;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+    utils::HashMap,
+};
+
+// Size of wgpu's DispatchIndirect arguments: x, y and z workgroup counts.
+pub const DISPATCH_INDIRECT_SIZE: u64 = 12;
+
+// The material queues intersect bins hits into, in the order they are laid out
+// in MaterialQueueGPUStorage and GlobalsGPU::material_counts.
+pub const MATERIAL_QUEUE_COUNT: usize = 7;
+
+// The alive queue plus every material queue.
+pub const RAY_QUEUE_COUNT: usize = MATERIAL_QUEUE_COUNT + 1;
+
+// Each queue of rays that gets its own indirect dispatch arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayQueue {
+    Alive,
+    Miss,
+    Diffuse,
+    Metal,
+    Dielectric,
+    Emissive,
+    Principled,
+    Medium,
+}
+
+impl RayQueue {
+    pub fn dispatch_offset(self) -> u64 {
+        self as u64 * DISPATCH_INDIRECT_SIZE
+    }
+}
+
+// Indices into the ray buffer of every ray that is still alive, packed by the
+// compact pass. The number of valid entries is GlobalsGPU::alive_count.
+// Keyed by RayTraced camera entity.
+#[derive(Default)]
+pub struct RayQueueGPUStorage {
+    pub buffers: HashMap<Entity, StorageBuffer<Vec<u32>>>,
+}
+
+// Indices into the ray buffer binned by what the ray hit. Every material queue
+// gets ray_count entries, starting at its index times ray_count. Keyed by
+// RayTraced camera entity.
+#[derive(Default)]
+pub struct MaterialQueueGPUStorage {
+    pub buffers: HashMap<Entity, StorageBuffer<Vec<u32>>>,
+}
+
+// Workgroup counts for the indirect passes, one set of DispatchIndirect args
+// per RayQueue. Written on the GPU by the dispatch passes from the queue
+// counts. Keyed by RayTraced camera entity.
+#[derive(Default)]
+pub struct DispatchIndirectStorage {
+    pub buffers: HashMap<Entity, Buffer>,
+}
+
+pub struct RayTraceQueuePlugin;
+
+impl Plugin for RayTraceQueuePlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<RayQueueGPUStorage>()
+            .init_resource::<MaterialQueueGPUStorage>()
+            .init_resource::<DispatchIndirectStorage>()
+            .add_system_to_stage(RenderStage::Prepare, prepare);
+    }
+}
+
+fn prepare(
+    views: Res<ExtractedRayTraceViews>,
+    mut queues: ResMut<RayQueueGPUStorage>,
+    mut material_queues: ResMut<MaterialQueueGPUStorage>,
+    mut dispatch: ResMut<DispatchIndirectStorage>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    queues.buffers.retain(|entity, _| views.contains(*entity));
+    material_queues
+        .buffers
+        .retain(|entity, _| views.contains(*entity));
+    dispatch.buffers.retain(|entity, _| views.contains(*entity));
+
+    for view in views.views.iter() {
+        // The queue can hold every ray of the view.
+        let ray_count = view.ray_count() as usize;
+        let queue = queues.buffers.entry(view.entity).or_default();
+
+        if queue.get().len() != ray_count {
+            queue.get_mut().clear();
+            queue.get_mut().append(&mut vec![0; ray_count]);
+
+            queue.write_buffer(&render_device, &render_queue);
+
+            println!(
+                "Ray Queue Buffer {:?}: {:?} {:?}",
+                view.entity,
+                ray_count,
+                queue.get().size()
+            );
+        }
+
+        let material_queue_size = ray_count * MATERIAL_QUEUE_COUNT;
+        let material_queue = material_queues.buffers.entry(view.entity).or_default();
+
+        if material_queue.get().len() != material_queue_size {
+            material_queue.get_mut().clear();
+            material_queue
+                .get_mut()
+                .append(&mut vec![0; material_queue_size]);
+
+            material_queue.write_buffer(&render_device, &render_queue);
+
+            println!(
+                "Material Queue Buffer {:?}: {:?} {:?}",
+                view.entity,
+                material_queue_size,
+                material_queue.get().size()
+            );
+        }
+
+        dispatch.buffers.entry(view.entity).or_insert_with(|| {
+            render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("dispatch_indirect_buffer"),
+                contents: &[0u8; RAY_QUEUE_COUNT * DISPATCH_INDIRECT_SIZE as usize],
+                usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+            })
+        });
+    }
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    }
+}
+
+pub fn describe_dispatch(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{RENDER_TARGET_SIZE, SAMPLES_PER_RAY};
+use crate::ray_trace_camera::ExtractedRayTraceViews;
 use bevy::{
     prelude::*,
     render::{
@@ -6,6 +6,7 @@ use bevy::{
         renderer::{RenderDevice, RenderQueue},
         RenderApp, RenderStage,
     },
+    utils::HashMap,
 };
 
 #[derive(ShaderType, Clone, Default, Debug)]
@@ -14,8 +15,14 @@ pub struct RayGPU {
     min: f32,
     dir: Vec3,
     max: f32,
+    // The ray's slot, sample * width * height + pixel. Picks its sampler.
     pixel: u32,
     bounces: u32,
+    // Ray cone for texture filtering: width at the origin and spread angle.
+    cone_width: f32,
+    cone_spread: f32,
+    // Color channel the path was narrowed to by a dispersive dielectric, 0 for all.
+    channel: u32,
 }
 
 #[derive(ShaderType, Clone, Default, Debug)]
@@ -25,9 +32,10 @@ pub struct RayBufGPU {
     pub rays: Vec<RayGPU>,
 }
 
+// One ray buffer per RayTraced camera entity.
 #[derive(Default)]
 pub struct RayBufGPUStorage {
-    pub buffer: StorageBuffer<RayBufGPU>,
+    pub buffers: HashMap<Entity, StorageBuffer<RayBufGPU>>,
 }
 
 pub struct RayTraceRaysPlugin;
@@ -42,30 +50,35 @@ impl Plugin for RayTraceRaysPlugin {
 }
 
 fn prepare(
+    views: Res<ExtractedRayTraceViews>,
     mut ray_buf: ResMut<RayBufGPUStorage>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
-    // How many rays should we need?
-    let ray_count = (RENDER_TARGET_SIZE.0 * RENDER_TARGET_SIZE.1) as usize * SAMPLES_PER_RAY;
+    ray_buf.buffers.retain(|entity, _| views.contains(*entity));
 
-    // Only re-allocate this buffer if the number of rays changed.
-    if ray_buf.buffer.get().rays.len() != ray_count {
-        ray_buf.buffer.get_mut().ray_count = ray_count as u32;
-        ray_buf.buffer.get_mut().rays.clear();
-        ray_buf
-            .buffer
-            .get_mut()
-            .rays
-            .append(&mut vec![RayGPU::default(); ray_count]);
+    for view in views.views.iter() {
+        let ray_count = view.ray_count() as usize;
+        let buffer = ray_buf.buffers.entry(view.entity).or_default();
 
-        ray_buf.buffer.write_buffer(&render_device, &render_queue);
+        // Only re-allocate this buffer if the number of rays changed.
+        if buffer.get().rays.len() != ray_count {
+            buffer.get_mut().ray_count = ray_count as u32;
+            buffer.get_mut().rays.clear();
+            buffer
+                .get_mut()
+                .rays
+                .append(&mut vec![RayGPU::default(); ray_count]);
 
-        println!(
-            "Ray Buffer: {:?} {:?}",
-            ray_count,
-            ray_buf.buffer.get().rays.size(),
-        );
+            buffer.write_buffer(&render_device, &render_queue);
+
+            println!(
+                "Ray Buffer {:?}: {:?} {:?}",
+                view.entity,
+                ray_count,
+                buffer.get().rays.size(),
+            );
+        }
     }
 }
 
@@ -0,0 +1,93 @@
+use bevy::render::{render_resource::*, renderer::RenderDevice};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadbackState {
+    // Ready to be copied into.
+    Idle,
+    // Copied into by this frame's commands, which haven't been submitted yet.
+    Copied,
+    // Waiting for the GPU to finish the frame and the buffer to map.
+    Mapping,
+    Mapped,
+}
+
+// A buffer a frame copies GPU results into, mapped back to the CPU a few
+// frames later. Frames aren't copied while the last one is still on its way.
+// The node only sees the world immutably, so the state is behind a mutex.
+// Map callbacks run when the device is polled, which every queue submit does,
+// so a copy is readable a frame or two after it was mapped.
+pub struct Readback {
+    buffer: Buffer,
+    state: Arc<Mutex<ReadbackState>>,
+}
+
+impl Readback {
+    pub fn new(render_device: &RenderDevice, label: &'static str, size: u64) -> Self {
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some(label),
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Readback {
+            buffer,
+            state: Arc::new(Mutex::new(ReadbackState::Idle)),
+        }
+    }
+
+    // Claims the buffer for this frame to copy into. False while the last
+    // copy hasn't been read.
+    pub fn begin(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if *state != ReadbackState::Idle {
+            return false;
+        }
+
+        *state = ReadbackState::Copied;
+        true
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    // Call after the frame's commands are submitted, so the copy is queued
+    // before the buffer is mapped.
+    pub fn map(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if *state != ReadbackState::Copied {
+                return;
+            }
+            *state = ReadbackState::Mapping;
+        }
+
+        // The callback can run as soon as it's registered, so don't hold the
+        // lock over it.
+        let state = self.state.clone();
+        self.buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                *state.lock().unwrap() = match result {
+                    Ok(()) => ReadbackState::Mapped,
+                    Err(_) => ReadbackState::Idle,
+                };
+            });
+    }
+
+    // Reads the copy once it has mapped, then frees the buffer for the next
+    // frame.
+    pub fn read<T>(&self, read: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        if *self.state.lock().unwrap() != ReadbackState::Mapped {
+            return None;
+        }
+
+        let result = read(&self.buffer.slice(..).get_mapped_range());
+        self.buffer.unmap();
+
+        *self.state.lock().unwrap() = ReadbackState::Idle;
+        Some(result)
+    }
+}
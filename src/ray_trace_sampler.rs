@@ -0,0 +1,582 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// The shared sampler functions, imported by the kernels as
+// bevy_raytrace::sampler.
+pub const SAMPLER_SHADER: &str = "shaders/sampler.wgsl";
+
+// The blue noise tile the BlueNoise sampler rotates its sequences by, one
+// value per pixel repeating across the image. Generated once from a fixed
+// seed, like the Perlin tables.
+const BLUE_NOISE_SIZE: usize = 64;
+const BLUE_NOISE_PIXELS: usize = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+const BLUE_NOISE_SEED: u64 = 0xb10e;
+// Width of the Gaussian that measures how clustered points are.
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+// Fraction of pixels set in the initial pattern.
+const INITIAL_DENSITY: f32 = 0.1;
+
+#[derive(ShaderType, Clone, Debug)]
+pub struct BlueNoiseGPU {
+    // Row major, each value in 0..1 and every value used once.
+    values: [f32; BLUE_NOISE_PIXELS],
+}
+
+impl BlueNoiseGPU {
+    // "The void-and-cluster method for dither array generation", Ulichney 1993.
+    // Each pixel is ranked by the order it fills the largest void, so any
+    // threshold of the tile gives evenly spread points.
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let kernel = Self::kernel();
+
+        let mut pattern = VoidAndCluster::new(&kernel);
+        let initial = (BLUE_NOISE_PIXELS as f32 * INITIAL_DENSITY) as usize;
+        while pattern.count < initial {
+            let pixel = rng.gen_range(0..BLUE_NOISE_PIXELS);
+            if !pattern.set[pixel] {
+                pattern.insert(pixel);
+            }
+        }
+
+        // Spread the initial points out by moving the most clustered one into
+        // the largest void until it would land where it started.
+        loop {
+            let cluster = pattern.tightest_cluster();
+            pattern.remove(cluster);
+            let void = pattern.largest_void();
+            pattern.insert(void);
+
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = [0usize; BLUE_NOISE_PIXELS];
+
+        // Rank the initial points by removing them tightest cluster first.
+        let mut removing = pattern.clone();
+        while removing.count > 0 {
+            let cluster = removing.tightest_cluster();
+            removing.remove(cluster);
+            ranks[cluster] = removing.count;
+        }
+
+        // Then fill the voids until every pixel is set. Past half full this
+        // is the same as removing clusters of the unset pixels, since the two
+        // energies sum to a constant.
+        while pattern.count < BLUE_NOISE_PIXELS {
+            let void = pattern.largest_void();
+            ranks[void] = pattern.count;
+            pattern.insert(void);
+        }
+
+        let mut values = [0.0; BLUE_NOISE_PIXELS];
+        for (value, rank) in values.iter_mut().zip(ranks.iter()) {
+            *value = (*rank as f32 + 0.5) / BLUE_NOISE_PIXELS as f32;
+        }
+
+        BlueNoiseGPU { values }
+    }
+
+    // The Gaussian by toroidal offset, so the tile repeats seamlessly.
+    fn kernel() -> Vec<f32> {
+        let mut kernel = vec![0.0; BLUE_NOISE_PIXELS];
+        for (i, k) in kernel.iter_mut().enumerate() {
+            let dx = wrapped_distance(i % BLUE_NOISE_SIZE) as f32;
+            let dy = wrapped_distance(i / BLUE_NOISE_SIZE) as f32;
+            *k = (-(dx * dx + dy * dy) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp();
+        }
+        kernel
+    }
+}
+
+fn wrapped_distance(d: usize) -> usize {
+    d.min(BLUE_NOISE_SIZE - d)
+}
+
+// A binary pattern and, for every pixel, the summed kernel of the set pixels
+// around it.
+#[derive(Clone)]
+struct VoidAndCluster<'a> {
+    kernel: &'a [f32],
+    set: Vec<bool>,
+    energy: Vec<f32>,
+    count: usize,
+}
+
+impl<'a> VoidAndCluster<'a> {
+    fn new(kernel: &'a [f32]) -> Self {
+        VoidAndCluster {
+            kernel,
+            set: vec![false; BLUE_NOISE_PIXELS],
+            energy: vec![0.0; BLUE_NOISE_PIXELS],
+            count: 0,
+        }
+    }
+
+    fn insert(&mut self, pixel: usize) {
+        self.set[pixel] = true;
+        self.count += 1;
+        self.splat(pixel, 1.0);
+    }
+
+    fn remove(&mut self, pixel: usize) {
+        self.set[pixel] = false;
+        self.count -= 1;
+        self.splat(pixel, -1.0);
+    }
+
+    fn splat(&mut self, pixel: usize, sign: f32) {
+        let (px, py) = (pixel % BLUE_NOISE_SIZE, pixel / BLUE_NOISE_SIZE);
+
+        for (i, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (i % BLUE_NOISE_SIZE + BLUE_NOISE_SIZE - px) % BLUE_NOISE_SIZE;
+            let dy = (i / BLUE_NOISE_SIZE + BLUE_NOISE_SIZE - py) % BLUE_NOISE_SIZE;
+            *energy += sign * self.kernel[dy * BLUE_NOISE_SIZE + dx];
+        }
+    }
+
+    // The set pixel with the most set neighbours.
+    fn tightest_cluster(&self) -> usize {
+        self.extreme(true, |a, b| a > b)
+    }
+
+    // The unset pixel with the fewest set neighbours.
+    fn largest_void(&self) -> usize {
+        self.extreme(false, |a, b| a < b)
+    }
+
+    fn extreme(&self, set: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best = None;
+
+        for (i, energy) in self.energy.iter().enumerate() {
+            if self.set[i] != set {
+                continue;
+            }
+
+            match best {
+                Some((_, best_energy)) if !better(*energy, best_energy) => {}
+                _ => best = Some((i, *energy)),
+            }
+        }
+
+        best.unwrap().0
+    }
+}
+
+pub struct BlueNoiseGPUStorage {
+    pub buffer: StorageBuffer<BlueNoiseGPU>,
+}
+
+impl FromWorld for BlueNoiseGPUStorage {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+
+        // The tile never changes, so upload it once.
+        let mut buffer = StorageBuffer::from(BlueNoiseGPU::new(BLUE_NOISE_SEED));
+        buffer.write_buffer(render_device, render_queue);
+
+        BlueNoiseGPUStorage { buffer }
+    }
+}
+
+// Keeps the sampler module loaded. Pipelines only load the shaders they're
+// built from, and an import path resolves only while its shader is loaded.
+pub struct SamplerShader(pub Handle<Shader>);
+
+pub struct RayTraceSamplerPlugin;
+
+impl Plugin for RayTraceSamplerPlugin {
+    fn build(&self, app: &mut App) {
+        let shader = app.world.resource::<AssetServer>().load(SAMPLER_SHADER);
+        app.insert_resource(SamplerShader(shader));
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.init_resource::<BlueNoiseGPUStorage>();
+    }
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What sample_1d and sample_2d in sampler.wgsl compute, to check the
+    // sequences' distributions on the CPU. A port, so it has to be changed
+    // along with sampler.wgsl; port_matches_the_shader catches the two
+    // drifting apart.
+    fn pcg_hash(v: u32) -> u32 {
+        let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+        let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+        (word >> 22) ^ word
+    }
+
+    fn unit_float(x: u32) -> f32 {
+        (x >> 8) as f32 / 16777216.0
+    }
+
+    fn sobol_1(index: u32) -> u32 {
+        let mut result = 0;
+        let mut direction = 0x8000_0000u32;
+        let mut i = index;
+        while i != 0 {
+            if i & 1 != 0 {
+                result ^= direction;
+            }
+            direction ^= direction >> 1;
+            i >>= 1;
+        }
+        result
+    }
+
+    fn laine_karras_permutation(v: u32, seed: u32) -> u32 {
+        let mut x = v.wrapping_add(seed);
+        x ^= x.wrapping_mul(0x6c50b47c);
+        x ^= x.wrapping_mul(0xb82f1e52);
+        x ^= x.wrapping_mul(0xc7afe638);
+        x ^= x.wrapping_mul(0x8d22f6e6);
+        x
+    }
+
+    fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+        laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+    }
+
+    fn sobol_1d(pixel: u32, index: u32, dimension: u32) -> f32 {
+        let seed = pcg_hash(pixel ^ pcg_hash(dimension));
+        let shuffled = nested_uniform_scramble(index, seed);
+        unit_float(nested_uniform_scramble(
+            shuffled.reverse_bits(),
+            pcg_hash(seed),
+        ))
+    }
+
+    fn sobol_2d(pixel: u32, index: u32, dimension: u32) -> [f32; 2] {
+        let seed = pcg_hash(pixel ^ pcg_hash(dimension));
+        let shuffled = nested_uniform_scramble(index, seed);
+        [
+            unit_float(nested_uniform_scramble(
+                shuffled.reverse_bits(),
+                pcg_hash(seed),
+            )),
+            unit_float(nested_uniform_scramble(
+                sobol_1(shuffled),
+                pcg_hash(seed.wrapping_add(1)),
+            )),
+        ]
+    }
+
+    // Rotated by the blue noise values of the pixel in the shader.
+    fn r2_2d(index: u32, rotation: [f32; 2]) -> [f32; 2] {
+        [
+            (unit_float(index.wrapping_mul(3242174889)) + rotation[0]).fract(),
+            (unit_float(index.wrapping_mul(2447445414)) + rotation[1]).fract(),
+        ]
+    }
+
+    fn independent_2d(pixel: u32, index: u32, dimension: u32) -> [f32; 2] {
+        let sample =
+            |dimension: u32| unit_float(pcg_hash(pixel ^ pcg_hash(index ^ pcg_hash(dimension))));
+        [sample(dimension), sample(dimension + 1)]
+    }
+
+    // L2 star discrepancy by Warnock's formula.
+    fn discrepancy(points: &[[f32; 2]]) -> f64 {
+        let n = points.len() as f64;
+        let mut single = 0.0;
+        let mut pairs = 0.0;
+        for p in points {
+            single += (1.0 - (p[0] as f64).powi(2)) * (1.0 - (p[1] as f64).powi(2));
+            for q in points {
+                pairs += (1.0 - p[0].max(q[0]) as f64) * (1.0 - p[1].max(q[1]) as f64);
+            }
+        }
+        (1.0 / 9.0 - single / (2.0 * n) + pairs / (n * n)).sqrt()
+    }
+
+    const PIXELS: [u32; 3] = [0, 17, 4000];
+    const DIMENSIONS: [u32; 3] = [0, 4, 15];
+    // Samples per pixel the sequences are checked over, 2^SAMPLE_BITS.
+    const SAMPLE_BITS: u32 = 8;
+
+    // Pixels set when thresholding the tile at `threshold`.
+    fn threshold(values: &[f32], threshold: f32) -> Vec<(i32, i32)> {
+        values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value < threshold)
+            .map(|(i, _)| ((i % BLUE_NOISE_SIZE) as i32, (i / BLUE_NOISE_SIZE) as i32))
+            .collect()
+    }
+
+    // Distance to the closest other point, wrapping around the tile.
+    fn nearest_distances(points: &[(i32, i32)]) -> Vec<f32> {
+        let size = BLUE_NOISE_SIZE as i32;
+        let wrap = |d: i32| d.abs().min(size - d.abs()) as f32;
+
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, a)| {
+                points
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, b)| wrap(a.0 - b.0).hypot(wrap(a.1 - b.1)))
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .collect()
+    }
+
+    // Power of the tile's spectrum within `radius` of DC, relative to the
+    // average over all frequencies, which white noise has everywhere.
+    fn low_frequency_power(values: &[f32], radius: i32) -> f64 {
+        let size = BLUE_NOISE_SIZE as i32;
+        let n = values.len() as f64;
+        let mean = values.iter().map(|&v| v as f64).sum::<f64>() / n;
+        let variance = values
+            .iter()
+            .map(|&v| (v as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+
+        let mut power = 0.0;
+        let mut count = 0;
+        for ky in -radius..=radius {
+            for kx in -radius..=radius {
+                if (kx, ky) == (0, 0) || kx * kx + ky * ky > radius * radius {
+                    continue;
+                }
+
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, &value) in values.iter().enumerate() {
+                    let (x, y) = (i as i32 % size, i as i32 / size);
+                    let phase = -std::f64::consts::TAU * (kx * x + ky * y) as f64 / size as f64;
+                    re += (value as f64 - mean) * phase.cos();
+                    im += (value as f64 - mean) * phase.sin();
+                }
+                power += re * re + im * im;
+                count += 1;
+            }
+        }
+
+        power / count as f64 / (n * variance)
+    }
+
+    // The lines of sampler.wgsl the port above copies.
+    const PORTED_SHADER_LINES: [&str; 12] = [
+        "let state = v * 747796405u + 2891336453u;",
+        "let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;",
+        "direction = direction ^ (direction >> 1u);",
+        "var x = v + seed;",
+        "x = x ^ (x * 0x6c50b47cu);",
+        "x = x ^ (x * 0xb82f1e52u);",
+        "x = x ^ (x * 0xc7afe638u);",
+        "x = x ^ (x * 0x8d22f6e6u);",
+        "return reverse_bits( laine_karras_permutation( reverse_bits(x), seed ) );",
+        "unit_float( nested_uniform_scramble( reverse_bits(shuffled), pcg_hash(seed) ) ),",
+        "unit_float( nested_uniform_scramble( sobol_1(shuffled), pcg_hash(seed + 1u) ) )",
+        "let sequence = vec2<f32>( unit_float( index * 3242174889u ), unit_float( index * 2447445414u ) );",
+    ];
+
+    #[test]
+    fn port_matches_the_shader() {
+        let shader = include_str!("../assets/shaders/sampler.wgsl");
+
+        for line in PORTED_SHADER_LINES {
+            assert!(
+                shader.lines().any(|shader_line| shader_line.trim() == line),
+                "sampler.wgsl no longer has {:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn sobol_matches_reference_values() {
+        // Direction numbers of the second Sobol dimension, from x + 1 with
+        // every initial m of 1 (Joe and Kuo's table).
+        let directions = [
+            0x8000_0000,
+            0xc000_0000,
+            0xa000_0000,
+            0xf000_0000,
+            0x8800_0000,
+            0xcc00_0000,
+            0xaa00_0000,
+            0xff00_0000,
+        ];
+        for (bit, direction) in directions.into_iter().enumerate() {
+            assert_eq!(sobol_1(1 << bit), direction, "bit {}", bit);
+        }
+
+        // The first points of both dimensions in index order, as Burley 2020
+        // generates them, rather than in Gray code order.
+        let first_0 = [0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875];
+        let first_1 = [0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875];
+        for (index, (expected_0, expected_1)) in (0u32..).zip(first_0.iter().zip(&first_1)) {
+            assert_eq!(unit_float(index.reverse_bits()), *expected_0);
+            assert_eq!(unit_float(sobol_1(index)), *expected_1);
+        }
+    }
+
+    #[test]
+    fn nested_uniform_scramble_is_an_owen_scramble() {
+        // An Owen scramble flips each bit depending only on the bits above
+        // it, so it permutes every level of the binary tree of intervals.
+        for seed in [0, 1, 0x1234_5678, 0xdead_beef] {
+            for bits in 1..=8 {
+                let shift = 32 - bits;
+                let mut seen = vec![false; 1 << bits];
+
+                for prefix in 0..1u32 << bits {
+                    let scrambled = nested_uniform_scramble(prefix << shift, seed) >> shift;
+                    assert!(!seen[scrambled as usize], "seed {} bits {}", seed, bits);
+                    seen[scrambled as usize] = true;
+
+                    // Lower bits never change the interval it lands in.
+                    for low in [1, 0x55_5555, (1 << shift) - 1] {
+                        let x = prefix << shift | low & ((1 << shift) - 1);
+                        assert_eq!(nested_uniform_scramble(x, seed) >> shift, scrambled);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blue_noise_uses_every_value_once() {
+        let tile = BlueNoiseGPU::new(BLUE_NOISE_SEED);
+
+        let mut ranks: Vec<usize> = tile
+            .values
+            .iter()
+            .map(|value| (value * BLUE_NOISE_PIXELS as f32 - 0.5).round() as usize)
+            .collect();
+        ranks.sort_unstable();
+
+        assert!(ranks.iter().enumerate().all(|(i, rank)| i == *rank));
+    }
+
+    #[test]
+    fn blue_noise_has_little_low_frequency_energy() {
+        let tile = BlueNoiseGPU::new(BLUE_NOISE_SEED);
+
+        // White noise would be around 1.
+        assert!(low_frequency_power(&tile.values, 8) < 0.01);
+    }
+
+    #[test]
+    fn blue_noise_thresholds_are_evenly_spread() {
+        let tile = BlueNoiseGPU::new(BLUE_NOISE_SEED);
+
+        for density in [0.02, 0.05, 0.1] {
+            let points = threshold(&tile.values, density);
+            let distances = nearest_distances(&points);
+            let mean = distances.iter().sum::<f32>() / distances.len() as f32;
+            let spacing = (1.0 / density).sqrt();
+
+            // White noise averages about half the spacing and has neighbours
+            // touching.
+            assert!(mean > 0.75 * spacing, "{} at {}", mean, density);
+            assert!(distances.iter().all(|d| *d >= 2.0), "{}", density);
+        }
+    }
+
+    #[test]
+    fn sobol_1d_is_stratified() {
+        let count = 1 << SAMPLE_BITS;
+
+        for pixel in PIXELS {
+            for dimension in DIMENSIONS {
+                let mut strata = vec![0; count];
+                for index in 0..count as u32 {
+                    strata[(sobol_1d(pixel, index, dimension) * count as f32) as usize] += 1;
+                }
+
+                assert!(strata.iter().all(|&n| n == 1));
+            }
+        }
+    }
+
+    #[test]
+    fn sobol_2d_is_a_net() {
+        // Every elementary interval of 2^SAMPLE_BITS area holds exactly one
+        // point, from 1 by 2^SAMPLE_BITS through to 2^SAMPLE_BITS by 1.
+        for pixel in PIXELS {
+            for dimension in DIMENSIONS {
+                let points: Vec<_> = (0..1 << SAMPLE_BITS)
+                    .map(|index| sobol_2d(pixel, index, dimension))
+                    .collect();
+
+                for x_bits in 0..=SAMPLE_BITS {
+                    let (columns, rows) = (1 << x_bits, 1 << (SAMPLE_BITS - x_bits));
+                    let mut cells = vec![0; 1 << SAMPLE_BITS];
+                    for p in points.iter() {
+                        let x = (p[0] * columns as f32) as usize;
+                        let y = (p[1] * rows as f32) as usize;
+                        cells[y * columns + x] += 1;
+                    }
+
+                    assert!(cells.iter().all(|&n| n == 1), "{} by {}", columns, rows);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn r2_is_evenly_spread() {
+        let count = 1 << SAMPLE_BITS;
+        let side = 1 << (SAMPLE_BITS / 2);
+
+        for rotation in [[0.0, 0.0], [0.3, 0.77], [0.91, 0.12]] {
+            let mut cells = vec![0; count];
+            for index in 0..count as u32 {
+                let p = r2_2d(index, rotation);
+                cells[(p[1] * side as f32) as usize * side + (p[0] * side as f32) as usize] += 1;
+            }
+
+            assert!(cells.iter().all(|&n| n <= 2));
+        }
+    }
+
+    #[test]
+    fn low_discrepancy_beats_independent() {
+        let count = 1 << SAMPLE_BITS;
+
+        for pixel in PIXELS {
+            for dimension in DIMENSIONS {
+                let sample = |f: &dyn Fn(u32) -> [f32; 2]| -> f64 {
+                    discrepancy(&(0..count).map(f).collect::<Vec<_>>())
+                };
+                let independent = sample(&|index| independent_2d(pixel, index, dimension));
+                let sobol = sample(&|index| sobol_2d(pixel, index, dimension));
+                let r2 = sample(&|index| r2_2d(index, [0.3, 0.77]));
+
+                assert!(sobol < 0.5 * independent, "{} {}", sobol, independent);
+                assert!(r2 < 0.5 * independent, "{} {}", r2, independent);
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+    utils::{HashMap, Instant},
+};
+use std::sync::Mutex;
+
+use crate::ray_trace_camera::ExtractedRayTraceViews;
+use crate::ray_trace_diagnostics::RayTraceMeasurements;
+use crate::ray_trace_readback::Readback;
+
+// The workgroup counting the kernels share, imported as
+// bevy_raytrace::ray_stats.
+pub const RAY_STATS_SHADER: &str = "shaders/ray_stats.wgsl";
+
+// Bounces past this are counted in the last entry. Matches MAX_STATS_BOUNCES
+// in ray_stats.wgsl.
+const MAX_STATS_BOUNCES: usize = 32;
+
+// primary_rays, the two intersection_tests words, then alive_rays and
+// terminated_rays per bounce, as ray_stats_buf in the shaders.
+const STATS_WORDS: usize = 3 + 2 * MAX_STATS_BOUNCES;
+const STATS_SIZE: u64 = (STATS_WORDS * 4) as u64;
+
+// What the passes counted for one view in one frame.
+struct RayStats {
+    primary_rays: u32,
+    // Ray against sphere and medium tests.
+    intersection_tests: u64,
+    // Rays intersected at each bounce, the first being the primary rays.
+    alive_rays: Vec<u32>,
+    // Paths that ended at each bounce.
+    terminated_rays: Vec<u32>,
+}
+
+impl RayStats {
+    fn from_bytes(data: &[u8]) -> Self {
+        let words: Vec<u32> = data
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let per_bounce = |offset: usize| words[offset..offset + MAX_STATS_BOUNCES].to_vec();
+
+        RayStats {
+            primary_rays: words[0],
+            // The low word carries into the high one.
+            intersection_tests: words[1] as u64 | (words[2] as u64) << 32,
+            alive_rays: per_bounce(3),
+            terminated_rays: per_bounce(3 + MAX_STATS_BOUNCES),
+        }
+    }
+}
+
+// Whether the passes count rays, which is while the diagnostics overlay is
+// shown. The counting is compiled out of the shaders otherwise.
+#[derive(Clone, Copy, Debug, ExtractResource)]
+pub struct RayStatsEnabled(pub bool);
+
+impl Default for RayStatsEnabled {
+    fn default() -> Self {
+        RayStatsEnabled(true)
+    }
+}
+
+// Seconds the GPU spent on each view's passes in one profiled frame. Left
+// empty when the adapter can't write timestamps.
+#[derive(Default)]
+pub struct RayTraceGPUTimes {
+    pub frame: u32,
+    pub views: HashMap<Entity, f64>,
+}
+
+// The counters one view's passes add to, cleared at the start of each frame.
+// Copied out at the end of a frame whenever the readback is free.
+pub struct RayStatsBuffer {
+    pub buffer: Buffer,
+    pub readback: Readback,
+    // The profiled frame the readback was copied in, if any, to pair the
+    // counts with that frame's GPU time.
+    profiled_frame: Mutex<Option<u32>>,
+}
+
+impl RayStatsBuffer {
+    fn new(render_device: &RenderDevice) -> Self {
+        // Not a StorageBuffer, which can't be copied from.
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("ray_stats"),
+            size: STATS_SIZE,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        RayStatsBuffer {
+            buffer,
+            readback: Readback::new(render_device, "ray_stats_readback", STATS_SIZE),
+            profiled_frame: Mutex::new(None),
+        }
+    }
+
+    pub fn copy_to_readback(&self, encoder: &mut CommandEncoder, profiled_frame: Option<u32>) {
+        if self.readback.begin() {
+            encoder.copy_buffer_to_buffer(&self.buffer, 0, self.readback.buffer(), 0, STATS_SIZE);
+            *self.profiled_frame.lock().unwrap() = profiled_frame;
+        }
+    }
+}
+
+// Keyed by RayTraced camera entity.
+#[derive(Default)]
+pub struct RayStatsGPUStorage {
+    pub buffers: HashMap<Entity, RayStatsBuffer>,
+}
+
+// Keeps the ray_stats module loaded, as SamplerShader does the sampler.
+pub struct RayStatsShader(pub Handle<Shader>);
+
+pub struct RayTraceStatsPlugin;
+
+impl Plugin for RayTraceStatsPlugin {
+    fn build(&self, app: &mut App) {
+        let shader = app.world.resource::<AssetServer>().load(RAY_STATS_SHADER);
+        app.insert_resource(RayStatsShader(shader))
+            .init_resource::<RayStatsEnabled>()
+            .add_plugin(ExtractResourcePlugin::<RayStatsEnabled>::default());
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<RayStatsGPUStorage>()
+            .add_system_to_stage(RenderStage::Prepare, prepare)
+            .add_system_to_stage(RenderStage::Cleanup, map_stats);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn prepare(
+    views: Res<ExtractedRayTraceViews>,
+    mut stats: ResMut<RayStatsGPUStorage>,
+    enabled: Res<RayStatsEnabled>,
+    gpu_times: Option<Res<RayTraceGPUTimes>>,
+    measurements: Res<RayTraceMeasurements>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+    mut last_prepare: Local<Option<Instant>>,
+) {
+    stats.buffers.retain(|entity, _| views.contains(*entity));
+
+    let now = Instant::now();
+    let frame_time = last_prepare
+        .replace(now)
+        .map(|last| (now - last).as_secs_f64());
+
+    for view in views.views.iter() {
+        // Every view needs a buffer to bind, counted into or not.
+        let buffer = stats
+            .buffers
+            .entry(view.entity)
+            .or_insert_with(|| RayStatsBuffer::new(&render_device));
+
+        // Read even while disabled, so counts from before aren't shown once
+        // it's enabled again.
+        let read = buffer.readback.read(RayStats::from_bytes);
+        if !enabled.0 {
+            continue;
+        }
+
+        render_queue.write_buffer(&buffer.buffer, 0, &[0; STATS_SIZE as usize]);
+
+        if let Some(stats) = read {
+            // Rays are counted per frame, so rays per second is best taken
+            // over the GPU time of the same frame, which only profiled frames
+            // have. Other frames use the time between frames instead, which
+            // also counts time not spent tracing this view.
+            let profiled_frame = *buffer.profiled_frame.lock().unwrap();
+            let time = gpu_times
+                .as_ref()
+                .filter(|times| Some(times.frame) == profiled_frame)
+                .and_then(|times| times.views.get(&view.entity).copied())
+                .or(frame_time);
+
+            push_measurements(&measurements, view.entity, &stats, time);
+        }
+    }
+}
+
+fn push_measurements(
+    measurements: &RayTraceMeasurements,
+    view: Entity,
+    stats: &RayStats,
+    time: Option<f64>,
+) {
+    let name = |name: &str| format!("view{}_{}", view.id(), name);
+    let millions = |count: u64| count as f64 / 1_000_000.0;
+    let traced: u64 = stats.alive_rays.iter().map(|&count| count as u64).sum();
+
+    measurements.push(
+        name("rays_primary"),
+        "M",
+        millions(stats.primary_rays as u64),
+    );
+    measurements.push(
+        name("rays_extension"),
+        "M",
+        millions(traced - stats.alive_rays[0] as u64),
+    );
+    measurements.push(
+        name("intersection_tests"),
+        "M",
+        millions(stats.intersection_tests),
+    );
+    if let Some(time) = time.filter(|&time| time > 0.0) {
+        measurements.push(name("rays_per_second"), "Mrays/s", millions(traced) / time);
+    }
+
+    // Up to the last bounce any path reached.
+    let bounces = stats
+        .alive_rays
+        .iter()
+        .rposition(|&count| count > 0)
+        .map_or(0, |last| last + 1);
+    let per_bounce = stats.alive_rays.iter().zip(stats.terminated_rays.iter());
+    for (bounce, (&alive, &terminated)) in per_bounce.enumerate().take(bounces) {
+        measurements.push(
+            name(&format!("rays_alive_bounce_{}", bounce)),
+            "M",
+            millions(alive as u64),
+        );
+        measurements.push(
+            name(&format!("rays_terminated_bounce_{}", bounce)),
+            "M",
+            millions(terminated as u64),
+        );
+    }
+}
+
+fn map_stats(stats: Res<RayStatsGPUStorage>) {
+    for buffer in stats.buffers.values() {
+        buffer.readback.map();
+    }
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    }
+}
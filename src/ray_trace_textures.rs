@@ -0,0 +1,282 @@
+use crate::ray_trace_materials::RayTraceMaterial;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        MainWorld, RenderApp, RenderStage,
+    },
+    utils::{HashMap, HashSet},
+};
+use std::num::NonZeroU32;
+
+// Every material texture is resampled into one layer of a texture array, so
+// the shaders can index them by layer from MaterialGPU.
+pub const MATERIAL_TEXTURE_SIZE: u32 = 512;
+pub const MATERIAL_TEXTURE_MIPS: u32 = 10;
+pub const MAX_MATERIAL_TEXTURES: u32 = 32;
+
+// Which layer of the texture array each image lives in. Layers are handed out
+// as soon as a material references an image, before it has loaded.
+#[derive(Default)]
+pub struct MaterialTextureLayers {
+    layers: HashMap<Handle<Image>, u32>,
+    uploaded: HashSet<Handle<Image>>,
+}
+
+impl MaterialTextureLayers {
+    // The layer for an optional texture, or -1 for none.
+    pub fn layer_of(&self, texture: &Option<Handle<Image>>) -> i32 {
+        texture
+            .as_ref()
+            .and_then(|handle| self.layers.get(handle))
+            .map_or(-1, |layer| *layer as i32)
+    }
+}
+
+pub struct MaterialTextureArray {
+    pub texture: Texture,
+    pub view: TextureView,
+    pub sampler: Sampler,
+}
+
+impl FromWorld for MaterialTextureArray {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("material_texture_array"),
+            size: Extent3d {
+                width: MATERIAL_TEXTURE_SIZE,
+                height: MATERIAL_TEXTURE_SIZE,
+                depth_or_array_layers: MAX_MATERIAL_TEXTURES,
+            },
+            mip_level_count: MATERIAL_TEXTURE_MIPS,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        });
+
+        let view = texture.create_view(&TextureViewDescriptor {
+            label: Some("material_texture_array_view"),
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        });
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("material_texture_sampler"),
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        MaterialTextureArray {
+            texture,
+            view,
+            sampler,
+        }
+    }
+}
+
+// Layers resampled and mipmapped on the CPU, waiting to be written to the array.
+#[derive(Default)]
+struct PendingTextureUploads {
+    uploads: Vec<(u32, Vec<Vec<u8>>)>,
+}
+
+pub struct RayTraceTexturesPlugin;
+
+impl Plugin for RayTraceTexturesPlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<MaterialTextureLayers>()
+            .init_resource::<MaterialTextureArray>()
+            .init_resource::<PendingTextureUploads>()
+            .add_system_to_stage(RenderStage::Extract, extract)
+            .add_system_to_stage(RenderStage::Prepare, prepare);
+    }
+}
+
+fn extract(
+    world: Res<MainWorld>,
+    mut layers: ResMut<MaterialTextureLayers>,
+    mut pending: ResMut<PendingTextureUploads>,
+) {
+    let materials = world.resource::<Assets<RayTraceMaterial>>();
+    let images = world.resource::<Assets<Image>>();
+
+    for (_, material) in materials.iter() {
+        for handle in material.textures() {
+            if layers.uploaded.contains(handle) {
+                continue;
+            }
+
+            let layer = match layers.layers.get(handle) {
+                Some(layer) => *layer,
+                None => {
+                    let layer = layers.layers.len() as u32;
+                    if layer >= MAX_MATERIAL_TEXTURES {
+                        warn!("Out of material texture layers, ignoring {:?}", handle);
+                        layers.uploaded.insert(handle.clone_weak());
+                        continue;
+                    }
+                    layers.layers.insert(handle.clone_weak(), layer);
+                    layer
+                }
+            };
+
+            // Not loaded yet, try again next frame.
+            let image = match images.get(handle) {
+                Some(image) => image,
+                None => continue,
+            };
+
+            layers.uploaded.insert(handle.clone_weak());
+
+            match resample(image) {
+                Some(base) => pending.uploads.push((layer, build_mips(base))),
+                None => warn!(
+                    "Material texture {:?} is {:?}, only 8 bit RGBA is supported",
+                    handle, image.texture_descriptor.format
+                ),
+            }
+        }
+    }
+}
+
+fn prepare(
+    mut pending: ResMut<PendingTextureUploads>,
+    array: Res<MaterialTextureArray>,
+    render_queue: Res<RenderQueue>,
+) {
+    for (layer, mips) in pending.uploads.drain(..) {
+        for (mip_level, data) in mips.iter().enumerate() {
+            let size = mip_size(mip_level as u32);
+
+            render_queue.write_texture(
+                ImageCopyTexture {
+                    texture: &array.texture,
+                    mip_level: mip_level as u32,
+                    origin: Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                data,
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(size * 4),
+                    rows_per_image: None,
+                },
+                Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+}
+
+fn mip_size(mip_level: u32) -> u32 {
+    (MATERIAL_TEXTURE_SIZE >> mip_level).max(1)
+}
+
+// Bilinearly resample an 8 bit RGBA image to the array layer size. The bytes
+// are copied as is, so sRGB images stay sRGB encoded.
+fn resample(image: &Image) -> Option<Vec<u8>> {
+    match image.texture_descriptor.format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {}
+        _ => return None,
+    }
+
+    let width = image.texture_descriptor.size.width as usize;
+    let height = image.texture_descriptor.size.height as usize;
+    let size = MATERIAL_TEXTURE_SIZE as usize;
+
+    let texel = |x: usize, y: usize, c: usize| {
+        image.data[((y % height) * width + (x % width)) * 4 + c] as f32
+    };
+
+    let mut data = vec![0u8; size * size * 4];
+    for y in 0..size {
+        let v = (y as f32 + 0.5) * height as f32 / size as f32 - 0.5;
+        let v = v.max(0.0);
+        let (y0, fy) = (v.floor() as usize, v.fract());
+
+        for x in 0..size {
+            let u = (x as f32 + 0.5) * width as f32 / size as f32 - 0.5;
+            let u = u.max(0.0);
+            let (x0, fx) = (u.floor() as usize, u.fract());
+
+            for c in 0..4 {
+                let top = texel(x0, y0, c) * (1.0 - fx) + texel(x0 + 1, y0, c) * fx;
+                let bottom = texel(x0, y0 + 1, c) * (1.0 - fx) + texel(x0 + 1, y0 + 1, c) * fx;
+                data[(y * size + x) * 4 + c] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+            }
+        }
+    }
+
+    Some(data)
+}
+
+// Box filter the base level down to 1x1.
+fn build_mips(base: Vec<u8>) -> Vec<Vec<u8>> {
+    let mut mips = vec![base];
+
+    for mip_level in 1..MATERIAL_TEXTURE_MIPS {
+        let parent = &mips[mip_level as usize - 1];
+        let parent_size = mip_size(mip_level - 1) as usize;
+        let size = mip_size(mip_level) as usize;
+
+        let mut data = vec![0u8; size * size * 4];
+        for y in 0..size {
+            for x in 0..size {
+                for c in 0..4 {
+                    let sum: u32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .iter()
+                        .map(|(dx, dy)| {
+                            let px = (x * 2 + dx).min(parent_size - 1);
+                            let py = (y * 2 + dy).min(parent_size - 1);
+                            parent[(py * parent_size + px) * 4 + c] as u32
+                        })
+                        .sum();
+                    data[(y * size + x) * 4 + c] = ((sum + 2) / 4) as u8;
+                }
+            }
+        }
+
+        mips.push(data);
+    }
+
+    mips
+}
+
+pub fn describe_texture(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D2Array,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+pub fn describe_sampler(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+        count: None,
+    }
+}
@@ -0,0 +1,77 @@
+use crate::ray_trace_camera::ExtractedRayTraceViews;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+    utils::HashMap,
+};
+
+// How much of the light arriving at each ray's next hit makes it back to the
+// camera, the product of every attenuation along the path so far. Shading
+// multiplies it in, and adds it weighted by emission to the path's color in
+// IntersectionGPU once the path ends. w is the roughest lobe the path has
+// scattered from, which regularization raises later lobes towards.
+#[derive(Default)]
+pub struct ThroughputGPUStorage {
+    pub buffers: HashMap<Entity, StorageBuffer<Vec<Vec4>>>,
+}
+
+pub struct RayTraceThroughputPlugin;
+
+impl Plugin for RayTraceThroughputPlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<ThroughputGPUStorage>()
+            .add_system_to_stage(RenderStage::Prepare, prepare);
+    }
+}
+
+fn prepare(
+    views: Res<ExtractedRayTraceViews>,
+    mut throughputs: ResMut<ThroughputGPUStorage>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    throughputs
+        .buffers
+        .retain(|entity, _| views.contains(*entity));
+
+    for view in views.views.iter() {
+        // One throughput per ray.
+        let ray_count = view.ray_count() as usize;
+        let buffer = throughputs.buffers.entry(view.entity).or_default();
+
+        if buffer.get().len() != ray_count {
+            buffer.get_mut().clear();
+            buffer
+                .get_mut()
+                .append(&mut vec![Vec4::new(1.0, 1.0, 1.0, 0.0); ray_count]);
+
+            buffer.write_buffer(&render_device, &render_queue);
+
+            println!(
+                "Throughput Buffer {:?}: {:?} {:?}",
+                view.entity,
+                ray_count,
+                buffer.get().size()
+            );
+        }
+    }
+}
+
+pub fn describe(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+    }
+}
@@ -1,5 +1,6 @@
 use crate::ray_trace_materials::{MaterialCache, RayTraceMaterial, Reflectance};
 use bevy::{
+    asset::HandleId,
     prelude::*,
     render::{
         render_resource::*,
@@ -28,20 +29,54 @@ pub struct ObjectListStorage {
     pub buffer: StorageBuffer<ObjectListGPU>,
 }
 
+// Shaded with the Handle<RayTraceMaterial> on the same entity.
 #[derive(Component, Default, Clone, Debug)]
 pub struct Sphere {
     radius: f32,
-    material: u32,
 }
 
-pub fn init_spheres(mut commands: Commands, mut materials: ResMut<MaterialCache>) {
+impl Sphere {
+    pub fn intersect(&self, center: Vec3, origin: Vec3, dir: Vec3) -> Option<f32> {
+        intersect_sphere(center, self.radius, origin, dir)
+    }
+}
+
+// Distance along a normalized ray to the first hit in front of it.
+pub fn intersect_sphere(center: Vec3, radius: f32, origin: Vec3, dir: Vec3) -> Option<f32> {
+    let oc = origin - center;
+    let half_b = oc.dot(dir);
+    let c = oc.length_squared() - radius * radius;
+
+    let dis = half_b * half_b - c;
+    if dis < 0.0 {
+        return None;
+    }
+
+    let sqrtd = dis.sqrt();
+    [-half_b - sqrtd, -half_b + sqrtd]
+        .into_iter()
+        .find(|t| *t > 0.0)
+}
+
+// Spheres pulled from the main world. The material is turned into an index
+// in prepare, once the materials have been extracted.
+#[derive(Default)]
+struct ExtractedSpheres {
+    spheres: Vec<(SphereGPU, HandleId)>,
+}
+
+pub fn init_spheres(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<RayTraceMaterial>>,
+    asset_server: Res<AssetServer>,
+) {
     commands
         .spawn()
-        .insert(Transform::from_xyz(0.0, -1000.0, -1.0))
-        .insert(Sphere {
-            radius: 1000.0,
-            material: materials.get_index_of("ground"),
-        });
+        .insert_bundle(TransformBundle::from(Transform::from_xyz(
+            0.0, -1000.0, -1.0,
+        )))
+        .insert(Sphere { radius: 1000.0 })
+        .insert(asset_server.load::<RayTraceMaterial, _>("materials/ground.rtmat.ron"));
 
     let mut rng = rand::thread_rng();
 
@@ -56,47 +91,55 @@ pub fn init_spheres(mut commands: Commands, mut materials: ResMut<MaterialCache>
                 b as f32 + 0.9 * rng.gen::<f32>(),
             );
             if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                let material_name = format!("material_{}_{}", a, b);
-
-                if rng.gen::<f32>() < 0.8 {
-                    materials.materials.insert(
-                        material_name.clone(),
-                        RayTraceMaterial {
-                            reflectance: Reflectance::Lambertian,
-                            color: Color::rgba(
-                                rng.gen::<f32>(),
-                                rng.gen::<f32>(),
-                                rng.gen::<f32>(),
-                                1.0,
-                            ),
-                            fuzziness: 1.0,
-                            index_of_refraction: 0.0,
-                        },
-                    );
+                let material = if rng.gen::<f32>() < 0.8 {
+                    materials.add(RayTraceMaterial {
+                        reflectance: Reflectance::Lambertian,
+                        color: Color::rgba(
+                            rng.gen::<f32>(),
+                            rng.gen::<f32>(),
+                            rng.gen::<f32>(),
+                            1.0,
+                        ),
+                        fuzziness: 1.0,
+                        index_of_refraction: 0.0,
+                        ..default()
+                    })
+                } else if rng.gen::<f32>() < 0.75 {
+                    materials.add(RayTraceMaterial {
+                        reflectance: Reflectance::Metallic,
+                        color: Color::rgba(
+                            rng.gen::<f32>(),
+                            rng.gen::<f32>(),
+                            rng.gen::<f32>(),
+                            1.0,
+                        ),
+                        fuzziness: rng.gen::<f32>() * 0.5,
+                        index_of_refraction: 0.0,
+                        ..default()
+                    })
                 } else {
-                    materials.materials.insert(
-                        material_name.clone(),
-                        RayTraceMaterial {
-                            reflectance: Reflectance::Metallic,
-                            color: Color::rgba(
-                                rng.gen::<f32>(),
-                                rng.gen::<f32>(),
-                                rng.gen::<f32>(),
-                                1.0,
-                            ),
-                            fuzziness: rng.gen::<f32>() * 0.5,
-                            index_of_refraction: 0.0,
-                        },
-                    );
-                }
+                    // Tinted flint-like glass, dispersive enough to see.
+                    materials.add(RayTraceMaterial {
+                        reflectance: Reflectance::Dielectric,
+                        color: Color::WHITE,
+                        index_of_refraction: 1.5,
+                        absorption: Color::rgba(
+                            0.5 + 0.5 * rng.gen::<f32>(),
+                            0.5 + 0.5 * rng.gen::<f32>(),
+                            0.5 + 0.5 * rng.gen::<f32>(),
+                            1.0,
+                        ),
+                        absorption_density: 2.0,
+                        cauchy_b: 0.01,
+                        ..default()
+                    })
+                };
 
                 commands
                     .spawn()
-                    .insert(Transform::from_translation(center))
-                    .insert(Sphere {
-                        radius: 0.2,
-                        material: materials.get_index_of(&material_name),
-                    });
+                    .insert_bundle(TransformBundle::from(Transform::from_translation(center)))
+                    .insert(Sphere { radius: 0.2 })
+                    .insert(material);
 
                 /*
                 shared_ptr<material> sphere_material;
@@ -124,27 +167,39 @@ pub fn init_spheres(mut commands: Commands, mut materials: ResMut<MaterialCache>
 
     commands
         .spawn()
-        .insert(Transform::from_xyz(0.0, 1.0, 0.0))
-        .insert(Sphere {
-            radius: 1.0,
-            material: materials.get_index_of("center"),
-        });
+        .insert_bundle(TransformBundle::from(Transform::from_xyz(0.0, 1.0, 0.0)))
+        .insert(Sphere { radius: 1.0 })
+        .insert(materials.add(RayTraceMaterial {
+            reflectance: Reflectance::Lambertian,
+            color: Color::rgba(0.7, 0.3, 0.3, 1.0),
+            fuzziness: 1.0,
+            index_of_refraction: 0.0,
+            ..default()
+        }));
 
     commands
         .spawn()
-        .insert(Transform::from_xyz(-4.0, 1.0, 0.0))
-        .insert(Sphere {
-            radius: 1.0,
-            material: materials.get_index_of("left"),
-        });
+        .insert_bundle(TransformBundle::from(Transform::from_xyz(-4.0, 1.0, 0.0)))
+        .insert(Sphere { radius: 1.0 })
+        .insert(materials.add(RayTraceMaterial {
+            reflectance: Reflectance::Metallic,
+            color: Color::rgba(0.8, 0.8, 0.8, 1.0),
+            fuzziness: 0.1,
+            index_of_refraction: 1.5,
+            ..default()
+        }));
 
     commands
         .spawn()
-        .insert(Transform::from_xyz(4.0, 1.0, 0.0))
-        .insert(Sphere {
-            radius: 1.0,
-            material: materials.get_index_of("right"),
-        });
+        .insert_bundle(TransformBundle::from(Transform::from_xyz(4.0, 1.0, 0.0)))
+        .insert(Sphere { radius: 1.0 })
+        .insert(materials.add(RayTraceMaterial {
+            reflectance: Reflectance::Metallic,
+            color: Color::rgba(0.7, 0.6, 0.5, 1.0),
+            fuzziness: 0.0,
+            index_of_refraction: 1.5,
+            ..default()
+        }));
 }
 
 pub struct SphereRenderPlugin;
@@ -155,7 +210,7 @@ impl Plugin for SphereRenderPlugin {
 
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app
-                .insert_resource(ObjectListGPU::default())
+                .init_resource::<ExtractedSpheres>()
                 .insert_resource(ObjectListStorage::default())
                 .add_system_to_stage(RenderStage::Extract, extract)
                 .add_system_to_stage(RenderStage::Prepare, prepare);
@@ -163,33 +218,37 @@ impl Plugin for SphereRenderPlugin {
     }
 }
 
-fn extract(mut world: ResMut<MainWorld>, mut object_list: ResMut<ObjectListGPU>) {
-    let mut query = world.query::<(&Sphere, &Transform)>();
+fn extract(mut world: ResMut<MainWorld>, mut extracted: ResMut<ExtractedSpheres>) {
+    let mut query = world.query::<(&Sphere, &GlobalTransform, &Handle<RayTraceMaterial>)>();
 
-    object_list.spheres.clear();
+    extracted.spheres.clear();
 
-    for (sphere, transform) in query.iter(&world) {
-        object_list.spheres.push(SphereGPU {
-            center: transform.translation,
-            radius: sphere.radius,
-            material: sphere.material,
-        });
+    for (sphere, transform, material) in query.iter(&world) {
+        extracted.spheres.push((
+            SphereGPU {
+                center: Transform::from(*transform).translation,
+                radius: sphere.radius,
+                material: 0,
+            },
+            material.id,
+        ));
     }
 }
 
 fn prepare(
-    mut object_list: ResMut<ObjectListGPU>,
+    mut extracted: ResMut<ExtractedSpheres>,
+    materials: Res<MaterialCache>,
     mut object_list_storage: ResMut<ObjectListStorage>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
-    object_list_storage.buffer.get_mut().sphere_count = object_list.spheres.len() as u32;
-    object_list_storage.buffer.get_mut().spheres.clear();
-    object_list_storage
-        .buffer
-        .get_mut()
-        .spheres
-        .append(&mut object_list.spheres);
+    let object_list = object_list_storage.buffer.get_mut();
+    object_list.sphere_count = extracted.spheres.len() as u32;
+    object_list.spheres.clear();
+    for (mut sphere, material) in extracted.spheres.drain(..) {
+        sphere.material = materials.index_of(material);
+        object_list.spheres.push(sphere);
+    }
 
     object_list_storage
         .buffer